@@ -0,0 +1,376 @@
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use async_trait::async_trait;
+use serde::Serialize;
+use tokio::sync::{oneshot, Notify};
+use tokio::time::Instant;
+use crate::actor_model::addr::Addr;
+use crate::actor_model::context::Context;
+use crate::actor_model::mailbox::{self, MailboxConfig, Receiver, WeakSender};
+use crate::actor_model::metrics::{self, ActorMetrics};
+
+///
+/// 邮箱默认容量，邮箱已满时 send 与 ask 等待空位
+///
+pub const DEFAULT_MAILBOX_CAPACITY: usize = 64;
+
+///
+/// actor 在进程内的唯一编号
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+pub struct ActorId(u64);
+
+impl ActorId {
+    pub(crate) fn next() -> ActorId {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        ActorId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl fmt::Display for ActorId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "actor-{}", self.0)
+    }
+}
+
+///
+/// actor 逐条处理邮箱中的消息，状态只在自己的任务中被修改
+/// Message 为可以接收的消息，Reply 为 handle 的返回值，ask 时交给调用方
+///
+#[async_trait]
+pub trait Actor: Sized + Send + 'static {
+    type Message: Send + 'static;
+    type Reply: Send + 'static;
+
+    ///
+    /// 处理第一条消息之前调用
+    ///
+    async fn started(&mut self, _ctx: &mut Context<Self>) {}
+
+    async fn handle(&mut self, msg: Self::Message, ctx: &mut Context<Self>) -> Self::Reply;
+
+    ///
+    /// 不再处理消息之后、停止之前调用
+    ///
+    async fn stopping(&mut self, _ctx: &mut Context<Self>) {}
+
+    ///
+    /// actor 停止后最后一次调用
+    ///
+    async fn stopped(&mut self, _ctx: &mut Context<Self>) {}
+}
+
+pub(crate) enum Envelope<A: Actor> {
+    Message {
+        msg: A::Message,
+        reply: Option<oneshot::Sender<A::Reply>>,
+    },
+    Stop,
+}
+
+///
+/// 在当前 tokio runtime 中启动 actor，返回它的地址
+///
+pub fn spawn<A: Actor>(actor: A) -> Addr<A> {
+    spawn_with(actor, MailboxConfig::default())
+}
+
+///
+/// 使用指定的邮箱配置启动 actor
+///
+pub fn spawn_with<A: Actor>(actor: A, config: MailboxConfig) -> Addr<A> {
+    spawn_local(actor, config).0
+}
+
+///
+/// 启动 actor，同时返回不计数的邮箱引用
+///
+pub(crate) fn spawn_local<A: Actor>(actor: A, config: MailboxConfig) -> (Addr<A>, WeakSender<Envelope<A>>) {
+    let (addr, weak, mut mailbox) = mailbox(config);
+    let ctx = Context::new(addr.id(), weak.clone());
+    tokio::spawn(async move { run(actor, ctx, &mut mailbox, None).await });
+    (addr, weak)
+}
+
+///
+/// 创建邮箱并登记到运行时统计中，actor 完全停止时移除
+///
+pub(crate) fn mailbox<A: Actor>(config: MailboxConfig) -> (Addr<A>, WeakSender<Envelope<A>>, Receiver<Envelope<A>>) {
+    let id = ActorId::next();
+    let metrics = Arc::new(ActorMetrics::new(id, std::any::type_name::<A>()));
+    let (sender, mailbox) = mailbox::channel(config, metrics);
+    metrics::register(id, sender.probe());
+    let weak = sender.downgrade();
+    (Addr::new(id, sender), weak, mailbox)
+}
+
+enum Next<A: Actor> {
+    Envelope(Option<Envelope<A>>),
+    Killed,
+}
+
+///
+/// actor 的消息循环，kill 由监督者用来终止当前实例
+/// 被 kill 时邮箱保持打开，重启后的新实例继续处理其中的消息
+///
+pub(crate) async fn run<A: Actor>(
+    mut actor: A,
+    mut ctx: Context<A>,
+    mailbox: &mut Receiver<Envelope<A>>,
+    kill: Option<&Notify>,
+) {
+    actor.started(&mut ctx).await;
+    let mut killed = false;
+    while !ctx.is_stopping() {
+        let next = match kill {
+            Some(kill) => tokio::select! {
+                biased;
+                _ = kill.notified() => Next::Killed,
+                envelope = mailbox.recv() => Next::Envelope(envelope),
+            },
+            None => Next::Envelope(mailbox.recv().await),
+        };
+        match next {
+            Next::Envelope(Some(Envelope::Message { msg, reply })) => {
+                let started = Instant::now();
+                let result = actor.handle(msg, &mut ctx).await;
+                mailbox.metrics().record(started.elapsed());
+                if let Some(reply) = reply {
+                    // 调用方可能已经不再等待回复
+                    let _ = reply.send(result);
+                }
+            }
+            // 收到停止请求或所有 Addr 都已释放
+            Next::Envelope(Some(Envelope::Stop)) | Next::Envelope(None) => break,
+            Next::Killed => {
+                killed = true;
+                break;
+            }
+        }
+    }
+    ctx.stop();
+    ctx.cancel_timers();
+    if !killed {
+        mailbox.close();
+    }
+    actor.stopping(&mut ctx).await;
+    actor.stopped(&mut ctx).await;
+    if !killed {
+        mailbox.terminate();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use crate::actor_model::error::ActorError;
+    use crate::actor_model::mailbox::Overflow;
+
+    enum CounterMessage {
+        Add(u32),
+        Get,
+        Quit,
+        Panic,
+        /// 等待 gate 被通知后才返回，用来让消息在邮箱中排队
+        Block(Arc<Notify>),
+        Sleep(u64),
+    }
+
+    struct Counter {
+        count: u32,
+        events: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl Actor for Counter {
+        type Message = CounterMessage;
+        type Reply = u32;
+
+        async fn started(&mut self, _ctx: &mut Context<Self>) {
+            self.events.lock().unwrap().push("started".into());
+        }
+
+        async fn handle(&mut self, msg: CounterMessage, ctx: &mut Context<Self>) -> u32 {
+            match msg {
+                CounterMessage::Add(n) => self.count += n,
+                CounterMessage::Get => {}
+                CounterMessage::Quit => ctx.stop(),
+                CounterMessage::Panic => panic!("counter failed"),
+                CounterMessage::Block(gate) => gate.notified().await,
+                CounterMessage::Sleep(ms) => tokio::time::sleep(Duration::from_millis(ms)).await,
+            }
+            self.count
+        }
+
+        async fn stopping(&mut self, _ctx: &mut Context<Self>) {
+            self.events.lock().unwrap().push(format!("stopping {}", self.count));
+        }
+
+        async fn stopped(&mut self, _ctx: &mut Context<Self>) {
+            self.events.lock().unwrap().push("stopped".into());
+        }
+    }
+
+    fn counter() -> (Addr<Counter>, Arc<Mutex<Vec<String>>>) {
+        let events = Arc::new(Mutex::new(vec![]));
+        (spawn(Counter { count: 0, events: events.clone() }), events)
+    }
+
+    async fn wait_stopped<A: Actor>(addr: &Addr<A>) {
+        while addr.is_alive() {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_and_ask() {
+        let (addr, _) = counter();
+        for n in 1..=3 {
+            addr.send(CounterMessage::Add(n)).await.unwrap();
+        }
+        assert_eq!(addr.ask(CounterMessage::Get).await, Ok(6));
+        assert_eq!(addr.clone().ask(CounterMessage::Add(4)).await, Ok(10));
+        assert_ne!(addr.id(), counter().0.id());
+    }
+
+    #[tokio::test]
+    async fn test_lifecycle() {
+        let (addr, events) = counter();
+        addr.send(CounterMessage::Add(2)).await.unwrap();
+        assert_eq!(addr.ask(CounterMessage::Quit).await, Ok(2));
+        wait_stopped(&addr).await;
+        assert_eq!(*events.lock().unwrap(), vec!["started", "stopping 2", "stopped"]);
+        assert_eq!(addr.send(CounterMessage::Get).await, Err(ActorError::Stopped));
+        assert_eq!(addr.ask(CounterMessage::Get).await, Err(ActorError::Stopped));
+    }
+
+    #[tokio::test]
+    async fn test_stop_from_addr() {
+        let (addr, events) = counter();
+        addr.send(CounterMessage::Add(1)).await.unwrap();
+        addr.stop().await;
+        wait_stopped(&addr).await;
+        // 停止请求之前的消息仍被处理
+        assert_eq!(events.lock().unwrap()[1], "stopping 1");
+    }
+
+    #[tokio::test]
+    async fn test_stop_when_addrs_dropped() {
+        let (addr, events) = counter();
+        addr.send(CounterMessage::Add(5)).await.unwrap();
+        drop(addr);
+        while events.lock().unwrap().len() < 3 {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(*events.lock().unwrap(), vec!["started", "stopping 5", "stopped"]);
+    }
+
+    #[tokio::test]
+    async fn test_panic_in_handler() {
+        let (addr, _) = counter();
+        assert_eq!(addr.ask(CounterMessage::Panic).await, Err(ActorError::NoReply));
+        wait_stopped(&addr).await;
+        assert_eq!(addr.send(CounterMessage::Get).await, Err(ActorError::Stopped));
+    }
+
+    #[tokio::test]
+    async fn test_mailbox_policies() {
+        let events = Arc::new(Mutex::new(vec![]));
+        let config = MailboxConfig::bounded(2).overflow(Overflow::DropNewest);
+        let addr = spawn_with(Counter { count: 0, events: events.clone() }, config);
+        let gate = Arc::new(Notify::new());
+
+        addr.send(CounterMessage::Block(gate.clone())).await.unwrap();
+        while addr.mailbox_len() != Some(0) {
+            tokio::task::yield_now().await;
+        }
+        addr.send(CounterMessage::Add(1)).await.unwrap();
+        addr.send(CounterMessage::Add(2)).await.unwrap();
+        assert_eq!(addr.send(CounterMessage::Add(3)).await, Err(ActorError::MailboxFull));
+        assert_eq!(addr.mailbox_len(), Some(2));
+
+        // 优先消息越过排队中的 Add
+        let priority = addr.clone();
+        let get = tokio::spawn(async move { priority.ask_priority(CounterMessage::Get).await });
+        while addr.mailbox_len() != Some(3) {
+            tokio::task::yield_now().await;
+        }
+        gate.notify_one();
+        assert_eq!(get.await.unwrap(), Ok(0));
+        assert_eq!(addr.ask(CounterMessage::Get).await, Ok(3));
+
+        // stop_now 丢弃尚未处理的普通消息
+        addr.send(CounterMessage::Block(gate.clone())).await.unwrap();
+        addr.send(CounterMessage::Add(10)).await.unwrap();
+        addr.stop_now().await;
+        gate.notify_one();
+        wait_stopped(&addr).await;
+        assert_eq!(events.lock().unwrap()[1], "stopping 3");
+    }
+
+    async fn blocked_counter(overflow: Overflow) -> (Addr<Counter>, Arc<Mutex<Vec<String>>>, Arc<Notify>) {
+        let events = Arc::new(Mutex::new(vec![]));
+        let config = MailboxConfig::bounded(2).overflow(overflow);
+        let addr = spawn_with(Counter { count: 0, events: events.clone() }, config);
+        let gate = Arc::new(Notify::new());
+        addr.send(CounterMessage::Block(gate.clone())).await.unwrap();
+        while addr.mailbox_len() != Some(0) {
+            tokio::task::yield_now().await;
+        }
+        (addr, events, gate)
+    }
+
+    #[tokio::test]
+    async fn test_stop_on_full_mailbox() {
+        // 邮箱已满时停止请求仍然排队，之后的消息照常被拒绝
+        let (addr, events, gate) = blocked_counter(Overflow::DropNewest).await;
+        addr.send(CounterMessage::Add(1)).await.unwrap();
+        addr.send(CounterMessage::Add(2)).await.unwrap();
+        addr.stop().await;
+        assert_eq!(addr.send(CounterMessage::Add(3)).await, Err(ActorError::MailboxFull));
+        assert_eq!(addr.mailbox_len(), Some(3));
+        gate.notify_one();
+        wait_stopped(&addr).await;
+        assert_eq!(events.lock().unwrap()[1], "stopping 3");
+
+        // 停止请求不会被之后的消息挤掉
+        let (addr, events, gate) = blocked_counter(Overflow::DropOldest).await;
+        addr.send(CounterMessage::Add(1)).await.unwrap();
+        addr.send(CounterMessage::Add(2)).await.unwrap();
+        addr.stop().await;
+        for n in 3..=5 {
+            addr.send(CounterMessage::Add(n)).await.unwrap();
+        }
+        assert_eq!(addr.mailbox_len(), Some(3));
+        gate.notify_one();
+        wait_stopped(&addr).await;
+        assert_eq!(events.lock().unwrap()[1], "stopping 0");
+    }
+
+    async fn queue_work(addr: &Addr<Counter>, n: usize) {
+        for _ in 0..n {
+            addr.send(CounterMessage::Sleep(10)).await.unwrap();
+            addr.send(CounterMessage::Add(1)).await.unwrap();
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_graceful_shutdown() {
+        let (addr, events) = counter();
+        let join = addr.join_handle();
+        queue_work(&addr, 3).await;
+        assert!(addr.shutdown(Duration::from_millis(100)).await);
+        assert!(join.is_finished());
+        assert_eq!(*events.lock().unwrap(), vec!["started", "stopping 3", "stopped"]);
+        assert_eq!(addr.send(CounterMessage::Get).await, Err(ActorError::Stopped));
+
+        // 超时后丢弃剩余的消息，正在处理的消息仍会完成
+        let (addr, events) = counter();
+        queue_work(&addr, 5).await;
+        assert!(!addr.shutdown(Duration::from_millis(25)).await);
+        assert_eq!(events.lock().unwrap()[1], "stopping 2");
+    }
+}
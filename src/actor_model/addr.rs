@@ -0,0 +1,250 @@
+use std::sync::Arc;
+use std::time::Duration;
+use async_trait::async_trait;
+use tokio::sync::oneshot;
+use crate::actor_model::actor::{Actor, ActorId, Envelope};
+use crate::actor_model::error::ActorError;
+use crate::actor_model::mailbox::{Priority, Sender, WeakSender};
+use crate::actor_model::metrics::{self, ActorSnapshot};
+
+///
+/// 远程 actor 的投递通道，由 remote 模块实现
+///
+#[async_trait]
+pub(crate) trait Link<A: Actor>: Send + Sync {
+    async fn send(&self, msg: A::Message, priority: Priority) -> Result<(), ActorError>;
+    async fn ask(&self, msg: A::Message, priority: Priority) -> Result<A::Reply, ActorError>;
+    async fn stop(&self, priority: Priority);
+    fn is_alive(&self) -> bool;
+    async fn closed(&self);
+}
+
+enum Inner<A: Actor> {
+    Local(Sender<Envelope<A>>),
+    Remote(Arc<dyn Link<A>>),
+}
+
+///
+/// actor 的地址，可以克隆后在多个任务间共享
+/// 所有 Addr 都被释放后 actor 处理完邮箱中剩余的消息便会停止
+/// 远程 actor 的 Addr 用法与本地相同，投递失败时返回 ActorError::Delivery
+///
+pub struct Addr<A: Actor> {
+    id: ActorId,
+    inner: Inner<A>,
+}
+
+impl<A: Actor> Addr<A> {
+    pub(crate) fn new(id: ActorId, sender: Sender<Envelope<A>>) -> Addr<A> {
+        Addr { id, inner: Inner::Local(sender) }
+    }
+
+    pub(crate) fn remote(link: Arc<dyn Link<A>>) -> Addr<A> {
+        Addr { id: ActorId::next(), inner: Inner::Remote(link) }
+    }
+
+    pub fn id(&self) -> ActorId {
+        self.id
+    }
+
+    pub fn is_local(&self) -> bool {
+        matches!(self.inner, Inner::Local(_))
+    }
+
+    ///
+    /// 投递消息但不等待回复，邮箱已满时按邮箱的 Overflow 配置等待或丢弃
+    ///
+    pub async fn send(&self, msg: A::Message) -> Result<(), ActorError> {
+        self.send_with(msg, Priority::Normal).await
+    }
+
+    ///
+    /// 经优先队列投递，排在普通消息之前处理
+    ///
+    pub async fn send_priority(&self, msg: A::Message) -> Result<(), ActorError> {
+        self.send_with(msg, Priority::High).await
+    }
+
+    ///
+    /// 投递消息并等待 handle 的返回值
+    ///
+    pub async fn ask(&self, msg: A::Message) -> Result<A::Reply, ActorError> {
+        self.ask_with(msg, Priority::Normal).await
+    }
+
+    pub async fn ask_priority(&self, msg: A::Message) -> Result<A::Reply, ActorError> {
+        self.ask_with(msg, Priority::High).await
+    }
+
+    async fn send_with(&self, msg: A::Message, priority: Priority) -> Result<(), ActorError> {
+        match &self.inner {
+            Inner::Local(sender) => sender.send(Envelope::Message { msg, reply: None }, priority).await,
+            Inner::Remote(link) => link.send(msg, priority).await,
+        }
+    }
+
+    async fn ask_with(&self, msg: A::Message, priority: Priority) -> Result<A::Reply, ActorError> {
+        match &self.inner {
+            Inner::Local(_) => self.enqueue(msg, priority).await?.await.map_err(|_| ActorError::NoReply),
+            Inner::Remote(link) => link.ask(msg, priority).await,
+        }
+    }
+
+    ///
+    /// 把需要回复的消息放进邮箱后立即返回，用于在保持投递顺序的同时并发等待回复
+    /// 远程地址无法分开这两步，改为在后台任务中等待，失败时表现为接收端被丢弃
+    ///
+    pub(crate) async fn enqueue(&self, msg: A::Message, priority: Priority) -> Result<oneshot::Receiver<A::Reply>, ActorError> {
+        let (tx, rx) = oneshot::channel();
+        match &self.inner {
+            Inner::Local(sender) => sender.send(Envelope::Message { msg, reply: Some(tx) }, priority).await?,
+            Inner::Remote(link) => {
+                let link = link.clone();
+                tokio::spawn(async move {
+                    if let Ok(reply) = link.ask(msg, priority).await {
+                        let _ = tx.send(reply);
+                    }
+                });
+            }
+        }
+        Ok(rx)
+    }
+
+    ///
+    /// 请求 actor 停止，排在停止请求之前的消息仍会被处理
+    ///
+    pub async fn stop(&self) {
+        self.stop_with(Priority::Normal).await
+    }
+
+    ///
+    /// 经优先队列请求停止，邮箱中尚未处理的普通消息被丢弃
+    ///
+    pub async fn stop_now(&self) {
+        self.stop_with(Priority::High).await
+    }
+
+    async fn stop_with(&self, priority: Priority) {
+        match &self.inner {
+            // 停止请求不受普通队列的容量限制，也不会被溢出策略丢弃
+            Inner::Local(sender) => {
+                let _ = match priority {
+                    Priority::Normal => sender.send_control(Envelope::Stop),
+                    Priority::High => sender.send(Envelope::Stop, priority).await,
+                };
+            }
+            Inner::Remote(link) => link.stop(priority).await,
+        }
+    }
+
+    ///
+    /// 不再接收新消息，处理完邮箱中已有的消息后停止；超过 timeout 时丢弃剩余的消息
+    /// 在 actor 完全停止后返回，按时排空邮箱时返回 true
+    /// 远程地址只发送普通的停止请求
+    ///
+    pub async fn shutdown(&self, timeout: Duration) -> bool {
+        match &self.inner {
+            Inner::Local(sender) => sender.shutdown(timeout).await,
+            Inner::Remote(link) => {
+                link.stop(Priority::Normal).await;
+                true
+            }
+        }
+    }
+
+    ///
+    /// 等待 actor 完全停止的句柄，它不会阻止 actor 在所有 Addr 释放后停止
+    ///
+    pub fn join_handle(&self) -> JoinHandle<A> {
+        let inner = match &self.inner {
+            Inner::Local(sender) => JoinInner::Local(sender.downgrade()),
+            Inner::Remote(link) => JoinInner::Remote(link.clone()),
+        };
+        JoinHandle { inner }
+    }
+
+    ///
+    /// actor 的统计快照，actor 已经停止或是远程地址时返回 None
+    ///
+    pub fn metrics(&self) -> Option<ActorSnapshot> {
+        match &self.inner {
+            Inner::Local(_) => metrics::snapshot_of(self.id),
+            Inner::Remote(_) => None,
+        }
+    }
+
+    ///
+    /// 邮箱中等待处理的消息数量，远程地址返回 None
+    ///
+    pub fn mailbox_len(&self) -> Option<usize> {
+        match &self.inner {
+            Inner::Local(sender) => Some(sender.len()),
+            Inner::Remote(_) => None,
+        }
+    }
+
+    ///
+    /// 远程地址只反映连接是否仍然可用
+    ///
+    pub fn is_alive(&self) -> bool {
+        match &self.inner {
+            Inner::Local(sender) => !sender.is_closed(),
+            Inner::Remote(link) => link.is_alive(),
+        }
+    }
+
+    ///
+    /// 等待 actor 停止，远程地址等待连接断开
+    ///
+    pub async fn closed(&self) {
+        match &self.inner {
+            Inner::Local(sender) => sender.closed().await,
+            Inner::Remote(link) => link.closed().await,
+        }
+    }
+}
+
+impl<A: Actor> Clone for Addr<A> {
+    fn clone(&self) -> Self {
+        let inner = match &self.inner {
+            Inner::Local(sender) => Inner::Local(sender.clone()),
+            Inner::Remote(link) => Inner::Remote(link.clone()),
+        };
+        Addr { id: self.id, inner }
+    }
+}
+
+impl<A: Actor> std::fmt::Debug for Addr<A> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Addr").field("id", &self.id).field("local", &self.is_local()).finish()
+    }
+}
+
+enum JoinInner<A: Actor> {
+    Local(WeakSender<Envelope<A>>),
+    Remote(Arc<dyn Link<A>>),
+}
+
+///
+/// actor 的 stopped 钩子执行完毕后 join 返回；被监督的 actor 重启时不算停止
+/// 远程地址在连接断开时返回
+///
+pub struct JoinHandle<A: Actor> {
+    inner: JoinInner<A>,
+}
+
+impl<A: Actor> JoinHandle<A> {
+    pub async fn join(&self) {
+        match &self.inner {
+            JoinInner::Local(sender) => sender.terminated().await,
+            JoinInner::Remote(link) => link.closed().await,
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        match &self.inner {
+            JoinInner::Local(sender) => sender.is_terminated(),
+            JoinInner::Remote(link) => !link.is_alive(),
+        }
+    }
+}
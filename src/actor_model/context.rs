@@ -0,0 +1,277 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::task::{AbortHandle, JoinHandle};
+use tokio::time::{self, Instant};
+use crate::actor_model::actor::{Actor, ActorId, Envelope};
+use crate::actor_model::addr::Addr;
+use crate::actor_model::error::ActorError;
+use crate::actor_model::mailbox::{Priority, WeakSender};
+
+///
+/// 定时器编号，用于 Context::cancel
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct TimerId(u64);
+
+///
+/// actor 运行时的上下文，在生命周期钩子与 handle 中使用
+///
+pub struct Context<A: Actor> {
+    id: ActorId,
+    /// 只持有弱引用，避免 actor 自己阻止自己在所有 Addr 释放后停止
+    sender: WeakSender<Envelope<A>>,
+    stopping: bool,
+    timers: HashMap<TimerId, AbortHandle>,
+    next_timer: u64,
+}
+
+impl<A: Actor> Context<A> {
+    pub(crate) fn new(id: ActorId, sender: WeakSender<Envelope<A>>) -> Context<A> {
+        Context { id, sender, stopping: false, timers: HashMap::new(), next_timer: 0 }
+    }
+
+    pub fn id(&self) -> ActorId {
+        self.id
+    }
+
+    ///
+    /// actor 自己的地址，所有 Addr 都已释放时返回 None
+    ///
+    pub fn address(&self) -> Option<Addr<A>> {
+        self.sender.upgrade().map(|sender| Addr::new(self.id, sender))
+    }
+
+    ///
+    /// 处理完当前消息后停止，邮箱中剩余的消息被丢弃
+    ///
+    pub fn stop(&mut self) {
+        self.stopping = true;
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        self.stopping
+    }
+
+    ///
+    /// 邮箱中等待处理的消息数量
+    ///
+    pub fn mailbox_len(&self) -> usize {
+        self.sender.len()
+    }
+
+    ///
+    /// delay 之后把 msg 发给自己
+    ///
+    pub fn run_later(&mut self, delay: Duration, msg: A::Message) -> TimerId {
+        let sender = self.sender.clone();
+        self.add_timer(tokio::spawn(async move {
+            time::sleep(delay).await;
+            send_to_self(&sender, msg).await;
+        }))
+    }
+
+    ///
+    /// 每隔 period 把 message 生成的消息发给自己，第一次在 period 之后
+    ///
+    pub fn run_interval<F>(&mut self, period: Duration, message: F) -> TimerId
+        where
+            F: Fn() -> A::Message + Send + 'static
+    {
+        let sender = self.sender.clone();
+        self.add_timer(tokio::spawn(async move {
+            let mut interval = time::interval_at(Instant::now() + period, period);
+            loop {
+                interval.tick().await;
+                if !send_to_self(&sender, message()).await {
+                    break;
+                }
+            }
+        }))
+    }
+
+    ///
+    /// 取消定时器，定时器仍在等待时返回 true
+    ///
+    pub fn cancel(&mut self, timer: TimerId) -> bool {
+        match self.timers.remove(&timer) {
+            Some(handle) => {
+                let pending = !handle.is_finished();
+                handle.abort();
+                pending
+            }
+            None => false,
+        }
+    }
+
+    ///
+    /// 仍在等待的定时器数量
+    ///
+    pub fn timers(&self) -> usize {
+        self.timers.values().filter(|handle| !handle.is_finished()).count()
+    }
+
+    ///
+    /// actor 停止时取消全部定时器
+    ///
+    pub(crate) fn cancel_timers(&mut self) {
+        for (_, handle) in self.timers.drain() {
+            handle.abort();
+        }
+    }
+
+    fn add_timer(&mut self, task: JoinHandle<()>) -> TimerId {
+        self.timers.retain(|_, handle| !handle.is_finished());
+        self.next_timer += 1;
+        let id = TimerId(self.next_timer);
+        self.timers.insert(id, task.abort_handle());
+        id
+    }
+}
+
+///
+/// handle 中 panic 时不会执行到 run 末尾的 cancel_timers，在释放上下文时取消，
+/// 避免监督者重启后旧实例的定时器继续向邮箱发送消息
+///
+impl<A: Actor> Drop for Context<A> {
+    fn drop(&mut self) {
+        self.cancel_timers();
+    }
+}
+
+///
+/// actor 已经停止时返回 false，邮箱已满而被丢弃的消息不影响之后的定时
+///
+async fn send_to_self<A: Actor>(sender: &WeakSender<Envelope<A>>, msg: A::Message) -> bool {
+    match sender.upgrade() {
+        Some(sender) => sender.send(Envelope::Message { msg, reply: None }, Priority::Normal).await != Err(ActorError::Stopped),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use async_trait::async_trait;
+    use crate::actor_model::actor;
+    use crate::actor_model::error::ActorError;
+    use crate::actor_model::supervisor::{Strategy, Supervisor};
+
+    enum TimerMessage {
+        Tick,
+        Later(&'static str),
+        Schedule(u64, &'static str),
+        Cancel,
+        Get,
+        Panic,
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum TimerReply {
+        Done,
+        State { ticks: usize, received: Vec<&'static str>, timers: usize },
+    }
+
+    #[derive(Default)]
+    struct Scheduler {
+        ticks: usize,
+        received: Vec<&'static str>,
+        pending: Option<TimerId>,
+        /// 生成 Tick 消息的次数，actor 停止后不应继续增长
+        generated: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl Actor for Scheduler {
+        type Message = TimerMessage;
+        type Reply = TimerReply;
+
+        async fn started(&mut self, ctx: &mut Context<Self>) {
+            let generated = self.generated.clone();
+            ctx.run_interval(Duration::from_millis(10), move || {
+                generated.fetch_add(1, Ordering::SeqCst);
+                TimerMessage::Tick
+            });
+        }
+
+        async fn handle(&mut self, msg: TimerMessage, ctx: &mut Context<Self>) -> TimerReply {
+            match msg {
+                TimerMessage::Tick => self.ticks += 1,
+                TimerMessage::Later(name) => self.received.push(name),
+                TimerMessage::Schedule(ms, name) => {
+                    self.pending = Some(ctx.run_later(Duration::from_millis(ms), TimerMessage::Later(name)));
+                }
+                TimerMessage::Cancel => {
+                    if let Some(timer) = self.pending.take() {
+                        assert!(ctx.cancel(timer));
+                        assert!(!ctx.cancel(timer));
+                    }
+                }
+                TimerMessage::Get => {
+                    return TimerReply::State { ticks: self.ticks, received: self.received.clone(), timers: ctx.timers() };
+                }
+                TimerMessage::Panic => panic!("scheduler failed"),
+            }
+            TimerReply::Done
+        }
+    }
+
+    fn state(ticks: usize, received: Vec<&'static str>, timers: usize) -> TimerReply {
+        TimerReply::State { ticks, received, timers }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_interval_and_delay() {
+        let addr = actor::spawn(Scheduler::default());
+        time::sleep(Duration::from_millis(35)).await;
+        assert_eq!(addr.ask(TimerMessage::Get).await, Ok(state(3, vec![], 1)));
+
+        addr.send(TimerMessage::Schedule(50, "first")).await.unwrap();
+        time::sleep(Duration::from_millis(49)).await;
+        assert_eq!(addr.ask(TimerMessage::Get).await, Ok(state(8, vec![], 2)));
+        time::sleep(Duration::from_millis(2)).await;
+        assert_eq!(addr.ask(TimerMessage::Get).await, Ok(state(8, vec!["first"], 1)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_cancel_timer() {
+        let addr = actor::spawn(Scheduler::default());
+        addr.send(TimerMessage::Schedule(20, "cancelled")).await.unwrap();
+        addr.send(TimerMessage::Cancel).await.unwrap();
+        time::sleep(Duration::from_millis(25)).await;
+        assert_eq!(addr.ask(TimerMessage::Get).await, Ok(state(2, vec![], 1)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_timers_cancelled_on_stop() {
+        let generated = Arc::new(AtomicUsize::new(0));
+        let addr = actor::spawn(Scheduler { generated: generated.clone(), ..Scheduler::default() });
+        addr.send(TimerMessage::Schedule(100, "never")).await.unwrap();
+        time::sleep(Duration::from_millis(15)).await;
+        addr.stop().await;
+        addr.closed().await;
+        assert_eq!(generated.load(Ordering::SeqCst), 1);
+
+        time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(generated.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_timers_cancelled_on_panic() {
+        let generated = Arc::new(AtomicUsize::new(0));
+        let mut supervisor = Supervisor::new(Strategy::OneForOne);
+        let factory_generated = generated.clone();
+        let addr = supervisor.child(move || Scheduler { generated: factory_generated.clone(), ..Scheduler::default() });
+        let handle = supervisor.start();
+        time::sleep(Duration::from_millis(35)).await;
+        assert_eq!(addr.ask(TimerMessage::Panic).await, Err(ActorError::NoReply));
+
+        // 只有重启后的实例在计时，旧实例的定时器随上下文一起取消
+        time::sleep(Duration::from_millis(95)).await;
+        assert_eq!(addr.ask(TimerMessage::Get).await, Ok(state(9, vec![], 1)));
+        assert_eq!(generated.load(Ordering::SeqCst), 12);
+        assert_eq!(handle.restarts(), 1);
+        handle.stop().await;
+    }
+}
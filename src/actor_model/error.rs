@@ -0,0 +1,33 @@
+use std::fmt;
+use serde::{Deserialize, Serialize};
+
+///
+/// 向 actor 投递消息时产生的错误
+///
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ActorError {
+    /// actor 已经停止，邮箱不再接收消息
+    Stopped,
+    /// actor 在回复之前停止，或处理消息时 panic
+    NoReply,
+    /// 名称已经被另一个运行中的 actor 注册
+    NameTaken(String),
+    /// 邮箱已满且配置为丢弃新消息
+    MailboxFull,
+    /// 远程投递失败：连接断开、对端没有导出该名称或消息无法序列化
+    Delivery(String),
+}
+
+impl fmt::Display for ActorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ActorError::Stopped => write!(f, "actor stopped"),
+            ActorError::NoReply => write!(f, "actor stopped before replying"),
+            ActorError::NameTaken(name) => write!(f, "name already registered: {}", name),
+            ActorError::MailboxFull => write!(f, "mailbox full"),
+            ActorError::Delivery(reason) => write!(f, "delivery failed: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for ActorError {}
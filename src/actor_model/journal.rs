@@ -0,0 +1,273 @@
+//!
+//! 持久化 actor 使用的事件日志与快照存储
+//! 事件以 JSON 保存，按 persistence_id 分成独立的事件流，序号从 1 开始连续递增。
+//! 快照相当于备忘录模式中的 Memento，Journal 则是保管它的 CareTaker。
+//!
+
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::fs::{self, OpenOptions};
+use tokio::io::AsyncWriteExt;
+
+///
+/// 某个序号时的状态快照，恢复时只需重放之后的事件
+///
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub sequence: u64,
+    pub state: Value,
+}
+
+#[async_trait]
+pub trait Journal: Send + Sync + 'static {
+    ///
+    /// 在事件流末尾追加事件，返回最后一个事件的序号
+    /// 返回错误时这一批事件都不应被之后的 read 读到
+    ///
+    async fn append(&self, persistence_id: &str, events: Vec<Value>) -> io::Result<u64>;
+
+    ///
+    /// 读取序号大于 after 的全部事件
+    ///
+    async fn read(&self, persistence_id: &str, after: u64) -> io::Result<Vec<Value>>;
+
+    ///
+    /// 保存快照，替换之前的快照
+    ///
+    async fn save_snapshot(&self, persistence_id: &str, snapshot: Snapshot) -> io::Result<()>;
+
+    async fn load_snapshot(&self, persistence_id: &str) -> io::Result<Option<Snapshot>>;
+}
+
+#[derive(Default)]
+struct Stream {
+    events: Vec<Value>,
+    snapshot: Option<Snapshot>,
+}
+
+///
+/// 保存在内存中的日志，进程退出后丢失，用于测试
+///
+#[derive(Default)]
+pub struct InMemoryJournal {
+    streams: Mutex<HashMap<String, Stream>>,
+}
+
+impl InMemoryJournal {
+    pub fn new() -> InMemoryJournal {
+        InMemoryJournal::default()
+    }
+}
+
+#[async_trait]
+impl Journal for InMemoryJournal {
+    async fn append(&self, persistence_id: &str, events: Vec<Value>) -> io::Result<u64> {
+        let mut streams = self.streams.lock().unwrap();
+        let stream = streams.entry(persistence_id.to_string()).or_default();
+        stream.events.extend(events);
+        Ok(stream.events.len() as u64)
+    }
+
+    async fn read(&self, persistence_id: &str, after: u64) -> io::Result<Vec<Value>> {
+        let streams = self.streams.lock().unwrap();
+        Ok(streams
+            .get(persistence_id)
+            .map(|stream| stream.events.iter().skip(after as usize).cloned().collect())
+            .unwrap_or_default())
+    }
+
+    async fn save_snapshot(&self, persistence_id: &str, snapshot: Snapshot) -> io::Result<()> {
+        let mut streams = self.streams.lock().unwrap();
+        streams.entry(persistence_id.to_string()).or_default().snapshot = Some(snapshot);
+        Ok(())
+    }
+
+    async fn load_snapshot(&self, persistence_id: &str) -> io::Result<Option<Snapshot>> {
+        let streams = self.streams.lock().unwrap();
+        Ok(streams.get(persistence_id).and_then(|stream| stream.snapshot.clone()))
+    }
+}
+
+///
+/// 以只追加文件保存的日志，每个事件流对应目录下的两个文件：
+/// <id>.events 每行一个事件，<id>.snapshot 保存最近的快照
+///
+pub struct FileJournal {
+    dir: PathBuf,
+    /// 各事件流当前的序号，第一次追加时从文件读出；持有锁期间写入，保证追加按顺序进行
+    sequences: tokio::sync::Mutex<HashMap<String, u64>>,
+}
+
+impl FileJournal {
+    ///
+    /// 目录不存在时创建
+    ///
+    pub async fn open<P: AsRef<Path>>(dir: P) -> io::Result<FileJournal> {
+        fs::create_dir_all(dir.as_ref()).await?;
+        Ok(FileJournal { dir: dir.as_ref().to_path_buf(), sequences: Default::default() })
+    }
+
+    fn path(&self, persistence_id: &str, extension: &str) -> PathBuf {
+        self.dir.join(format!("{}.{}", file_name(persistence_id), extension))
+    }
+}
+
+///
+/// 把 persistence_id 转成安全的文件名，字母、数字、'-' 与 '_' 之外的字节按十六进制转义
+///
+fn file_name(persistence_id: &str) -> String {
+    persistence_id
+        .bytes()
+        .map(|b| match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+///
+/// 读取事件文件，只保留完整的行；文件不存在时为空
+///
+async fn read_lines(path: &Path) -> io::Result<(Vec<u8>, usize)> {
+    let mut data = match fs::read(path).await {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok((vec![], 0)),
+        Err(e) => return Err(e),
+    };
+    let len = data.len();
+    let complete = data.iter().rposition(|b| *b == b'\n').map(|i| i + 1).unwrap_or(0);
+    data.truncate(complete);
+    Ok((data, len))
+}
+
+fn lines(data: &[u8]) -> impl Iterator<Item = &[u8]> {
+    data.split(|b| *b == b'\n').filter(|line| !line.is_empty())
+}
+
+fn invalid_data<E: ToString>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+#[async_trait]
+impl Journal for FileJournal {
+    async fn append(&self, persistence_id: &str, events: Vec<Value>) -> io::Result<u64> {
+        let mut data = Vec::new();
+        for event in &events {
+            data.extend(serde_json::to_vec(event).map_err(invalid_data)?);
+            data.push(b'\n');
+        }
+        let path = self.path(persistence_id, "events");
+        let mut sequences = self.sequences.lock().await;
+        // 写入失败时不缓存序号，下次追加重新读取
+        let sequence = match sequences.remove(persistence_id) {
+            Some(sequence) => sequence,
+            None => {
+                // 写入中途崩溃会在末尾留下不完整的一行，持有锁时截掉，不会与其他追加同时进行
+                let (data, len) = read_lines(&path).await?;
+                if data.len() < len {
+                    OpenOptions::new().write(true).open(&path).await?.set_len(data.len() as u64).await?;
+                }
+                lines(&data).count() as u64
+            }
+        };
+        let mut file = OpenOptions::new().create(true).append(true).open(&path).await?;
+        // 一批事件要么全部写入要么都不写入，失败时截回写入前的长度，
+        // 否则已经写出的完整行会在恢复时被当作成功的事件重放
+        let len = file.metadata().await?.len();
+        let written = match file.write_all(&data).await {
+            Ok(()) => file.sync_data().await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = written {
+            let _ = file.set_len(len).await;
+            return Err(e);
+        }
+        let sequence = sequence + events.len() as u64;
+        sequences.insert(persistence_id.to_string(), sequence);
+        Ok(sequence)
+    }
+
+    ///
+    /// 不完整的末行可能是崩溃留下的，也可能正在写入，读取时忽略它
+    ///
+    async fn read(&self, persistence_id: &str, after: u64) -> io::Result<Vec<Value>> {
+        let (data, _) = read_lines(&self.path(persistence_id, "events")).await?;
+        lines(&data)
+            .skip(after as usize)
+            .map(|line| serde_json::from_slice(line).map_err(invalid_data))
+            .collect()
+    }
+
+    ///
+    /// 先写临时文件再改名，避免崩溃时留下不完整的快照
+    ///
+    async fn save_snapshot(&self, persistence_id: &str, snapshot: Snapshot) -> io::Result<()> {
+        let path = self.path(persistence_id, "snapshot");
+        let temp = self.path(persistence_id, "snapshot.tmp");
+        let mut file = fs::File::create(&temp).await?;
+        file.write_all(&serde_json::to_vec(&snapshot).map_err(invalid_data)?).await?;
+        file.sync_data().await?;
+        fs::rename(&temp, &path).await
+    }
+
+    async fn load_snapshot(&self, persistence_id: &str) -> io::Result<Option<Snapshot>> {
+        match fs::read(self.path(persistence_id, "snapshot")).await {
+            Ok(data) => serde_json::from_slice(&data).map(Some).map_err(invalid_data),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    async fn exercise(journal: &dyn Journal) {
+        assert!(journal.read("counter/1", 0).await.unwrap().is_empty());
+        assert_eq!(journal.append("counter/1", vec![json!(1), json!(2)]).await.unwrap(), 2);
+        assert_eq!(journal.append("counter/1", vec![json!({"add": 3})]).await.unwrap(), 3);
+        assert_eq!(journal.append("counter/2", vec![json!(10)]).await.unwrap(), 1);
+        assert_eq!(journal.read("counter/1", 1).await.unwrap(), vec![json!(2), json!({"add": 3})]);
+
+        assert_eq!(journal.load_snapshot("counter/1").await.unwrap(), None);
+        let snapshot = Snapshot { sequence: 2, state: json!(3) };
+        journal.save_snapshot("counter/1", snapshot.clone()).await.unwrap();
+        assert_eq!(journal.load_snapshot("counter/1").await.unwrap(), Some(snapshot));
+        assert_eq!(journal.load_snapshot("counter/2").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_journal() {
+        exercise(&InMemoryJournal::new()).await;
+    }
+
+    #[tokio::test]
+    async fn test_file_journal() {
+        let dir = std::env::temp_dir().join(format!("journal-{}", uuid::Uuid::new_v4()));
+        let journal = FileJournal::open(&dir).await.unwrap();
+        exercise(&journal).await;
+        assert!(dir.join("counter%2F1.events").exists());
+
+        // 重新打开后数据仍在，不完整的末行被丢弃
+        let journal = FileJournal::open(&dir).await.unwrap();
+        let mut file = OpenOptions::new().append(true).open(dir.join("counter%2F2.events")).await.unwrap();
+        file.write_all(b"{\"torn\":").await.unwrap();
+        let events = dir.join("counter%2F2.events");
+        let len = fs::metadata(&events).await.unwrap().len();
+        // 读取不修改文件，末行由追加时截掉
+        assert_eq!(journal.read("counter/2", 0).await.unwrap(), vec![json!(10)]);
+        assert_eq!(fs::metadata(&events).await.unwrap().len(), len);
+        assert_eq!(journal.append("counter/2", vec![json!(11)]).await.unwrap(), 2);
+        assert_eq!(journal.read("counter/2", 0).await.unwrap(), vec![json!(10), json!(11)]);
+        assert_eq!(journal.read("counter/1", 0).await.unwrap().len(), 3);
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
@@ -0,0 +1,511 @@
+//!
+//! actor 的邮箱：普通消息与优先消息两条队列，接收时优先队列中的消息先出队
+//! 普通队列可以有界或无界，满时按 Overflow 等待空位或丢弃消息；
+//! 优先队列不设上限也不丢弃，留给停止、统计之类的控制消息使用。
+//! 经普通队列发送的控制消息保持与普通消息的先后顺序，但不占容量也不会被丢弃。
+//!
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+use tokio::time;
+use crate::actor_model::actor::DEFAULT_MAILBOX_CAPACITY;
+use crate::actor_model::error::ActorError;
+use crate::actor_model::metrics::{self, ActorMetrics, ActorSnapshot, Probe};
+
+///
+/// 消息进入的队列
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum Priority {
+    #[default]
+    Normal,
+    High,
+}
+
+///
+/// 有界邮箱已满时的处理方式
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Overflow {
+    /// send 等待空位
+    BackPressure,
+    /// 丢弃新消息，send 返回 MailboxFull
+    DropNewest,
+    /// 丢弃最早的普通消息，被丢弃的 ask 得到 NoReply
+    DropOldest,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MailboxConfig {
+    capacity: Option<usize>,
+    overflow: Overflow,
+}
+
+impl MailboxConfig {
+    ///
+    /// 普通队列最多容纳 capacity 条消息，默认满时等待空位
+    ///
+    pub fn bounded(capacity: usize) -> MailboxConfig {
+        assert!(capacity > 0, "mailbox capacity must be greater than 0");
+        MailboxConfig { capacity: Some(capacity), overflow: Overflow::BackPressure }
+    }
+
+    pub fn unbounded() -> MailboxConfig {
+        MailboxConfig { capacity: None, overflow: Overflow::BackPressure }
+    }
+
+    ///
+    /// 无界邮箱不会满，设置不起作用
+    ///
+    pub fn overflow(mut self, overflow: Overflow) -> MailboxConfig {
+        self.overflow = overflow;
+        self
+    }
+
+    pub fn capacity(&self) -> Option<usize> {
+        self.capacity
+    }
+}
+
+impl Default for MailboxConfig {
+    fn default() -> Self {
+        MailboxConfig::bounded(DEFAULT_MAILBOX_CAPACITY)
+    }
+}
+
+struct Queue<T> {
+    high: VecDeque<T>,
+    /// 为 true 的是控制消息
+    normal: VecDeque<(T, bool)>,
+    /// 普通队列中控制消息的数量
+    controls: usize,
+    closed: bool,
+    /// 不再接收新消息，取完剩余的消息后结束
+    draining: bool,
+    /// actor 已经完全停止
+    terminated: bool,
+}
+
+struct Shared<T> {
+    queue: Mutex<Queue<T>>,
+    config: MailboxConfig,
+    senders: AtomicUsize,
+    /// 有新消息或发送端全部释放
+    readable: Notify,
+    /// 普通队列出现空位或邮箱关闭
+    writable: Notify,
+    closed: Notify,
+    terminated: Notify,
+    /// 随邮箱保存的统计，监督者重启 actor 后继续累计
+    metrics: Arc<ActorMetrics>,
+}
+
+impl<T> Shared<T> {
+    fn len(&self) -> usize {
+        let queue = self.queue.lock().unwrap();
+        queue.high.len() + queue.normal.len()
+    }
+
+    fn is_closed(&self) -> bool {
+        self.queue.lock().unwrap().closed
+    }
+
+    fn drain(&self) {
+        self.queue.lock().unwrap().draining = true;
+        self.readable.notify_one();
+        self.writable.notify_waiters();
+    }
+
+    ///
+    /// 关闭邮箱并丢弃其中剩余的消息，之后的 send 返回 Stopped
+    ///
+    fn close(&self) {
+        let (high, normal) = {
+            let mut queue = self.queue.lock().unwrap();
+            queue.closed = true;
+            queue.controls = 0;
+            (std::mem::take(&mut queue.high), std::mem::take(&mut queue.normal))
+        };
+        drop((high, normal));
+        self.readable.notify_one();
+        self.writable.notify_waiters();
+        self.closed.notify_waiters();
+    }
+
+    fn terminate(&self) {
+        self.queue.lock().unwrap().terminated = true;
+        self.terminated.notify_waiters();
+        metrics::unregister(self.metrics.id());
+    }
+
+    fn is_terminated(&self) -> bool {
+        self.queue.lock().unwrap().terminated
+    }
+
+    async fn terminated(&self) {
+        loop {
+            let terminated = self.terminated.notified();
+            tokio::pin!(terminated);
+            terminated.as_mut().enable();
+            if self.is_terminated() {
+                return;
+            }
+            terminated.await;
+        }
+    }
+
+    ///
+    /// 先排空邮箱，超过 timeout 时丢弃剩余的消息，在 actor 完全停止后返回
+    /// 正在处理的消息总会完成；在 timeout 之内排空时返回 true
+    ///
+    async fn shutdown(&self, timeout: Duration) -> bool {
+        self.drain();
+        if time::timeout(timeout, self.terminated()).await.is_ok() {
+            return true;
+        }
+        self.close();
+        self.terminated().await;
+        false
+    }
+}
+
+impl<T: Send> Probe for Shared<T> {
+    fn snapshot(&self) -> ActorSnapshot {
+        self.metrics.snapshot(self.len())
+    }
+}
+
+pub(crate) fn channel<T>(config: MailboxConfig, metrics: Arc<ActorMetrics>) -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        queue: Mutex::new(Queue {
+            high: VecDeque::new(),
+            normal: VecDeque::new(),
+            controls: 0,
+            closed: false,
+            draining: false,
+            terminated: false,
+        }),
+        config,
+        senders: AtomicUsize::new(1),
+        readable: Notify::new(),
+        writable: Notify::new(),
+        closed: Notify::new(),
+        terminated: Notify::new(),
+        metrics,
+    });
+    (Sender { shared: shared.clone() }, Receiver { shared })
+}
+
+///
+/// 发送端全部释放后，接收端取完剩余的消息便返回 None
+///
+pub(crate) struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Sender<T> {
+    pub(crate) async fn send(&self, value: T, priority: Priority) -> Result<(), ActorError> {
+        loop {
+            let writable = self.shared.writable.notified();
+            tokio::pin!(writable);
+            writable.as_mut().enable();
+            {
+                let mut queue = self.shared.queue.lock().unwrap();
+                if queue.closed || queue.draining {
+                    return Err(ActorError::Stopped);
+                }
+                let full = priority == Priority::Normal
+                    && matches!(self.shared.config.capacity, Some(capacity) if queue.normal.len() - queue.controls >= capacity);
+                if !full {
+                    match priority {
+                        Priority::Normal => queue.normal.push_back((value, false)),
+                        Priority::High => queue.high.push_back(value),
+                    }
+                    drop(queue);
+                    self.shared.readable.notify_one();
+                    return Ok(());
+                }
+                match self.shared.config.overflow {
+                    Overflow::DropNewest => return Err(ActorError::MailboxFull),
+                    Overflow::DropOldest => {
+                        // 队列已满时至少有一条普通消息，控制消息不会被丢弃
+                        let oldest = queue.normal
+                            .iter()
+                            .position(|(_, control)| !control)
+                            .and_then(|i| queue.normal.remove(i));
+                        queue.normal.push_back((value, false));
+                        drop(queue);
+                        drop(oldest);
+                        self.shared.readable.notify_one();
+                        return Ok(());
+                    }
+                    Overflow::BackPressure => {}
+                }
+            }
+            writable.await;
+        }
+    }
+
+    ///
+    /// 把控制消息排在普通队列末尾，不受容量限制，也不会被 DropOldest 丢弃
+    ///
+    pub(crate) fn send_control(&self, value: T) -> Result<(), ActorError> {
+        let mut queue = self.shared.queue.lock().unwrap();
+        if queue.closed || queue.draining {
+            return Err(ActorError::Stopped);
+        }
+        queue.normal.push_back((value, true));
+        queue.controls += 1;
+        drop(queue);
+        self.shared.readable.notify_one();
+        Ok(())
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.shared.len()
+    }
+
+    pub(crate) fn is_closed(&self) -> bool {
+        self.shared.is_closed()
+    }
+
+    ///
+    /// 等待接收端关闭
+    ///
+    pub(crate) async fn closed(&self) {
+        loop {
+            let closed = self.shared.closed.notified();
+            tokio::pin!(closed);
+            closed.as_mut().enable();
+            if self.is_closed() {
+                return;
+            }
+            closed.await;
+        }
+    }
+
+    pub(crate) fn downgrade(&self) -> WeakSender<T> {
+        WeakSender { shared: self.shared.clone() }
+    }
+
+    pub(crate) async fn shutdown(&self, timeout: Duration) -> bool {
+        self.shared.shutdown(timeout).await
+    }
+}
+
+impl<T: Send + 'static> Sender<T> {
+    pub(crate) fn probe(&self) -> Arc<dyn Probe> {
+        self.shared.clone()
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.senders.fetch_add(1, Ordering::SeqCst);
+        Sender { shared: self.shared.clone() }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if self.shared.senders.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.shared.readable.notify_one();
+        }
+    }
+}
+
+///
+/// 不计入发送端数量的引用，不会阻止接收端在所有 Sender 释放后结束
+///
+pub(crate) struct WeakSender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> WeakSender<T> {
+    pub(crate) fn upgrade(&self) -> Option<Sender<T>> {
+        self.shared
+            .senders
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| if n == 0 { None } else { Some(n + 1) })
+            .ok()
+            .map(|_| Sender { shared: self.shared.clone() })
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.shared.len()
+    }
+
+    pub(crate) fn is_terminated(&self) -> bool {
+        self.shared.is_terminated()
+    }
+
+    pub(crate) fn metrics(&self) -> &ActorMetrics {
+        &self.shared.metrics
+    }
+
+    pub(crate) async fn terminated(&self) {
+        self.shared.terminated().await
+    }
+
+    pub(crate) async fn shutdown(&self, timeout: Duration) -> bool {
+        self.shared.shutdown(timeout).await
+    }
+}
+
+impl<T> Clone for WeakSender<T> {
+    fn clone(&self) -> Self {
+        WeakSender { shared: self.shared.clone() }
+    }
+}
+
+pub(crate) struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Receiver<T> {
+    ///
+    /// 优先队列中的消息先出队，可以安全地在 select 中取消
+    ///
+    pub(crate) async fn recv(&mut self) -> Option<T> {
+        loop {
+            let readable = self.shared.readable.notified();
+            tokio::pin!(readable);
+            readable.as_mut().enable();
+            {
+                let mut queue = self.shared.queue.lock().unwrap();
+                if let Some(value) = queue.high.pop_front() {
+                    return Some(value);
+                }
+                if let Some((value, control)) = queue.normal.pop_front() {
+                    if control {
+                        queue.controls -= 1;
+                    } else {
+                        drop(queue);
+                        self.shared.writable.notify_one();
+                    }
+                    return Some(value);
+                }
+                if queue.closed || queue.draining || self.shared.senders.load(Ordering::SeqCst) == 0 {
+                    return None;
+                }
+            }
+            readable.await;
+        }
+    }
+
+    pub(crate) fn close(&mut self) {
+        self.shared.close();
+    }
+
+    pub(crate) fn metrics(&self) -> &ActorMetrics {
+        &self.shared.metrics
+    }
+
+    ///
+    /// actor 的 stopped 钩子执行完毕后调用，唤醒等待 join 的任务
+    ///
+    pub(crate) fn terminate(&mut self) {
+        self.shared.terminate();
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.close();
+        self.shared.terminate();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time;
+    use crate::actor_model::actor::ActorId;
+
+    fn channel<T>(config: MailboxConfig) -> (Sender<T>, Receiver<T>) {
+        super::channel(config, Arc::new(ActorMetrics::new(ActorId::next(), "test")))
+    }
+
+    #[tokio::test]
+    async fn test_priority_lane() {
+        let (sender, mut receiver) = channel(MailboxConfig::bounded(2));
+        sender.send(1, Priority::Normal).await.unwrap();
+        sender.send(2, Priority::Normal).await.unwrap();
+        // 普通队列已满，优先消息不受容量限制
+        sender.send(10, Priority::High).await.unwrap();
+        sender.send(11, Priority::High).await.unwrap();
+        assert_eq!(sender.len(), 4);
+
+        let mut received = vec![];
+        for _ in 0..4 {
+            received.push(receiver.recv().await.unwrap());
+        }
+        assert_eq!(received, vec![10, 11, 1, 2]);
+        assert_eq!(sender.len(), 0);
+
+        drop(sender);
+        assert_eq!(receiver.recv().await, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_back_pressure() {
+        let (sender, mut receiver) = channel(MailboxConfig::bounded(1));
+        sender.send(1, Priority::Normal).await.unwrap();
+        let blocked = tokio::spawn(async move {
+            sender.send(2, Priority::Normal).await.unwrap();
+            sender
+        });
+        time::sleep(Duration::from_millis(10)).await;
+        assert!(!blocked.is_finished());
+
+        assert_eq!(receiver.recv().await, Some(1));
+        let sender = blocked.await.unwrap();
+        assert_eq!(receiver.recv().await, Some(2));
+
+        receiver.close();
+        assert!(sender.is_closed());
+        assert_eq!(sender.send(3, Priority::High).await, Err(ActorError::Stopped));
+        sender.closed().await;
+    }
+
+    #[tokio::test]
+    async fn test_drop_on_full() {
+        let (sender, mut receiver) = channel(MailboxConfig::bounded(2).overflow(Overflow::DropNewest));
+        for n in 1..=3 {
+            let _ = sender.send(n, Priority::Normal).await;
+        }
+        assert_eq!(sender.send(4, Priority::Normal).await, Err(ActorError::MailboxFull));
+        assert_eq!((receiver.recv().await, receiver.recv().await), (Some(1), Some(2)));
+
+        let (sender, mut receiver) = channel(MailboxConfig::bounded(2).overflow(Overflow::DropOldest));
+        for n in 1..=4 {
+            sender.send(n, Priority::Normal).await.unwrap();
+        }
+        assert_eq!(sender.len(), 2);
+        assert_eq!((receiver.recv().await, receiver.recv().await), (Some(3), Some(4)));
+    }
+
+    #[tokio::test]
+    async fn test_unbounded() {
+        let (sender, mut receiver) = channel(MailboxConfig::unbounded());
+        for n in 0..1000 {
+            sender.send(n, Priority::Normal).await.unwrap();
+        }
+        assert_eq!(sender.len(), 1000);
+        let weak = sender.downgrade();
+        assert!(weak.upgrade().is_some());
+        drop(sender);
+        assert!(weak.upgrade().is_none());
+        assert_eq!(weak.len(), 1000);
+
+        let mut count = 0;
+        while receiver.recv().await.is_some() {
+            count += 1;
+        }
+        assert_eq!(count, 1000);
+    }
+}
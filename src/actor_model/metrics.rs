@@ -0,0 +1,349 @@
+//!
+//! actor 运行时的统计信息
+//! 每个 actor 记录处理的消息数、处理耗时分布与被监督者重启的次数，
+//! 统计随邮箱保存，重启后继续累计。snapshot 返回进程内所有运行中 actor 的快照，
+//! 快照可以直接在测试中检查，也可以用 to_json 导出。
+//!
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use serde::Serialize;
+use tokio::time::Instant;
+use crate::actor_model::actor::ActorId;
+
+///
+/// 耗时分布各个区间的上界，单位为微秒，超过最后一个上界的计入溢出区间
+///
+const LATENCY_BOUNDS: [u64; 14] = [
+    50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 500_000, 1_000_000,
+];
+
+#[derive(Default)]
+struct Stats {
+    buckets: [u64; LATENCY_BOUNDS.len() + 1],
+    sum_micros: u64,
+    max_micros: u64,
+    /// 按整秒统计处理速度：当前这一秒与上一秒处理的消息数
+    second: u64,
+    current: u64,
+    previous: u64,
+}
+
+pub(crate) struct ActorMetrics {
+    id: ActorId,
+    name: &'static str,
+    started: Instant,
+    processed: AtomicU64,
+    restarts: AtomicU64,
+    stats: Mutex<Stats>,
+}
+
+impl ActorMetrics {
+    pub(crate) fn new(id: ActorId, name: &'static str) -> ActorMetrics {
+        ActorMetrics {
+            id,
+            name,
+            started: Instant::now(),
+            processed: AtomicU64::new(0),
+            restarts: AtomicU64::new(0),
+            stats: Mutex::new(Stats::default()),
+        }
+    }
+
+    pub(crate) fn id(&self) -> ActorId {
+        self.id
+    }
+
+    ///
+    /// 记录一条消息的处理耗时
+    ///
+    pub(crate) fn record(&self, latency: Duration) {
+        self.processed.fetch_add(1, Ordering::Relaxed);
+        let micros = latency.as_micros().min(u64::MAX as u128) as u64;
+        let second = self.started.elapsed().as_secs();
+        let mut stats = self.stats.lock().unwrap();
+        let bucket = LATENCY_BOUNDS.iter().position(|bound| micros <= *bound).unwrap_or(LATENCY_BOUNDS.len());
+        stats.buckets[bucket] += 1;
+        stats.sum_micros = stats.sum_micros.saturating_add(micros);
+        stats.max_micros = stats.max_micros.max(micros);
+        if second != stats.second {
+            stats.previous = if second == stats.second + 1 { stats.current } else { 0 };
+            stats.current = 0;
+            stats.second = second;
+        }
+        stats.current += 1;
+    }
+
+    pub(crate) fn restarted(&self) {
+        self.restarts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self, mailbox_len: usize) -> ActorSnapshot {
+        let second = self.started.elapsed().as_secs();
+        let stats = self.stats.lock().unwrap();
+        let messages_per_second = if second == stats.second {
+            stats.previous
+        } else if second == stats.second + 1 {
+            stats.current
+        } else {
+            0
+        };
+        let buckets = stats.buckets
+            .iter()
+            .enumerate()
+            .map(|(i, count)| LatencyBucket { le_micros: LATENCY_BOUNDS.get(i).copied(), count: *count })
+            .collect();
+        ActorSnapshot {
+            id: self.id,
+            name: self.name.to_string(),
+            mailbox_len,
+            processed: self.processed.load(Ordering::Relaxed),
+            messages_per_second,
+            restarts: self.restarts.load(Ordering::Relaxed),
+            uptime_ms: self.started.elapsed().as_millis() as u64,
+            latency: LatencyHistogram {
+                buckets,
+                count: stats.buckets.iter().sum(),
+                sum_micros: stats.sum_micros,
+                max_micros: stats.max_micros,
+            },
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct LatencyBucket {
+    /// 区间上界，溢出区间为 None
+    pub le_micros: Option<u64>,
+    pub count: u64,
+}
+
+///
+/// handle 的耗时分布
+///
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct LatencyHistogram {
+    pub buckets: Vec<LatencyBucket>,
+    pub count: u64,
+    pub sum_micros: u64,
+    pub max_micros: u64,
+}
+
+impl LatencyHistogram {
+    pub fn mean_micros(&self) -> Option<u64> {
+        self.sum_micros.checked_div(self.count)
+    }
+
+    ///
+    /// 分位数所在区间的上界，落在溢出区间时返回最大耗时
+    ///
+    pub fn quantile_micros(&self, q: f64) -> Option<u64> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = ((q.clamp(0.0, 1.0) * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for bucket in &self.buckets {
+            seen += bucket.count;
+            if seen >= rank {
+                return Some(bucket.le_micros.unwrap_or(self.max_micros).min(self.max_micros));
+            }
+        }
+        Some(self.max_micros)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ActorSnapshot {
+    pub id: ActorId,
+    /// actor 的类型名
+    pub name: String,
+    pub mailbox_len: usize,
+    pub processed: u64,
+    /// 上一个完整的一秒内处理的消息数
+    pub messages_per_second: u64,
+    pub restarts: u64,
+    pub uptime_ms: u64,
+    pub latency: LatencyHistogram,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct MetricsSnapshot {
+    pub live_actors: usize,
+    pub processed: u64,
+    pub restarts: u64,
+    /// 按 actor 编号排序
+    pub actors: Vec<ActorSnapshot>,
+}
+
+impl MetricsSnapshot {
+    pub(crate) fn new(mut actors: Vec<ActorSnapshot>) -> MetricsSnapshot {
+        actors.sort_by_key(|actor| actor.id);
+        MetricsSnapshot {
+            live_actors: actors.len(),
+            processed: actors.iter().map(|actor| actor.processed).sum(),
+            restarts: actors.iter().map(|actor| actor.restarts).sum(),
+            actors,
+        }
+    }
+
+    pub fn actor(&self, id: ActorId) -> Option<&ActorSnapshot> {
+        self.actors.iter().find(|actor| actor.id == id)
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("metrics snapshot is always serializable")
+    }
+}
+
+///
+/// 运行中 actor 的统计入口，由 actor 的邮箱实现
+///
+pub(crate) trait Probe: Send + Sync {
+    fn snapshot(&self) -> ActorSnapshot;
+}
+
+type Probes = Mutex<HashMap<ActorId, Arc<dyn Probe>>>;
+
+fn probes() -> &'static Probes {
+    static PROBES: OnceLock<Probes> = OnceLock::new();
+    PROBES.get_or_init(Probes::default)
+}
+
+pub(crate) fn register(id: ActorId, probe: Arc<dyn Probe>) {
+    probes().lock().unwrap().insert(id, probe);
+}
+
+///
+/// actor 完全停止时调用
+///
+pub(crate) fn unregister(id: ActorId) {
+    probes().lock().unwrap().remove(&id);
+}
+
+pub(crate) fn snapshot_of(id: ActorId) -> Option<ActorSnapshot> {
+    let probe = probes().lock().unwrap().get(&id).cloned();
+    probe.map(|probe| probe.snapshot())
+}
+
+///
+/// 只包含 ids 中仍在运行的 actor
+///
+pub(crate) fn snapshot_filtered(ids: &[ActorId]) -> MetricsSnapshot {
+    MetricsSnapshot::new(ids.iter().filter_map(|id| snapshot_of(*id)).collect())
+}
+
+///
+/// 进程内所有运行中 actor 的快照
+///
+pub fn snapshot() -> MetricsSnapshot {
+    let probes: Vec<Arc<dyn Probe>> = probes().lock().unwrap().values().cloned().collect();
+    MetricsSnapshot::new(probes.iter().map(|probe| probe.snapshot()).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::Notify;
+    use async_trait::async_trait;
+    use crate::actor_model::actor::{self, Actor};
+    use crate::actor_model::context::Context;
+    use crate::actor_model::error::ActorError;
+    use crate::actor_model::supervisor::{Strategy, Supervisor};
+    use crate::actor_model::system::ActorSystem;
+
+    enum Job {
+        Sleep(u64),
+        /// 通知第一个 Notify 后等待第二个
+        Block(Arc<Notify>, Arc<Notify>),
+        Panic,
+    }
+
+    struct Worker;
+
+    #[async_trait]
+    impl Actor for Worker {
+        type Message = Job;
+        type Reply = ();
+
+        async fn handle(&mut self, job: Job, _ctx: &mut Context<Self>) {
+            match job {
+                Job::Sleep(ms) => tokio::time::sleep(Duration::from_millis(ms)).await,
+                Job::Block(entered, release) => {
+                    entered.notify_one();
+                    release.notified().await;
+                }
+                Job::Panic => panic!("worker failed"),
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_actor_metrics() {
+        let addr = actor::spawn(Worker);
+        addr.ask(Job::Sleep(0)).await.unwrap();
+        addr.ask(Job::Sleep(0)).await.unwrap();
+        addr.ask(Job::Sleep(10)).await.unwrap();
+
+        let metrics = addr.metrics().unwrap();
+        assert_eq!(metrics.processed, 3);
+        assert_eq!(metrics.messages_per_second, 0);
+        assert!(metrics.name.ends_with("Worker"));
+        let latency = &metrics.latency;
+        assert_eq!((latency.count, latency.max_micros), (3, 10_000));
+        assert_eq!(latency.buckets[0], LatencyBucket { le_micros: Some(50), count: 2 });
+        assert_eq!(latency.quantile_micros(0.5), Some(50));
+        assert_eq!(latency.quantile_micros(1.0), Some(10_000));
+        assert_eq!(latency.buckets.last().unwrap().le_micros, None);
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert_eq!(addr.metrics().unwrap().messages_per_second, 3);
+
+        // 处理中的消息不计入邮箱深度
+        let (entered, release) = (Arc::new(Notify::new()), Arc::new(Notify::new()));
+        addr.send(Job::Block(entered.clone(), release.clone())).await.unwrap();
+        entered.notified().await;
+        addr.send(Job::Sleep(0)).await.unwrap();
+        addr.send(Job::Sleep(0)).await.unwrap();
+        let current = snapshot();
+        assert_eq!(current.actor(addr.id()).map(|actor| actor.mailbox_len), Some(2));
+        let json = current.to_json();
+        assert!(json.contains("\"live_actors\":"));
+        assert!(json.contains("\"mailbox_len\":2"));
+        assert!(json.contains("\"le_micros\":null"));
+
+        release.notify_one();
+        let join = addr.join_handle();
+        addr.stop().await;
+        join.join().await;
+        assert_eq!(addr.metrics(), None);
+        assert_eq!(snapshot().actor(addr.id()), None);
+    }
+
+    #[tokio::test]
+    async fn test_system_metrics() {
+        let system = ActorSystem::new();
+        let worker = system.spawn(Worker);
+        let mut supervisor = Supervisor::new(Strategy::OneForOne);
+        let child = supervisor.child(|| Worker);
+        system.supervise(supervisor);
+        let outsider = actor::spawn(Worker);
+
+        assert_eq!(child.ask(Job::Panic).await, Err(ActorError::NoReply));
+        child.ask(Job::Sleep(0)).await.unwrap();
+        worker.ask(Job::Sleep(0)).await.unwrap();
+
+        // 只包含通过系统启动的 actor，重启后统计继续累计
+        let metrics = system.metrics();
+        assert_eq!(metrics.live_actors, 2);
+        assert_eq!((metrics.processed, metrics.restarts), (2, 1));
+        assert_eq!(metrics.actor(child.id()).map(|actor| actor.restarts), Some(1));
+        assert_eq!(metrics.actor(outsider.id()), None);
+        assert!(snapshot().actor(outsider.id()).is_some());
+
+        assert!(system.shutdown(Duration::from_secs(1)).await);
+        assert_eq!(system.metrics(), MetricsSnapshot::default());
+    }
+}
@@ -1,62 +1,78 @@
-use tokio::sync::{oneshot, mpsc};
+//!
+//! 基于 tokio 任务与双队列邮箱的 actor 模型
+//! 实现 Actor trait 后通过 actor::spawn 启动，得到的 Addr 用于 send 或 ask
+//!
+
+pub mod actor;
+pub mod addr;
+pub mod context;
+pub mod error;
+pub mod journal;
+pub mod mailbox;
+pub mod metrics;
+pub mod persistence;
+pub mod registry;
+pub mod remote;
+pub mod supervisor;
+pub mod system;
+
+use std::time::Duration;
+use async_trait::async_trait;
+use crate::actor_model::actor::Actor;
+use crate::actor_model::addr::Addr;
+use crate::actor_model::context::Context;
 
 ///
 /// Example from https://ryhl.io/blog/actors-with-tokio/
 ///
-
 struct MyActor {
     next_id: u32,
-    receiver: mpsc::Receiver<ActorMessage>,
 }
 
 enum ActorMessage {
-    GetUniqueID {
-        respond_to: oneshot::Sender<u32>
-    }
+    GetUniqueID,
 }
 
-impl MyActor {
-    pub fn new(receiver: mpsc::Receiver<ActorMessage>) -> Self {
-        MyActor { next_id: 0, receiver }
-    }
+#[async_trait]
+impl Actor for MyActor {
+    type Message = ActorMessage;
+    type Reply = u32;
 
-    fn handle_message(&mut self, msg: ActorMessage) {
+    async fn handle(&mut self, msg: ActorMessage, _ctx: &mut Context<Self>) -> u32 {
         match msg {
-            ActorMessage::GetUniqueID { respond_to } => {
+            ActorMessage::GetUniqueID => {
                 self.next_id += 1;
-                respond_to.send(self.next_id);
+                self.next_id
             }
         }
     }
 }
 
-impl MyActor{
-    async fn run(&mut self){
-        while let Some(msg) = self.receiver.recv().await {
-            self.handle_message(msg)
-        }
-    }
-}
-
 #[derive(Clone)]
 pub struct MyActorHandle {
-    sender: mpsc::Sender<ActorMessage>
+    addr: Addr<MyActor>,
 }
 
 impl MyActorHandle {
     pub fn new() -> Self {
-        let (sender, receiver) = mpsc::channel(8);
-        let mut actor = MyActor::new(receiver);
-        tokio::spawn(async move {actor.run().await});
-        MyActorHandle { sender }
+        MyActorHandle { addr: actor::spawn(MyActor { next_id: 0 }) }
     }
 
     pub async fn get_unique_id(&self) -> u32 {
-        let (send, recv) = oneshot::channel();
-        let msg = ActorMessage::GetUniqueID { respond_to: send };
+        self.addr.ask(ActorMessage::GetUniqueID).await.expect("actor task has been killed")
+    }
 
-        self.sender.send(msg).await;
-        recv.await.expect("actor task has been killed")
+    ///
+    /// 处理完已经排队的请求后停止，超过 timeout 时放弃剩余的请求
+    ///
+    pub async fn shutdown(&self, timeout: Duration) -> bool {
+        self.addr.shutdown(timeout).await
+    }
+}
+
+impl Default for MyActorHandle {
+    fn default() -> Self {
+        MyActorHandle::new()
     }
 }
 
@@ -66,12 +82,10 @@ mod tests {
 
     #[tokio::test]
     async fn my_test() {
-        assert!(true);
-
         let handle = MyActorHandle::new();
 
-        let id = handle.get_unique_id().await;
-
-        println!("{}", id);
+        assert_eq!(handle.get_unique_id().await, 1);
+        assert_eq!(handle.clone().get_unique_id().await, 2);
+        assert!(handle.shutdown(Duration::from_secs(1)).await);
     }
 }
@@ -0,0 +1,271 @@
+//!
+//! 事件溯源的持久化 actor
+//! 命令只产生事件而不直接修改状态，事件写入 Journal 成功后才应用到状态上；
+//! 启动时先加载最近的快照，再重放快照之后的事件，得到停止前的状态。
+//! 写入失败时 actor 以 panic 退出，由监督者重启并从日志恢复。
+//!
+
+use std::sync::Arc;
+use async_trait::async_trait;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use crate::actor_model::actor::Actor;
+use crate::actor_model::context::Context;
+use crate::actor_model::journal::{Journal, Snapshot};
+
+pub trait PersistentActor: Send + 'static {
+    type Command: Send + 'static;
+    type Event: Serialize + DeserializeOwned + Send + 'static;
+    type Reply: Send + 'static;
+    /// 状态的备忘录，用于保存与恢复快照
+    type Snapshot: Serialize + DeserializeOwned + Send + 'static;
+
+    ///
+    /// 事件流的名称，同一个名称只应由一个 actor 使用
+    ///
+    fn persistence_id(&self) -> String;
+
+    ///
+    /// 根据当前状态处理命令，返回需要持久化的事件与回复，此时不修改状态
+    ///
+    fn handle_command(&self, cmd: Self::Command) -> (Vec<Self::Event>, Self::Reply);
+
+    ///
+    /// 把事件应用到状态上，处理命令与恢复时都会调用
+    ///
+    fn apply(&mut self, event: &Self::Event);
+
+    fn snapshot(&self) -> Self::Snapshot;
+
+    fn restore(&mut self, snapshot: Self::Snapshot);
+}
+
+///
+/// 把 PersistentActor 包装成 Actor，通过 actor::spawn 或监督者启动
+///
+pub struct Persistent<P: PersistentActor> {
+    actor: P,
+    journal: Arc<dyn Journal>,
+    sequence: u64,
+    snapshot_sequence: u64,
+    snapshot_every: Option<u64>,
+}
+
+impl<P: PersistentActor> Persistent<P> {
+    pub fn new(actor: P, journal: Arc<dyn Journal>) -> Persistent<P> {
+        Persistent { actor, journal, sequence: 0, snapshot_sequence: 0, snapshot_every: None }
+    }
+
+    ///
+    /// 每持久化 events 个事件保存一次快照，限制恢复时需要重放的事件数量
+    ///
+    pub fn snapshot_every(mut self, events: u64) -> Persistent<P> {
+        assert!(events > 0, "snapshot interval must be greater than 0");
+        self.snapshot_every = Some(events);
+        self
+    }
+
+    async fn recover(&mut self) -> Result<(), String> {
+        let id = self.actor.persistence_id();
+        if let Some(snapshot) = self.journal.load_snapshot(&id).await.map_err(|e| e.to_string())? {
+            self.actor.restore(serde_json::from_value(snapshot.state).map_err(|e| e.to_string())?);
+            self.sequence = snapshot.sequence;
+            self.snapshot_sequence = snapshot.sequence;
+        }
+        for event in self.journal.read(&id, self.sequence).await.map_err(|e| e.to_string())? {
+            let event: P::Event = serde_json::from_value(event).map_err(|e| e.to_string())?;
+            self.actor.apply(&event);
+            self.sequence += 1;
+        }
+        Ok(())
+    }
+
+    async fn persist(&mut self, events: Vec<P::Event>) -> Result<(), String> {
+        let values = events
+            .iter()
+            .map(serde_json::to_value)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| e.to_string())?;
+        let id = self.actor.persistence_id();
+        self.sequence = self.journal.append(&id, values).await.map_err(|e| e.to_string())?;
+        for event in &events {
+            self.actor.apply(event);
+        }
+        if matches!(self.snapshot_every, Some(every) if self.sequence - self.snapshot_sequence >= every) {
+            let snapshot = serde_json::to_value(self.actor.snapshot()).map_err(|e| e.to_string())?;
+            // 快照只用于加快恢复，保存失败时下次再试
+            if self.journal.save_snapshot(&id, Snapshot { sequence: self.sequence, state: snapshot }).await.is_ok() {
+                self.snapshot_sequence = self.sequence;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<P: PersistentActor> Actor for Persistent<P> {
+    type Message = P::Command;
+    type Reply = P::Reply;
+
+    async fn started(&mut self, _ctx: &mut Context<Self>) {
+        if let Err(e) = self.recover().await {
+            panic!("recovery failed: {}", e);
+        }
+    }
+
+    async fn handle(&mut self, cmd: P::Command, _ctx: &mut Context<Self>) -> P::Reply {
+        let (events, reply) = self.actor.handle_command(cmd);
+        if !events.is_empty() {
+            if let Err(e) = self.persist(events).await {
+                panic!("failed to persist events: {}", e);
+            }
+        }
+        reply
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use serde::Deserialize;
+    use serde_json::Value;
+    use crate::actor_model::actor;
+    use crate::actor_model::error::ActorError;
+    use crate::actor_model::journal::InMemoryJournal;
+    use crate::actor_model::supervisor::{Strategy, Supervisor};
+
+    enum IdCommand {
+        Next,
+        Skip(u32),
+        /// 返回当前的编号与本实例应用过的事件数
+        Get,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    enum IdEvent {
+        Advanced(u32),
+    }
+
+    ///
+    /// 与 MyActor 相同的编号生成器，重启后编号不会重复
+    ///
+    #[derive(Default)]
+    struct IdGenerator {
+        next_id: u32,
+        applied: usize,
+    }
+
+    impl PersistentActor for IdGenerator {
+        type Command = IdCommand;
+        type Event = IdEvent;
+        type Reply = (u32, usize);
+        type Snapshot = u32;
+
+        fn persistence_id(&self) -> String {
+            "id-generator".into()
+        }
+
+        fn handle_command(&self, cmd: IdCommand) -> (Vec<IdEvent>, (u32, usize)) {
+            match cmd {
+                IdCommand::Next => (vec![IdEvent::Advanced(1)], (self.next_id + 1, self.applied + 1)),
+                IdCommand::Skip(n) => ((0..n).map(|_| IdEvent::Advanced(1)).collect(), (self.next_id + n, self.applied + n as usize)),
+                IdCommand::Get => (vec![], (self.next_id, self.applied)),
+            }
+        }
+
+        fn apply(&mut self, event: &IdEvent) {
+            match event {
+                IdEvent::Advanced(n) => self.next_id += n,
+            }
+            self.applied += 1;
+        }
+
+        fn snapshot(&self) -> u32 {
+            self.next_id
+        }
+
+        fn restore(&mut self, snapshot: u32) {
+            self.next_id = snapshot;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_recover_from_journal() {
+        let journal: Arc<dyn Journal> = Arc::new(InMemoryJournal::new());
+        let addr = actor::spawn(Persistent::new(IdGenerator::default(), journal.clone()));
+        assert_eq!(addr.ask(IdCommand::Next).await, Ok((1, 1)));
+        assert_eq!(addr.ask(IdCommand::Next).await, Ok((2, 2)));
+        addr.stop().await;
+        addr.closed().await;
+
+        let addr = actor::spawn(Persistent::new(IdGenerator::default(), journal.clone()));
+        assert_eq!(addr.ask(IdCommand::Get).await, Ok((2, 2)));
+        assert_eq!(addr.ask(IdCommand::Next).await, Ok((3, 3)));
+        assert_eq!(journal.read("id-generator", 0).await.unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_snapshots() {
+        let journal: Arc<dyn Journal> = Arc::new(InMemoryJournal::new());
+        let addr = actor::spawn(Persistent::new(IdGenerator::default(), journal.clone()).snapshot_every(4));
+        addr.ask(IdCommand::Skip(3)).await.unwrap();
+        assert_eq!(journal.load_snapshot("id-generator").await.unwrap(), None);
+        addr.ask(IdCommand::Skip(2)).await.unwrap();
+        addr.ask(IdCommand::Next).await.unwrap();
+        assert_eq!(journal.load_snapshot("id-generator").await.unwrap().map(|s| s.sequence), Some(5));
+        addr.stop().await;
+        addr.closed().await;
+
+        // 只重放快照之后的一个事件
+        let addr = actor::spawn(Persistent::new(IdGenerator::default(), journal.clone()));
+        assert_eq!(addr.ask(IdCommand::Get).await, Ok((6, 1)));
+    }
+
+    ///
+    /// 第一次追加失败，之后正常写入
+    ///
+    #[derive(Default)]
+    struct FlakyJournal {
+        inner: InMemoryJournal,
+        failed: AtomicBool,
+    }
+
+    #[async_trait]
+    impl Journal for FlakyJournal {
+        async fn append(&self, persistence_id: &str, events: Vec<Value>) -> io::Result<u64> {
+            if !self.failed.swap(true, Ordering::SeqCst) {
+                return Err(io::Error::other("disk full"));
+            }
+            self.inner.append(persistence_id, events).await
+        }
+
+        async fn read(&self, persistence_id: &str, after: u64) -> io::Result<Vec<Value>> {
+            self.inner.read(persistence_id, after).await
+        }
+
+        async fn save_snapshot(&self, persistence_id: &str, snapshot: Snapshot) -> io::Result<()> {
+            self.inner.save_snapshot(persistence_id, snapshot).await
+        }
+
+        async fn load_snapshot(&self, persistence_id: &str) -> io::Result<Option<Snapshot>> {
+            self.inner.load_snapshot(persistence_id).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_persist_failure_restarts() {
+        let journal: Arc<dyn Journal> = Arc::new(FlakyJournal::default());
+        let mut supervisor = Supervisor::new(Strategy::OneForOne);
+        let factory_journal = journal.clone();
+        let addr = supervisor.child(move || Persistent::new(IdGenerator::default(), factory_journal.clone()));
+        let handle = supervisor.start();
+
+        // 写入失败时状态不变，重启后从日志恢复
+        assert_eq!(addr.ask(IdCommand::Next).await, Err(ActorError::NoReply));
+        assert_eq!(addr.ask(IdCommand::Next).await, Ok((1, 1)));
+        assert_eq!(handle.restarts(), 1);
+        handle.stop().await;
+    }
+}
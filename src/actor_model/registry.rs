@@ -0,0 +1,205 @@
+//!
+//! 按名称注册与查找 actor 地址，相当于作用在 Addr 上的服务定位器
+//! 查找时按 actor 类型还原出 Addr<A>，类型不符时返回 None；
+//! actor 停止后对应的注册会被自动移除。
+//!
+
+use std::any::Any;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use tokio::task::AbortHandle;
+use crate::actor_model::actor::{Actor, ActorId};
+use crate::actor_model::addr::Addr;
+use crate::actor_model::error::ActorError;
+
+struct Entry {
+    id: ActorId,
+    addr: Box<dyn Any + Send>,
+    alive: fn(&(dyn Any + Send)) -> bool,
+    /// 等待 actor 停止并移除注册的任务
+    watcher: AbortHandle,
+}
+
+impl Drop for Entry {
+    fn drop(&mut self) {
+        self.watcher.abort();
+    }
+}
+
+type Entries = Mutex<HashMap<String, Entry>>;
+
+#[derive(Clone, Default)]
+pub struct Registry {
+    entries: Arc<Entries>,
+}
+
+impl Registry {
+    pub fn new() -> Registry {
+        Registry::default()
+    }
+
+    ///
+    /// 进程内共享的注册表
+    ///
+    pub fn global() -> &'static Registry {
+        static GLOBAL: OnceLock<Registry> = OnceLock::new();
+        GLOBAL.get_or_init(Registry::new)
+    }
+
+    ///
+    /// 以 name 注册 actor，注册表持有 Addr 直到 actor 停止或被注销
+    /// 名称已被运行中的 actor 占用时返回 NameTaken
+    ///
+    pub fn register<A: Actor, S: Into<String>>(&self, name: S, addr: &Addr<A>) -> Result<(), ActorError> {
+        let name = name.into();
+        let mut entries = self.entries.lock().unwrap();
+        if matches!(entries.get(&name), Some(entry) if (entry.alive)(entry.addr.as_ref())) {
+            return Err(ActorError::NameTaken(name));
+        }
+        let watcher = tokio::spawn(remove_when_stopped(
+            Arc::downgrade(&self.entries),
+            name.clone(),
+            addr.clone(),
+        ));
+        entries.insert(name, Entry {
+            id: addr.id(),
+            addr: Box::new(addr.clone()),
+            alive: is_alive::<A>,
+            watcher: watcher.abort_handle(),
+        });
+        Ok(())
+    }
+
+    ///
+    /// 查找名称对应的地址，未注册、actor 已停止或类型不符时返回 None
+    ///
+    pub fn lookup<A: Actor>(&self, name: &str) -> Option<Addr<A>> {
+        let entries = self.entries.lock().unwrap();
+        let addr = entries.get(name)?.addr.downcast_ref::<Addr<A>>()?;
+        if addr.is_alive() { Some(addr.clone()) } else { None }
+    }
+
+    pub fn unregister(&self, name: &str) -> bool {
+        self.entries.lock().unwrap().remove(name).is_some()
+    }
+
+    ///
+    /// 已注册的名称，按字母顺序排列
+    ///
+    pub fn names(&self) -> Vec<String> {
+        let entries = self.entries.lock().unwrap();
+        let mut names: Vec<String> = entries
+            .iter()
+            .filter(|(_, entry)| (entry.alive)(entry.addr.as_ref()))
+            .map(|(name, _)| name.clone())
+            .collect();
+        names.sort();
+        names
+    }
+
+    pub fn len(&self) -> usize {
+        self.names().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+fn is_alive<A: Actor>(addr: &(dyn Any + Send)) -> bool {
+    matches!(addr.downcast_ref::<Addr<A>>(), Some(addr) if addr.is_alive())
+}
+
+async fn remove_when_stopped<A: Actor>(entries: Weak<Entries>, name: String, addr: Addr<A>) {
+    addr.closed().await;
+    if let Some(entries) = entries.upgrade() {
+        let mut entries = entries.lock().unwrap();
+        // 同名的注册可能已经换成了另一个 actor
+        if matches!(entries.get(&name), Some(entry) if entry.id == addr.id()) {
+            entries.remove(&name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use crate::actor_model::actor;
+    use crate::actor_model::context::Context;
+
+    struct Echo;
+
+    #[async_trait]
+    impl Actor for Echo {
+        type Message = String;
+        type Reply = String;
+
+        async fn handle(&mut self, msg: String, _ctx: &mut Context<Self>) -> String {
+            msg
+        }
+    }
+
+    struct Doubler;
+
+    #[async_trait]
+    impl Actor for Doubler {
+        type Message = u32;
+        type Reply = u32;
+
+        async fn handle(&mut self, msg: u32, _ctx: &mut Context<Self>) -> u32 {
+            msg * 2
+        }
+    }
+
+    #[tokio::test]
+    async fn test_register_and_lookup() {
+        let registry = Registry::new();
+        registry.register("echo", &actor::spawn(Echo)).unwrap();
+        registry.register("doubler", &actor::spawn(Doubler)).unwrap();
+        assert_eq!(registry.names(), vec!["doubler", "echo"]);
+
+        let echo = registry.lookup::<Echo>("echo").unwrap();
+        assert_eq!(echo.ask("hi".into()).await, Ok("hi".into()));
+        let doubler = registry.lookup::<Doubler>("doubler").unwrap();
+        assert_eq!(doubler.ask(21).await, Ok(42));
+
+        // 类型不符与未注册的名称
+        assert!(registry.lookup::<Doubler>("echo").is_none());
+        assert!(registry.lookup::<Echo>("missing").is_none());
+
+        let other = actor::spawn(Echo);
+        assert_eq!(registry.register("echo", &other), Err(ActorError::NameTaken("echo".into())));
+        assert!(registry.unregister("echo"));
+        assert!(!registry.unregister("echo"));
+        registry.register("echo", &other).unwrap();
+        assert_eq!(registry.lookup::<Echo>("echo").unwrap().id(), other.id());
+    }
+
+    #[tokio::test]
+    async fn test_removed_when_stopped() {
+        let registry = Registry::new();
+        let addr = actor::spawn(Echo);
+        registry.register("echo", &addr).unwrap();
+        addr.stop().await;
+        addr.closed().await;
+        assert!(registry.lookup::<Echo>("echo").is_none());
+        while !registry.entries.lock().unwrap().is_empty() {
+            tokio::task::yield_now().await;
+        }
+
+        // 停止后名称可以被重新注册
+        let addr = actor::spawn(Echo);
+        registry.register("echo", &addr).unwrap();
+        assert_eq!(registry.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_global_registry() {
+        let addr = actor::spawn(Doubler);
+        Registry::global().register("global-doubler", &addr).unwrap();
+        let found = Registry::global().lookup::<Doubler>("global-doubler").unwrap();
+        assert_eq!(found.ask(4).await, Ok(8));
+        assert!(Registry::global().unregister("global-doubler"));
+    }
+}
@@ -0,0 +1,490 @@
+//!
+//! 跨进程的 actor 通信，建立在 rpc 模块的 TCP 帧之上
+//! Node 按名称导出本地 actor 并监听端口；RemoteNode 连接到对端后，
+//! 通过 actor::<A>(name) 得到与本地用法相同的 Addr<A>，消息与回复以 serde JSON 编码。
+//! 同一个连接上的消息按发送顺序投递，连接断开等远程失败以 ActorError::Delivery 返回。
+//!
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::task::AbortHandle;
+use crate::actor_model::actor::Actor;
+use crate::actor_model::addr::{Addr, Link};
+use crate::actor_model::error::ActorError;
+use crate::actor_model::mailbox::Priority;
+use crate::rpc::{read_frame, write_frame};
+
+///
+/// 可以在节点之间传递的消息与回复类型
+///
+pub trait RemoteMessage: Serialize + DeserializeOwned + Send + 'static {}
+
+impl<T> RemoteMessage for T where T: Serialize + DeserializeOwned + Send + 'static {}
+
+#[derive(Serialize, Deserialize)]
+enum Request {
+    Send { id: u64, actor: String, payload: Value, priority: Priority },
+    Ask { id: u64, actor: String, payload: Value, priority: Priority },
+    Stop { id: u64, actor: String, priority: Priority },
+}
+
+#[derive(Serialize, Deserialize)]
+struct Response {
+    id: u64,
+    result: Result<Value, ActorError>,
+}
+
+fn delivery<E: ToString>(e: E) -> ActorError {
+    ActorError::Delivery(e.to_string())
+}
+
+type PendingReply = Pin<Box<dyn Future<Output = Result<Value, ActorError>> + Send>>;
+
+///
+/// 导出的 actor，负责把 JSON 还原成消息
+///
+#[async_trait]
+trait Export: Send + Sync {
+    async fn send(&self, payload: Value, priority: Priority) -> Result<(), ActorError>;
+    async fn enqueue(&self, payload: Value, priority: Priority) -> Result<PendingReply, ActorError>;
+    async fn stop(&self, priority: Priority);
+}
+
+struct Exported<A: Actor>(Addr<A>);
+
+#[async_trait]
+impl<A> Export for Exported<A>
+    where
+        A: Actor,
+        A::Message: RemoteMessage,
+        A::Reply: RemoteMessage
+{
+    async fn send(&self, payload: Value, priority: Priority) -> Result<(), ActorError> {
+        let msg = serde_json::from_value(payload).map_err(delivery)?;
+        match priority {
+            Priority::Normal => self.0.send(msg).await,
+            Priority::High => self.0.send_priority(msg).await,
+        }
+    }
+
+    async fn enqueue(&self, payload: Value, priority: Priority) -> Result<PendingReply, ActorError> {
+        let msg = serde_json::from_value(payload).map_err(delivery)?;
+        let reply = self.0.enqueue(msg, priority).await?;
+        Ok(Box::pin(async move {
+            let reply = reply.await.map_err(|_| ActorError::NoReply)?;
+            serde_json::to_value(reply).map_err(delivery)
+        }))
+    }
+
+    async fn stop(&self, priority: Priority) {
+        match priority {
+            Priority::Normal => self.0.stop().await,
+            Priority::High => self.0.stop_now().await,
+        }
+    }
+}
+
+type Exports = Mutex<HashMap<String, Arc<dyn Export>>>;
+
+///
+/// 本地节点，对外提供已导出的 actor
+///
+#[derive(Clone, Default)]
+pub struct Node {
+    exports: Arc<Exports>,
+    /// 监听与连接任务，shutdown 时全部中止
+    tasks: Arc<Mutex<Vec<AbortHandle>>>,
+}
+
+impl Node {
+    pub fn new() -> Node {
+        Node::default()
+    }
+
+    ///
+    /// 以 name 导出 actor，同名的旧导出被替换
+    /// 节点持有 Addr 直到 unexport
+    ///
+    pub fn export<A, S>(&self, name: S, addr: &Addr<A>)
+        where
+            A: Actor,
+            A::Message: RemoteMessage,
+            A::Reply: RemoteMessage,
+            S: Into<String>
+    {
+        self.exports.lock().unwrap().insert(name.into(), Arc::new(Exported(addr.clone())));
+    }
+
+    pub fn unexport(&self, name: &str) -> bool {
+        self.exports.lock().unwrap().remove(name).is_some()
+    }
+
+    ///
+    /// 在 addr 上监听连接，返回实际绑定的地址
+    ///
+    pub async fn listen<T: ToSocketAddrs>(&self, addr: T) -> io::Result<std::net::SocketAddr> {
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        let node = self.clone();
+        let task = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let task = tokio::spawn(serve(node.exports.clone(), stream));
+                node.add_task(task.abort_handle());
+            }
+        });
+        self.add_task(task.abort_handle());
+        Ok(local_addr)
+    }
+
+    ///
+    /// 停止监听并断开所有连接，导出的 actor 不受影响
+    ///
+    pub fn shutdown(&self) {
+        for task in self.tasks.lock().unwrap().drain(..) {
+            task.abort();
+        }
+    }
+
+    fn add_task(&self, task: AbortHandle) {
+        let mut tasks = self.tasks.lock().unwrap();
+        tasks.retain(|task| !task.is_finished());
+        tasks.push(task);
+    }
+}
+
+///
+/// 处理一个连接：按顺序投递请求，ask 的回复在各自的任务中等待，完成后交给写任务
+///
+async fn serve(exports: Arc<Exports>, stream: TcpStream) {
+    let (mut reader, mut writer) = stream.into_split();
+    let (tx, mut rx) = mpsc::unbounded_channel::<Response>();
+    let reading = async move {
+        while let Ok(Some(request)) = read_frame::<_, Request>(&mut reader).await {
+            let (id, actor) = match &request {
+                Request::Send { id, actor, .. } | Request::Ask { id, actor, .. } | Request::Stop { id, actor, .. } => (*id, actor),
+            };
+            let export = exports.lock().unwrap().get(actor).cloned();
+            let export = match export {
+                Some(export) => export,
+                None => {
+                    let result = Err(ActorError::Delivery(format!("no actor exported as {}", actor)));
+                    let _ = tx.send(Response { id, result });
+                    continue;
+                }
+            };
+            let result = match request {
+                Request::Send { payload, priority, .. } => export.send(payload, priority).await.map(|_| Value::Null),
+                Request::Ask { payload, priority, .. } => match export.enqueue(payload, priority).await {
+                    Ok(reply) => {
+                        let tx = tx.clone();
+                        tokio::spawn(async move {
+                            let _ = tx.send(Response { id, result: reply.await });
+                        });
+                        continue;
+                    }
+                    Err(e) => Err(e),
+                },
+                Request::Stop { priority, .. } => {
+                    export.stop(priority).await;
+                    Ok(Value::Null)
+                }
+            };
+            let _ = tx.send(Response { id, result });
+        }
+    };
+    let writing = async move {
+        while let Some(response) = rx.recv().await {
+            if write_frame(&mut writer, &response).await.is_err() {
+                break;
+            }
+        }
+    };
+    tokio::join!(reading, writing);
+}
+
+type Pending = Mutex<Option<HashMap<u64, oneshot::Sender<Result<Value, ActorError>>>>>;
+
+struct Shared {
+    /// 等待回复的请求，连接断开后为 None
+    pending: Pending,
+    closed: watch::Sender<bool>,
+}
+
+impl Shared {
+    ///
+    /// 断开连接，所有等待中的请求以 Delivery 错误返回
+    ///
+    fn close(&self) {
+        self.pending.lock().unwrap().take();
+        self.closed.send_replace(true);
+    }
+}
+
+struct Connection {
+    next_id: AtomicU64,
+    outgoing: mpsc::UnboundedSender<Request>,
+    shared: Arc<Shared>,
+    reader: AbortHandle,
+}
+
+impl Connection {
+    async fn request<F>(&self, request: F) -> Result<Value, ActorError>
+        where
+            F: FnOnce(u64) -> Request
+    {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        match self.shared.pending.lock().unwrap().as_mut() {
+            Some(pending) => pending.insert(id, tx),
+            None => return Err(connection_closed()),
+        };
+        if self.outgoing.send(request(id)).is_err() {
+            self.shared.close();
+        }
+        rx.await.unwrap_or_else(|_| Err(connection_closed()))
+    }
+
+    fn is_closed(&self) -> bool {
+        *self.shared.closed.borrow()
+    }
+
+    async fn closed(&self) {
+        let mut closed = self.shared.closed.subscribe();
+        let _ = closed.wait_for(|closed| *closed).await;
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+fn connection_closed() -> ActorError {
+    ActorError::Delivery("connection closed".into())
+}
+
+///
+/// 到远程节点的连接，所有 RemoteNode 与远程 Addr 都被释放后连接断开
+///
+#[derive(Clone)]
+pub struct RemoteNode {
+    conn: Arc<Connection>,
+}
+
+impl RemoteNode {
+    pub async fn connect<T: ToSocketAddrs>(addr: T) -> io::Result<RemoteNode> {
+        let (mut reader, mut writer) = TcpStream::connect(addr).await?.into_split();
+        let shared = Arc::new(Shared { pending: Mutex::new(Some(HashMap::new())), closed: watch::channel(false).0 });
+        let (outgoing, mut requests) = mpsc::unbounded_channel::<Request>();
+
+        let writer_shared = shared.clone();
+        tokio::spawn(async move {
+            while let Some(request) = requests.recv().await {
+                if write_frame(&mut writer, &request).await.is_err() {
+                    writer_shared.close();
+                    break;
+                }
+            }
+        });
+
+        let reader_shared = shared.clone();
+        let reader = tokio::spawn(async move {
+            while let Ok(Some(response)) = read_frame::<_, Response>(&mut reader).await {
+                let waiting = reader_shared.pending.lock().unwrap().as_mut().and_then(|pending| pending.remove(&response.id));
+                if let Some(waiting) = waiting {
+                    let _ = waiting.send(response.result);
+                }
+            }
+            reader_shared.close();
+        });
+
+        let conn = Connection { next_id: AtomicU64::new(1), outgoing, shared, reader: reader.abort_handle() };
+        Ok(RemoteNode { conn: Arc::new(conn) })
+    }
+
+    ///
+    /// 远程节点上以 name 导出的 actor，名称不存在时在投递时返回 Delivery 错误
+    ///
+    pub fn actor<A>(&self, name: &str) -> Addr<A>
+        where
+            A: Actor,
+            A::Message: RemoteMessage,
+            A::Reply: RemoteMessage
+    {
+        Addr::remote(Arc::new(RemoteActor { conn: self.conn.clone(), name: name.to_string(), actor: PhantomData }))
+    }
+
+    pub fn is_connected(&self) -> bool {
+        !self.conn.is_closed()
+    }
+
+    ///
+    /// 等待连接断开
+    ///
+    pub async fn closed(&self) {
+        self.conn.closed().await
+    }
+}
+
+struct RemoteActor<A> {
+    conn: Arc<Connection>,
+    name: String,
+    actor: PhantomData<fn() -> A>,
+}
+
+#[async_trait]
+impl<A> Link<A> for RemoteActor<A>
+    where
+        A: Actor,
+        A::Message: RemoteMessage,
+        A::Reply: RemoteMessage
+{
+    async fn send(&self, msg: A::Message, priority: Priority) -> Result<(), ActorError> {
+        let payload = serde_json::to_value(msg).map_err(delivery)?;
+        let actor = self.name.clone();
+        self.conn.request(|id| Request::Send { id, actor, payload, priority }).await.map(|_| ())
+    }
+
+    async fn ask(&self, msg: A::Message, priority: Priority) -> Result<A::Reply, ActorError> {
+        let payload = serde_json::to_value(msg).map_err(delivery)?;
+        let actor = self.name.clone();
+        let reply = self.conn.request(|id| Request::Ask { id, actor, payload, priority }).await?;
+        serde_json::from_value(reply).map_err(delivery)
+    }
+
+    async fn stop(&self, priority: Priority) {
+        let actor = self.name.clone();
+        let _ = self.conn.request(|id| Request::Stop { id, actor, priority }).await;
+    }
+
+    fn is_alive(&self) -> bool {
+        !self.conn.is_closed()
+    }
+
+    async fn closed(&self) {
+        self.conn.closed().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::actor_model::actor;
+    use crate::actor_model::context::Context;
+
+    #[derive(Serialize, Deserialize)]
+    enum CounterMessage {
+        Add(u32),
+        Get,
+        Fail,
+    }
+
+    #[derive(Default)]
+    struct Counter {
+        count: u32,
+    }
+
+    #[async_trait]
+    impl Actor for Counter {
+        type Message = CounterMessage;
+        type Reply = u32;
+
+        async fn handle(&mut self, msg: CounterMessage, _ctx: &mut Context<Self>) -> u32 {
+            match msg {
+                CounterMessage::Add(n) => self.count += n,
+                CounterMessage::Get => {}
+                CounterMessage::Fail => panic!("counter failed"),
+            }
+            self.count
+        }
+    }
+
+    ///
+    /// 同一段代码分别作用于本地与远程地址
+    ///
+    async fn count_to(addr: &Addr<Counter>, n: u32) -> Result<u32, ActorError> {
+        for _ in 0..n {
+            addr.send(CounterMessage::Add(1)).await?;
+        }
+        addr.ask(CounterMessage::Get).await
+    }
+
+    async fn start() -> (Node, RemoteNode) {
+        let node = Node::new();
+        node.export("counter", &actor::spawn(Counter::default()));
+        let addr = node.listen("127.0.0.1:0").await.unwrap();
+        (node, RemoteNode::connect(addr).await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_location_transparency() {
+        let local = actor::spawn(Counter::default());
+        assert_eq!(count_to(&local, 10).await, Ok(10));
+
+        let (_node, remote_node) = start().await;
+        let remote = remote_node.actor::<Counter>("counter");
+        assert!(!remote.is_local());
+        assert_eq!(count_to(&remote, 10).await, Ok(10));
+        assert_eq!(remote.clone().ask(CounterMessage::Add(5)).await, Ok(15));
+
+        // 并发的 ask 在同一个连接上各自得到回复
+        let asks: Vec<_> = (0..5).map(|_| {
+            let remote = remote.clone();
+            tokio::spawn(async move { remote.ask(CounterMessage::Add(1)).await.unwrap() })
+        }).collect();
+        let mut replies = Vec::new();
+        for ask in asks {
+            replies.push(ask.await.unwrap());
+        }
+        replies.sort();
+        assert_eq!(replies, vec![16, 17, 18, 19, 20]);
+    }
+
+    #[tokio::test]
+    async fn test_remote_failures() {
+        let (node, remote_node) = start().await;
+        let missing = remote_node.actor::<Counter>("missing");
+        assert_eq!(
+            missing.ask(CounterMessage::Get).await,
+            Err(ActorError::Delivery("no actor exported as missing".into()))
+        );
+
+        // 远程 actor 的错误原样返回
+        let counter = remote_node.actor::<Counter>("counter");
+        assert_eq!(counter.ask(CounterMessage::Fail).await, Err(ActorError::NoReply));
+        assert_eq!(counter.send(CounterMessage::Add(1)).await, Err(ActorError::Stopped));
+
+        node.shutdown();
+        remote_node.closed().await;
+        assert!(!remote_node.is_connected());
+        assert!(!counter.is_alive());
+        assert_eq!(counter.ask(CounterMessage::Get).await, Err(ActorError::Delivery("connection closed".into())));
+    }
+
+    #[tokio::test]
+    async fn test_stop_remote_actor() {
+        let node = Node::new();
+        let local = actor::spawn(Counter::default());
+        node.export("counter", &local);
+        let remote_node = RemoteNode::connect(node.listen("127.0.0.1:0").await.unwrap()).await.unwrap();
+        let remote = remote_node.actor::<Counter>("counter");
+        remote.stop().await;
+        local.closed().await;
+        assert_eq!(remote.ask(CounterMessage::Get).await, Err(ActorError::Stopped));
+
+        assert!(node.unexport("counter"));
+        assert!(!node.unexport("counter"));
+    }
+}
@@ -0,0 +1,545 @@
+//!
+//! 监督者负责启动子 actor，在子 actor panic 时按策略重启
+//! 子 actor 的邮箱由监督者持有，重启只替换 actor 实例，原有的 Addr 继续可用。
+//! 正常停止的子 actor 不会被重启；重启过于频繁时监督者停止全部子 actor，
+//! 并以失败退出，交给上一级监督者处理。
+//!
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+use async_trait::async_trait;
+use tokio::sync::{mpsc, Mutex, Notify};
+use tokio::task::{JoinError, JoinHandle};
+use tokio::time::Instant;
+use crate::actor_model::actor::{self, Actor, ActorId, Envelope};
+use crate::actor_model::addr::Addr;
+use crate::actor_model::context::Context;
+use crate::actor_model::mailbox::{MailboxConfig, Receiver, WeakSender};
+
+///
+/// 子 actor 失败后的重启策略
+///
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Strategy {
+    /// 只重启失败的子 actor
+    OneForOne,
+    /// 停止并重启全部子 actor
+    OneForAll,
+    /// 重启失败的子 actor 以及在它之后启动的子 actor
+    RestForOne,
+}
+
+///
+/// 子 actor 或监督者的退出原因
+///
+#[derive(Clone, Debug, PartialEq)]
+pub enum Exit {
+    Normal,
+    Failed(String),
+}
+
+fn exit_of(result: Result<Exit, JoinError>) -> Exit {
+    match result {
+        Ok(exit) => exit,
+        Err(e) if e.is_panic() => {
+            let panic = e.into_panic();
+            let reason = panic
+                .downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "panic".into());
+            Exit::Failed(reason)
+        }
+        Err(_) => Exit::Failed("cancelled".into()),
+    }
+}
+
+///
+/// 可以被监督者启动的子节点，kill 被通知时子节点应尽快退出
+///
+trait Child: Send + Sync {
+    fn start(&self, kill: Arc<Notify>) -> JoinHandle<Exit>;
+
+    ///
+    /// 子节点包含的全部 actor
+    ///
+    fn actor_ids(&self) -> Vec<ActorId>;
+
+    ///
+    /// 子节点包含的全部邮箱，与 actor_ids 的顺序相同
+    ///
+    fn mailboxes(&self) -> Vec<Arc<dyn Drain>>;
+}
+
+///
+/// 排空子 actor 的邮箱，与重启无关，邮箱在监督者退出前一直有效
+///
+#[async_trait]
+trait Drain: Send + Sync {
+    async fn shutdown(&self, timeout: Duration) -> bool;
+}
+
+#[async_trait]
+impl<T: Send> Drain for WeakSender<T> {
+    async fn shutdown(&self, timeout: Duration) -> bool {
+        WeakSender::shutdown(self, timeout).await
+    }
+}
+
+struct ActorChild<A: Actor> {
+    id: ActorId,
+    sender: WeakSender<Envelope<A>>,
+    mailbox: Arc<Mutex<Receiver<Envelope<A>>>>,
+    factory: Arc<dyn Fn() -> A + Send + Sync>,
+    started: AtomicBool,
+}
+
+impl<A: Actor> Child for ActorChild<A> {
+    fn start(&self, kill: Arc<Notify>) -> JoinHandle<Exit> {
+        if self.started.swap(true, Ordering::SeqCst) {
+            self.sender.metrics().restarted();
+        }
+        let ctx = Context::new(self.id, self.sender.clone());
+        let mailbox = self.mailbox.clone();
+        let factory = self.factory.clone();
+        tokio::spawn(async move {
+            let mut mailbox = mailbox.lock().await;
+            actor::run(factory(), ctx, &mut mailbox, Some(&kill)).await;
+            Exit::Normal
+        })
+    }
+
+    fn actor_ids(&self) -> Vec<ActorId> {
+        vec![self.id]
+    }
+
+    fn mailboxes(&self) -> Vec<Arc<dyn Drain>> {
+        vec![Arc::new(self.sender.clone())]
+    }
+}
+
+struct SupervisorChild(Arc<Supervisor>);
+
+impl Child for SupervisorChild {
+    fn start(&self, kill: Arc<Notify>) -> JoinHandle<Exit> {
+        let supervisor = self.0.clone();
+        tokio::spawn(async move { supervise(&supervisor, &kill).await })
+    }
+
+    fn actor_ids(&self) -> Vec<ActorId> {
+        self.0.actor_ids()
+    }
+
+    fn mailboxes(&self) -> Vec<Arc<dyn Drain>> {
+        self.0.mailboxes()
+    }
+}
+
+pub struct Supervisor {
+    strategy: Strategy,
+    max_restarts: usize,
+    period: Duration,
+    children: Vec<Box<dyn Child>>,
+    restarts: Arc<AtomicUsize>,
+}
+
+impl Supervisor {
+    ///
+    /// 默认 5 秒内最多重启 3 次
+    ///
+    pub fn new(strategy: Strategy) -> Supervisor {
+        Supervisor {
+            strategy,
+            max_restarts: 3,
+            period: Duration::from_secs(5),
+            children: vec![],
+            restarts: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    ///
+    /// period 时间内重启次数超过 max_restarts 时放弃重启并向上一级报告失败
+    ///
+    pub fn max_restarts(mut self, max_restarts: usize, period: Duration) -> Supervisor {
+        self.max_restarts = max_restarts;
+        self.period = period;
+        self
+    }
+
+    ///
+    /// 添加子 actor，factory 在每次启动与重启时创建新的实例
+    /// 子 actor 按添加的顺序启动，停止时顺序相反
+    ///
+    pub fn child<A, F>(&mut self, factory: F) -> Addr<A>
+        where
+            A: Actor,
+            F: Fn() -> A + Send + Sync + 'static
+    {
+        self.child_with(MailboxConfig::default(), factory)
+    }
+
+    ///
+    /// 使用指定的邮箱配置添加子 actor，邮箱在重启之间保留
+    ///
+    pub fn child_with<A, F>(&mut self, config: MailboxConfig, factory: F) -> Addr<A>
+        where
+            A: Actor,
+            F: Fn() -> A + Send + Sync + 'static
+    {
+        let (addr, sender, mailbox) = actor::mailbox(config);
+        self.children.push(Box::new(ActorChild {
+            id: addr.id(),
+            sender,
+            mailbox: Arc::new(Mutex::new(mailbox)),
+            factory: Arc::new(factory),
+            started: AtomicBool::new(false),
+        }));
+        addr
+    }
+
+    ///
+    /// 添加下一级监督者，它放弃重启时由当前监督者按策略处理
+    ///
+    pub fn supervisor(&mut self, child: Supervisor) -> &mut Supervisor {
+        self.children.push(Box::new(SupervisorChild(Arc::new(child))));
+        self
+    }
+
+    ///
+    /// 所有层级的子 actor，按添加的顺序排列
+    ///
+    pub fn actor_ids(&self) -> Vec<ActorId> {
+        self.children.iter().flat_map(|child| child.actor_ids()).collect()
+    }
+
+    fn mailboxes(&self) -> Vec<Arc<dyn Drain>> {
+        self.children.iter().flat_map(|child| child.mailboxes()).collect()
+    }
+
+    pub fn start(self) -> SupervisorHandle {
+        let kill = Arc::new(Notify::new());
+        let restarts = self.restarts.clone();
+        let mailboxes = self.mailboxes();
+        let signal = kill.clone();
+        let handle = tokio::spawn(async move { supervise(&self, &signal).await });
+        SupervisorHandle { kill, handle, restarts, mailboxes }
+    }
+}
+
+///
+/// 顶层监督者的句柄，监督者退出后全部子 actor 的邮箱随之关闭
+///
+pub struct SupervisorHandle {
+    kill: Arc<Notify>,
+    handle: JoinHandle<Exit>,
+    restarts: Arc<AtomicUsize>,
+    mailboxes: Vec<Arc<dyn Drain>>,
+}
+
+impl SupervisorHandle {
+    ///
+    /// 已经执行的重启次数，按策略一次重启多个子 actor 只计一次
+    ///
+    pub fn restarts(&self) -> usize {
+        self.restarts.load(Ordering::SeqCst)
+    }
+
+    pub fn is_alive(&self) -> bool {
+        !self.handle.is_finished()
+    }
+
+    ///
+    /// 按启动的相反顺序停止全部子 actor
+    ///
+    pub async fn stop(self) -> Exit {
+        self.kill.notify_one();
+        self.join().await
+    }
+
+    ///
+    /// 按启动的相反顺序逐个排空子 actor 的邮箱，包括下一级监督者的子 actor，
+    /// 每个子 actor 最多用 timeout，超时丢弃剩余的消息；之后停止监督者
+    /// 全部按时排空时返回 true
+    ///
+    pub async fn shutdown(self, timeout: Duration) -> bool {
+        let mut drained = true;
+        for mailbox in self.mailboxes.iter().rev() {
+            drained &= mailbox.shutdown(timeout).await;
+        }
+        self.stop().await;
+        drained
+    }
+
+    ///
+    /// 等待监督者退出，放弃重启时返回 Failed
+    ///
+    pub async fn join(self) -> Exit {
+        exit_of(self.handle.await)
+    }
+}
+
+#[derive(Default)]
+struct Slot {
+    kill: Arc<Notify>,
+    watcher: Option<JoinHandle<()>>,
+    generation: u64,
+    /// 子 actor 已经正常停止，不再重启
+    finished: bool,
+}
+
+type ExitEvents = mpsc::UnboundedSender<(usize, u64, Exit)>;
+
+fn start_child(child: &dyn Child, slot: &mut Slot, index: usize, events: &ExitEvents) {
+    slot.generation += 1;
+    slot.kill = Arc::new(Notify::new());
+    let handle = child.start(slot.kill.clone());
+    let (events, generation) = (events.clone(), slot.generation);
+    slot.watcher = Some(tokio::spawn(async move {
+        let _ = events.send((index, generation, exit_of(handle.await)));
+    }));
+}
+
+///
+/// 按相反顺序停止 from 之后仍在运行的子节点，并等待它们退出
+///
+async fn stop_children(slots: &mut [Slot], from: usize) {
+    for slot in slots[from..].iter_mut().rev() {
+        if let Some(watcher) = slot.watcher.take() {
+            slot.kill.notify_one();
+            let _ = watcher.await;
+            // 忽略被停止的子节点发出的退出事件
+            slot.generation += 1;
+        }
+    }
+}
+
+async fn supervise(supervisor: &Supervisor, kill: &Notify) -> Exit {
+    let (events_tx, mut events) = mpsc::unbounded_channel();
+    let mut slots: Vec<Slot> = supervisor.children.iter().map(|_| Slot::default()).collect();
+    for (index, (child, slot)) in supervisor.children.iter().zip(slots.iter_mut()).enumerate() {
+        start_child(child.as_ref(), slot, index, &events_tx);
+    }
+    let mut history = VecDeque::new();
+    loop {
+        let (index, generation, exit) = tokio::select! {
+            _ = kill.notified() => {
+                stop_children(&mut slots, 0).await;
+                return Exit::Normal;
+            }
+            Some(event) = events.recv() => event,
+        };
+        if slots[index].generation != generation {
+            continue;
+        }
+        slots[index].watcher = None;
+        let reason = match exit {
+            Exit::Normal => {
+                slots[index].finished = true;
+                continue;
+            }
+            Exit::Failed(reason) => reason,
+        };
+
+        let now = Instant::now();
+        history.push_back(now);
+        while matches!(history.front(), Some(t) if now.duration_since(*t) > supervisor.period) {
+            history.pop_front();
+        }
+        if history.len() > supervisor.max_restarts {
+            stop_children(&mut slots, 0).await;
+            return Exit::Failed(format!("restart intensity exceeded: {}", reason));
+        }
+
+        let from = match supervisor.strategy {
+            Strategy::OneForOne => index,
+            Strategy::OneForAll => 0,
+            Strategy::RestForOne => index,
+        };
+        if supervisor.strategy != Strategy::OneForOne {
+            stop_children(&mut slots, from).await;
+        }
+        for (i, slot) in slots.iter_mut().enumerate().skip(from) {
+            let restart = if supervisor.strategy == Strategy::OneForOne { i == index } else { !slot.finished };
+            if restart {
+                start_child(supervisor.children[i].as_ref(), slot, i, &events_tx);
+            }
+        }
+        supervisor.restarts.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use crate::actor_model::error::ActorError;
+    use crate::actor_model::system::ActorSystem;
+
+    enum CounterMessage {
+        Add(u32),
+        Panic,
+    }
+
+    #[derive(Default)]
+    struct Counter {
+        count: u32,
+    }
+
+    #[async_trait]
+    impl Actor for Counter {
+        type Message = CounterMessage;
+        type Reply = u32;
+
+        async fn handle(&mut self, msg: CounterMessage, _ctx: &mut Context<Self>) -> u32 {
+            match msg {
+                CounterMessage::Add(n) => self.count += n,
+                CounterMessage::Panic => panic!("counter failed"),
+            }
+            self.count
+        }
+    }
+
+    async fn counts(addrs: &[&Addr<Counter>]) -> Vec<u32> {
+        let mut counts = vec![];
+        for addr in addrs {
+            counts.push(addr.ask(CounterMessage::Add(0)).await.unwrap());
+        }
+        counts
+    }
+
+    async fn fail(handle: &SupervisorHandle, addr: &Addr<Counter>) {
+        let restarts = handle.restarts();
+        assert_eq!(addr.ask(CounterMessage::Panic).await, Err(ActorError::NoReply));
+        while handle.restarts() == restarts {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    async fn three(strategy: Strategy) -> Vec<u32> {
+        let mut supervisor = Supervisor::new(strategy);
+        let (a, b, c) = (supervisor.child(Counter::default), supervisor.child(Counter::default), supervisor.child(Counter::default));
+        let handle = supervisor.start();
+        for (i, addr) in [&a, &b, &c].iter().enumerate() {
+            addr.send(CounterMessage::Add(i as u32 + 1)).await.unwrap();
+        }
+        fail(&handle, &b).await;
+        assert_eq!(handle.restarts(), 1);
+        let counts = counts(&[&a, &b, &c]).await;
+        assert_eq!(handle.stop().await, Exit::Normal);
+        assert_eq!(a.send(CounterMessage::Add(1)).await, Err(ActorError::Stopped));
+        counts
+    }
+
+    #[tokio::test]
+    async fn test_strategies() {
+        assert_eq!(three(Strategy::OneForOne).await, vec![1, 0, 3]);
+        assert_eq!(three(Strategy::OneForAll).await, vec![0, 0, 0]);
+        assert_eq!(three(Strategy::RestForOne).await, vec![1, 0, 0]);
+    }
+
+    #[tokio::test]
+    async fn test_messages_kept_across_restart() {
+        let mut supervisor = Supervisor::new(Strategy::OneForOne);
+        let addr = supervisor.child(Counter::default);
+        let _handle = supervisor.start();
+        addr.send(CounterMessage::Add(5)).await.unwrap();
+        addr.send(CounterMessage::Panic).await.unwrap();
+        // 排在 panic 之后的消息由重启后的实例处理
+        addr.send(CounterMessage::Add(2)).await.unwrap();
+        assert_eq!(addr.ask(CounterMessage::Add(0)).await, Ok(2));
+    }
+
+    #[tokio::test]
+    async fn test_restart_intensity() {
+        let mut supervisor = Supervisor::new(Strategy::OneForOne).max_restarts(1, Duration::from_secs(60));
+        let addr = supervisor.child(Counter::default);
+        let handle = supervisor.start();
+        fail(&handle, &addr).await;
+        assert_eq!(addr.ask(CounterMessage::Panic).await, Err(ActorError::NoReply));
+        assert_eq!(handle.join().await, Exit::Failed("restart intensity exceeded: counter failed".into()));
+        assert_eq!(addr.ask(CounterMessage::Add(1)).await, Err(ActorError::Stopped));
+    }
+
+    #[tokio::test]
+    async fn test_escalation() {
+        let mut inner = Supervisor::new(Strategy::OneForOne).max_restarts(1, Duration::from_secs(60));
+        let child = inner.child(Counter::default);
+        let mut root = Supervisor::new(Strategy::OneForAll);
+        let sibling = root.child(Counter::default);
+        root.supervisor(inner);
+        let handle = root.start();
+
+        sibling.send(CounterMessage::Add(7)).await.unwrap();
+        child.send(CounterMessage::Add(3)).await.unwrap();
+        assert_eq!(child.ask(CounterMessage::Panic).await, Err(ActorError::NoReply));
+        assert_eq!(counts(&[&sibling, &child]).await, vec![7, 0]);
+        assert_eq!(handle.restarts(), 0);
+
+        // 下一级监督者放弃后，上一级按 OneForAll 重启全部子节点
+        fail(&handle, &child).await;
+        assert_eq!(handle.restarts(), 1);
+        assert_eq!(counts(&[&sibling, &child]).await, vec![0, 0]);
+        assert_eq!(handle.stop().await, Exit::Normal);
+    }
+
+    ///
+    /// 每条消息耗时 ms 毫秒，停止时记录处理过的消息数
+    ///
+    struct Worker {
+        name: &'static str,
+        done: u32,
+        log: Arc<std::sync::Mutex<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl Actor for Worker {
+        type Message = u64;
+        type Reply = ();
+
+        async fn handle(&mut self, ms: u64, _ctx: &mut Context<Self>) {
+            tokio::time::sleep(Duration::from_millis(ms)).await;
+            self.done += 1;
+        }
+
+        async fn stopping(&mut self, _ctx: &mut Context<Self>) {
+            self.log.lock().unwrap().push(format!("{} {}", self.name, self.done));
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_graceful_shutdown() {
+        let log = Arc::new(std::sync::Mutex::new(vec![]));
+        let worker = |name| {
+            let log = log.clone();
+            move || Worker { name, done: 0, log: log.clone() }
+        };
+        let mut root = Supervisor::new(Strategy::OneForOne);
+        let a = root.child(worker("a"));
+        let mut inner = Supervisor::new(Strategy::OneForOne);
+        let b = inner.child(worker("b"));
+        root.supervisor(inner);
+        let handle = root.start();
+        for _ in 0..3 {
+            a.send(10).await.unwrap();
+            b.send(10).await.unwrap();
+        }
+        // 按相反顺序排空，下一级监督者的子 actor 先停止
+        assert!(handle.shutdown(Duration::from_secs(1)).await);
+        assert_eq!(*log.lock().unwrap(), vec!["b 3", "a 3"]);
+        assert_eq!(a.send(10).await, Err(ActorError::Stopped));
+
+        // 超时后丢弃剩余的消息，正在处理的消息仍会完成
+        log.lock().unwrap().clear();
+        let system = ActorSystem::new();
+        let mut supervisor = Supervisor::new(Strategy::OneForOne);
+        let c = supervisor.child(worker("c"));
+        system.supervise(supervisor);
+        for _ in 0..5 {
+            c.send(100).await.unwrap();
+        }
+        assert!(!system.shutdown(Duration::from_millis(250)).await);
+        assert_eq!(*log.lock().unwrap(), vec!["c 3"]);
+    }
+}
@@ -0,0 +1,187 @@
+//!
+//! actor 系统：记录通过它启动的 actor 与监督者，关闭时按启动的相反顺序逐个停止
+//! 系统只持有不计数的引用，actor 仍会在所有 Addr 释放后自行停止。
+//!
+
+use std::sync::Mutex;
+use std::time::Duration;
+use async_trait::async_trait;
+use crate::actor_model::actor::{self, Actor, ActorId, Envelope};
+use crate::actor_model::addr::Addr;
+use crate::actor_model::mailbox::{MailboxConfig, WeakSender};
+use crate::actor_model::metrics::{self, MetricsSnapshot};
+use crate::actor_model::supervisor::{Supervisor, SupervisorHandle};
+
+#[async_trait]
+trait Stop: Send + Sync {
+    async fn shutdown(&self, timeout: Duration) -> bool;
+    fn is_finished(&self) -> bool;
+    fn actor_ids(&self) -> Vec<ActorId>;
+}
+
+struct ActorEntry<A: Actor>(ActorId, WeakSender<Envelope<A>>);
+
+#[async_trait]
+impl<A: Actor> Stop for ActorEntry<A> {
+    async fn shutdown(&self, timeout: Duration) -> bool {
+        self.1.shutdown(timeout).await
+    }
+
+    fn is_finished(&self) -> bool {
+        self.1.is_terminated()
+    }
+
+    fn actor_ids(&self) -> Vec<ActorId> {
+        vec![self.0]
+    }
+}
+
+struct SupervisorEntry(Mutex<Option<SupervisorHandle>>, Vec<ActorId>);
+
+#[async_trait]
+impl Stop for SupervisorEntry {
+    async fn shutdown(&self, timeout: Duration) -> bool {
+        let handle = self.0.lock().unwrap().take();
+        match handle {
+            Some(handle) => handle.shutdown(timeout).await,
+            None => true,
+        }
+    }
+
+    fn is_finished(&self) -> bool {
+        !matches!(self.0.lock().unwrap().as_ref(), Some(handle) if handle.is_alive())
+    }
+
+    fn actor_ids(&self) -> Vec<ActorId> {
+        self.1.clone()
+    }
+}
+
+#[derive(Default)]
+pub struct ActorSystem {
+    entries: Mutex<Vec<Box<dyn Stop>>>,
+}
+
+impl ActorSystem {
+    pub fn new() -> ActorSystem {
+        ActorSystem::default()
+    }
+
+    pub fn spawn<A: Actor>(&self, actor: A) -> Addr<A> {
+        self.spawn_with(actor, MailboxConfig::default())
+    }
+
+    pub fn spawn_with<A: Actor>(&self, actor: A, config: MailboxConfig) -> Addr<A> {
+        let (addr, sender) = actor::spawn_local(actor, config);
+        self.add(Box::new(ActorEntry(addr.id(), sender)));
+        addr
+    }
+
+    ///
+    /// 启动监督者，关闭系统时与其他 actor 一起按启动顺序的相反顺序停止
+    ///
+    pub fn supervise(&self, supervisor: Supervisor) {
+        let ids = supervisor.actor_ids();
+        self.add(Box::new(SupervisorEntry(Mutex::new(Some(supervisor.start())), ids)));
+    }
+
+    ///
+    /// 通过系统启动、仍在运行的 actor 的统计快照，包括监督者的子 actor
+    ///
+    pub fn metrics(&self) -> MetricsSnapshot {
+        let ids: Vec<ActorId> = self.entries.lock().unwrap().iter().flat_map(|entry| entry.actor_ids()).collect();
+        metrics::snapshot_filtered(&ids)
+    }
+
+    ///
+    /// 仍在运行的 actor 与监督者数量
+    ///
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().iter().filter(|entry| !entry.is_finished()).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    ///
+    /// 按启动的相反顺序逐个停止，每个 actor 最多用 timeout 排空邮箱
+    /// 全部按时排空时返回 true
+    ///
+    pub async fn shutdown(&self, timeout: Duration) -> bool {
+        let entries = std::mem::take(&mut *self.entries.lock().unwrap());
+        let mut drained = true;
+        for entry in entries.iter().rev() {
+            drained &= entry.shutdown(timeout).await;
+        }
+        drained
+    }
+
+    fn add(&self, entry: Box<dyn Stop>) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|entry| !entry.is_finished());
+        entries.push(entry);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use crate::actor_model::context::Context;
+    use crate::actor_model::supervisor::Strategy;
+
+    struct Named {
+        name: &'static str,
+        log: Arc<Mutex<Vec<&'static str>>>,
+    }
+
+    #[async_trait]
+    impl Actor for Named {
+        type Message = ();
+        type Reply = &'static str;
+
+        async fn handle(&mut self, _msg: (), _ctx: &mut Context<Self>) -> &'static str {
+            self.name
+        }
+
+        async fn stopped(&mut self, _ctx: &mut Context<Self>) {
+            self.log.lock().unwrap().push(self.name);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_in_reverse_order() {
+        let system = ActorSystem::new();
+        let log = Arc::new(Mutex::new(vec![]));
+        let named = |name| Named { name, log: log.clone() };
+        let a = system.spawn(named("a"));
+        let b = system.spawn_with(named("b"), MailboxConfig::unbounded());
+        let mut supervisor = Supervisor::new(Strategy::OneForOne);
+        let supervised_log = log.clone();
+        let supervised = supervisor.child(move || Named { name: "supervised", log: supervised_log.clone() });
+        system.supervise(supervisor);
+        let c = system.spawn(named("c"));
+        assert_eq!(system.len(), 4);
+        assert_eq!(supervised.ask(()).await, Ok("supervised"));
+
+        assert!(system.shutdown(Duration::from_secs(1)).await);
+        assert_eq!(*log.lock().unwrap(), vec!["c", "supervised", "b", "a"]);
+        assert!(!a.is_alive() && !b.is_alive() && !c.is_alive());
+        assert!(system.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_actor_stopped_on_its_own() {
+        let system = ActorSystem::new();
+        let log = Arc::new(Mutex::new(vec![]));
+        let addr = system.spawn(Named { name: "a", log: log.clone() });
+        let join = addr.join_handle();
+        drop(addr);
+        join.join().await;
+        assert!(join.is_finished());
+        assert_eq!(system.len(), 0);
+        assert!(system.shutdown(Duration::from_secs(1)).await);
+        assert_eq!(*log.lock().unwrap(), vec!["a"]);
+    }
+}
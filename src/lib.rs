@@ -1,4 +1,4 @@
-mod actor_model;
+pub mod actor_model;
 mod async_server;
 mod common_trait;
 mod component_or_entity_system;
@@ -9,4 +9,3 @@ mod game_components;
 mod knowledge_points;
 pub mod rpc;
 pub mod websocket;
-// mod http_get;
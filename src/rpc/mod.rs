@@ -2,9 +2,11 @@ mod client;
 pub mod server;
 
 use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
 use std::any::Any;
+use std::io;
 use tokio::net::TcpStream;
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 fn decode<'a, T: Deserialize<'a>>(data: &'a [u8]) -> T {
     serde_json::from_slice(data).unwrap()
@@ -16,6 +18,50 @@ async fn encode_and_send<T: Serialize>(stream: &mut TcpStream, data: T) {
     stream.flush();
 }
 
+///
+/// 单个帧的最大长度，超过时视为数据错误
+///
+const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+///
+/// 以 4 字节大端长度作为前缀写出一个 JSON 帧，同一个连接上可以连续收发多个帧
+///
+pub(crate) async fn write_frame<W, T>(writer: &mut W, data: &T) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+        T: Serialize
+{
+    let data = serde_json::to_vec(data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+    let mut frame = (data.len() as u32).to_be_bytes().to_vec();
+    frame.extend(data);
+    writer.write_all(&frame).await
+}
+
+///
+/// 读取一个 write_frame 写出的帧，对端在帧边界关闭连接时返回 None
+///
+pub(crate) async fn read_frame<R, T>(reader: &mut R) -> io::Result<Option<T>>
+    where
+        R: AsyncRead + Unpin,
+        T: DeserializeOwned
+{
+    let mut len = [0; 4];
+    match reader.read_exact(&mut len).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "frame too large"));
+    }
+    let mut data = vec![0; len];
+    reader.read_exact(&mut data).await?;
+    serde_json::from_slice(&data)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+}
+
 trait Data {
     fn data(&self) -> &str;
     fn set_data<T>(&mut self, data: T) where T: Serialize;
@@ -87,3 +133,24 @@ trait HelloService {
     fn send_hello(&self, author: String, content: String) -> String;
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_frame_round_trip() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        let writer = tokio::spawn(async move {
+            write_frame(&mut client, &Request::new("say_hello".into(), ("rpc",))).await.unwrap();
+            write_frame(&mut client, &"x".repeat(200)).await.unwrap();
+        });
+        let request: Request = read_frame(&mut server).await.unwrap().unwrap();
+        assert_eq!((request.type_name.as_str(), request.data.as_str()), ("say_hello", "[\"rpc\"]"));
+        assert_eq!(read_frame::<_, String>(&mut server).await.unwrap(), Some("x".repeat(200)));
+        writer.await.unwrap();
+        assert!(read_frame::<_, String>(&mut server).await.unwrap().is_none());
+
+        let mut data: &[u8] = &[0, 0, 0, 3, b'{', b'}', b'x'];
+        assert_eq!(read_frame::<_, Request>(&mut data).await.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,407 @@
+use rand::Rng;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use crate::websocket::deflate::{DeflateConfig, DeflateParams, Deflater, Inflater};
+use crate::websocket::error::WebsocketError;
+use crate::websocket::frame::{self, FrameLimits, MessageDecoder};
+use crate::websocket::handler::{CloseFrame, Message};
+use crate::websocket::handshake::{self, HandshakeResponse};
+use crate::websocket::server::{ServerConfig, WebsocketSubProtocols};
+
+///
+/// 客户端握手时的可选配置
+///
+#[derive(Clone)]
+pub struct ClientConfig {
+    /// 按优先级提供给服务端的子协议
+    pub protocols: Vec<WebsocketSubProtocols>,
+    /// 为 Some 时请求 permessage-deflate 压缩扩展
+    pub deflate: Option<DeflateConfig>,
+    /// 额外的请求头，例如 Origin、Authorization
+    pub headers: Vec<(String, String)>,
+    /// 服务端发来的单个帧负载的最大字节数
+    pub max_frame_size: usize,
+    /// 服务端发来的单条消息（解压后）的最大字节数
+    pub max_message_size: usize,
+}
+
+///
+/// 消息大小的限制默认与服务端相同
+///
+impl Default for ClientConfig {
+    fn default() -> Self {
+        let server = ServerConfig::default();
+        ClientConfig {
+            protocols: vec![],
+            deflate: None,
+            headers: vec![],
+            max_frame_size: server.max_frame_size,
+            max_message_size: server.max_message_size,
+        }
+    }
+}
+
+///
+/// websocket 客户端，与 WebsocketServer 共用同一套帧编解码
+///
+pub struct WebsocketClient {
+    stream: TcpStream,
+    buf: Vec<u8>,
+    protocol: Option<WebsocketSubProtocols>,
+    deflater: Option<Deflater>,
+    decoder: MessageDecoder,
+    limits: FrameLimits,
+    close_sent: bool,
+    close_received: bool,
+}
+
+///
+/// 解析后的 ws:// 地址
+///
+struct WebsocketUrl {
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl WebsocketUrl {
+    fn parse(url: &str) -> Result<WebsocketUrl, WebsocketError> {
+        let rest = match url.strip_prefix("ws://") {
+            Some(rest) => rest,
+            None => return Err(WebsocketError::Handshake(format!("unsupported url: {}", url))),
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        // IPv6 地址写在方括号中，其中的冒号不是端口分隔符
+        let (host, port) = match authority.strip_prefix('[') {
+            Some(bracketed) => match bracketed.split_once(']') {
+                Some((host, "")) => (host, None),
+                Some((host, port)) => match port.strip_prefix(':') {
+                    Some(port) => (host, Some(port)),
+                    None => return Err(WebsocketError::Handshake(format!("invalid host in url: {}", url))),
+                },
+                None => return Err(WebsocketError::Handshake(format!("invalid host in url: {}", url))),
+            },
+            None => match authority.rsplit_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            },
+        };
+        let port = match port {
+            Some(port) => port
+                .parse::<u16>()
+                .map_err(|_| WebsocketError::Handshake(format!("invalid port in url: {}", url)))?,
+            None => 80,
+        };
+        if host.is_empty() {
+            return Err(WebsocketError::Handshake(format!("missing host in url: {}", url)));
+        }
+        Ok(WebsocketUrl { host: host.into(), port, path: path.into() })
+    }
+
+    fn host_header(&self) -> String {
+        let host = if self.host.contains(':') { format!("[{}]", self.host) } else { self.host.clone() };
+        if self.port == 80 {
+            host
+        } else {
+            format!("{}:{}", host, self.port)
+        }
+    }
+}
+
+impl WebsocketClient {
+    pub async fn connect(url: &str) -> Result<WebsocketClient, WebsocketError> {
+        WebsocketClient::connect_with(url, ClientConfig::default()).await
+    }
+
+    ///
+    /// 建立连接并完成握手，校验 Sec-WebSocket-Accept 以及协商结果
+    ///
+    pub async fn connect_with(url: &str, config: ClientConfig) -> Result<WebsocketClient, WebsocketError> {
+        let url = WebsocketUrl::parse(url)?;
+        let mut stream = TcpStream::connect((url.host.as_str(), url.port)).await?;
+
+        let nonce: [u8; 16] = rand::thread_rng().gen();
+        let key = base64::encode(nonce);
+        let mut request: Vec<String> = vec![
+            format!("GET {} HTTP/1.1", url.path),
+            format!("Host: {}", url.host_header()),
+            "Upgrade: websocket".into(),
+            "Connection: Upgrade".into(),
+            format!("Sec-WebSocket-Key: {}", key),
+            "Sec-WebSocket-Version: 13".into(),
+        ];
+        if !config.protocols.is_empty() {
+            let names: Vec<&str> = config.protocols.iter().map(|p| p.as_str()).collect();
+            request.push(format!("Sec-WebSocket-Protocol: {}", names.join(", ")));
+        }
+        if let Some(deflate) = &config.deflate {
+            request.push(format!("Sec-WebSocket-Extensions: {}", deflate.offer()));
+        }
+        for (name, value) in config.headers.iter() {
+            request.push(format!("{}: {}", name, value));
+        }
+        stream.write_all((request.join("\r\n") + "\r\n\r\n").as_bytes()).await?;
+
+        let mut buf = Vec::new();
+        let head = handshake::read_head(&mut stream, &mut buf)
+            .await
+            .map_err(|e| WebsocketError::Handshake(e.to_string()))?;
+        let response = HandshakeResponse::parse(&head)
+            .map_err(|e| WebsocketError::Handshake(e.to_string()))?;
+
+        if response.status() != 101 {
+            return Err(WebsocketError::Handshake(format!("unexpected status {}", response.status())));
+        }
+        if !response.header_contains("Upgrade", "websocket") || !response.header_contains("Connection", "upgrade") {
+            return Err(WebsocketError::Handshake("missing upgrade headers".into()));
+        }
+        if response.header("Sec-WebSocket-Accept") != Some(handshake::accept_key(&key).as_str()) {
+            return Err(WebsocketError::Handshake("invalid Sec-WebSocket-Accept".into()));
+        }
+
+        let protocol = match response.header("Sec-WebSocket-Protocol") {
+            Some(name) => match WebsocketSubProtocols::from_name(name) {
+                Some(p) if config.protocols.contains(&p) => Some(p),
+                _ => return Err(WebsocketError::Handshake(format!("unexpected subprotocol {}", name))),
+            },
+            None => None,
+        };
+
+        let extensions = response.extensions();
+        let deflate = match (extensions.as_slice(), &config.deflate) {
+            ([], _) => None,
+            ([value], Some(_)) => match DeflateParams::parse_response(value) {
+                Some(params) => Some(params),
+                None => return Err(WebsocketError::Handshake(format!("unexpected extension {}", value))),
+            },
+            _ => return Err(WebsocketError::Handshake(format!("unexpected extensions {}", extensions.join(", ")))),
+        };
+
+        Ok(WebsocketClient {
+            stream,
+            buf,
+            protocol,
+            deflater: deflate.map(|d| Deflater::new(d.client_no_context_takeover)),
+            decoder: MessageDecoder::new(
+                deflate.map(|d| Inflater::new(d.server_no_context_takeover)),
+                config.max_message_size,
+                false,
+            ),
+            limits: FrameLimits {
+                max_frame_size: config.max_frame_size,
+                max_message_size: config.max_message_size,
+                ..FrameLimits::default()
+            },
+            close_sent: false,
+            close_received: false,
+        })
+    }
+
+    ///
+    /// 服务端选择的子协议
+    ///
+    pub fn protocol(&self) -> Option<WebsocketSubProtocols> {
+        self.protocol
+    }
+
+    ///
+    /// 是否协商了 permessage-deflate
+    ///
+    pub fn is_compressed(&self) -> bool {
+        self.deflater.is_some()
+    }
+
+    pub async fn send(&mut self, msg: Message) -> Result<(), WebsocketError> {
+        if self.close_sent {
+            return Err(WebsocketError::ConnectionClosed);
+        }
+        let data = frame::encode_message(&msg, self.deflater.as_mut(), true)?;
+        self.stream.write_all(&data).await?;
+        if let Message::Close(_) = msg {
+            self.close_sent = true;
+        }
+        Ok(())
+    }
+
+    pub async fn text<S: Into<String>>(&mut self, text: S) -> Result<(), WebsocketError> {
+        self.send(Message::Text(text.into())).await
+    }
+
+    pub async fn binary<B: Into<Vec<u8>>>(&mut self, data: B) -> Result<(), WebsocketError> {
+        self.send(Message::Binary(data.into())).await
+    }
+
+    ///
+    /// 接收下一条消息，Ping 会被自动回应
+    /// 收到关闭帧时返回 Close，之后返回 None
+    /// 服务端违反协议或消息超过大小限制时以对应的状态码关闭连接并返回错误
+    ///
+    pub async fn recv(&mut self) -> Result<Option<Message>, WebsocketError> {
+        loop {
+            if self.close_received {
+                return Ok(None);
+            }
+            let msg = match frame::read_frame(&mut self.stream, &mut self.buf, &self.limits).await {
+                Ok(Some(frame)) => self.decoder.decode(frame),
+                Ok(None) => return Ok(None),
+                Err(e) => Err(e),
+            };
+            let msg = match msg {
+                Ok(msg) => msg,
+                Err(e) => {
+                    if let (Some(code), false) = (e.close_code(), self.close_sent) {
+                        let _ = self.send(Message::Close(Some(CloseFrame { code, reason: e.to_string() }))).await;
+                    }
+                    return Err(e);
+                }
+            };
+            match msg {
+                Some(Message::Ping(payload)) if !self.close_sent => {
+                    self.send(Message::Pong(payload)).await?;
+                }
+                Some(Message::Ping(_)) => {}
+                Some(Message::Close(close_frame)) => {
+                    self.close_received = true;
+                    if !self.close_sent {
+                        self.send(Message::Close(close_frame.clone())).await?;
+                    }
+                    return Ok(Some(Message::Close(close_frame)));
+                }
+                Some(msg) => return Ok(Some(msg)),
+                None => {}
+            }
+        }
+    }
+
+    ///
+    /// 发送关闭帧并等待服务端的关闭回应，期间收到的其他消息被丢弃
+    ///
+    pub async fn close(&mut self, code: u16, reason: &str) -> Result<Option<CloseFrame>, WebsocketError> {
+        self.send(Message::Close(Some(CloseFrame { code, reason: reason.into() }))).await?;
+        loop {
+            match self.recv().await? {
+                Some(Message::Close(frame)) => return Ok(frame),
+                Some(_) => {}
+                None => return Ok(None),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_parse_url() {
+        let url = WebsocketUrl::parse("ws://127.0.0.1:7878/chat?room=1").unwrap();
+        assert_eq!((url.host.as_str(), url.port, url.path.as_str()), ("127.0.0.1", 7878, "/chat?room=1"));
+        assert_eq!(url.host_header(), "127.0.0.1:7878");
+
+        let url = WebsocketUrl::parse("ws://localhost").unwrap();
+        assert_eq!((url.host.as_str(), url.port, url.path.as_str()), ("localhost", 80, "/"));
+
+        let url = WebsocketUrl::parse("ws://[::1]:8080/chat").unwrap();
+        assert_eq!((url.host.as_str(), url.port, url.path.as_str()), ("::1", 8080, "/chat"));
+        assert_eq!(url.host_header(), "[::1]:8080");
+        let url = WebsocketUrl::parse("ws://[fe80::1]").unwrap();
+        assert_eq!((url.host.as_str(), url.port), ("fe80::1", 80));
+        assert_eq!(url.host_header(), "[fe80::1]");
+
+        assert!(WebsocketUrl::parse("wss://localhost").is_err());
+        assert!(WebsocketUrl::parse("ws://localhost:abc/").is_err());
+        assert!(WebsocketUrl::parse("ws://[::1/").is_err());
+        assert!(WebsocketUrl::parse("ws://[::1]8080/").is_err());
+        assert!(WebsocketUrl::parse("ws://[]:8080/").is_err());
+    }
+
+    ///
+    /// 只用于测试的最小服务端，返回固定的握手响应
+    ///
+    async fn raw_server(response: fn(&str) -> String) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = vec![];
+            // 校验客户端的帧带有掩码
+            let mut decoder = MessageDecoder::new(None, usize::MAX, true);
+            let request = handshake::read_request(&mut socket, &mut buf).await.unwrap();
+            let key = request.validate().unwrap().to_string();
+            socket.write_all(response(&key).as_bytes()).await.unwrap();
+
+            // 原样返回客户端的消息
+            while let Ok(Some(frame)) = frame::read_frame(&mut socket, &mut buf, &FrameLimits::default()).await {
+                let msg = decoder.decode(frame).unwrap().unwrap();
+                let data = frame::encode_message(&msg, None, false).unwrap();
+                socket.write_all(&data).await.unwrap();
+            }
+        });
+        format!("ws://{}/", addr)
+    }
+
+    #[tokio::test]
+    async fn test_send_and_recv() {
+        let url = raw_server(|key| handshake::response(key, None, None)).await;
+        let mut client = WebsocketClient::connect(&url).await.unwrap();
+        assert!(client.protocol().is_none());
+
+        client.text("hello").await.unwrap();
+        assert_eq!(client.recv().await.unwrap(), Some(Message::Text("hello".into())));
+        client.binary(vec![1, 2, 3]).await.unwrap();
+        assert_eq!(client.recv().await.unwrap(), Some(Message::Binary(vec![1, 2, 3])));
+
+        let frame = client.close(1000, "bye").await.unwrap();
+        assert_eq!(frame, Some(CloseFrame { code: 1000, reason: "bye".into() }));
+        assert!(matches!(client.text("late").await, Err(WebsocketError::ConnectionClosed)));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_accept_key() {
+        let url = raw_server(|_| handshake::response("c2FtcGxlIG5vbmNlIDEyMw==", None, None)).await;
+        assert!(matches!(WebsocketClient::connect(&url).await, Err(WebsocketError::Handshake(_))));
+    }
+
+    #[tokio::test]
+    async fn test_unexpected_protocol() {
+        let url = raw_server(|key| handshake::response(key, Some("mqtt"), None)).await;
+        assert!(matches!(WebsocketClient::connect(&url).await, Err(WebsocketError::Handshake(_))));
+
+        let url = raw_server(|key| handshake::response(key, Some("mqtt"), None)).await;
+        let config = ClientConfig { protocols: vec![WebsocketSubProtocols::MQTT], ..ClientConfig::default() };
+        let client = WebsocketClient::connect_with(&url, config).await.unwrap();
+        assert_eq!(client.protocol(), Some(WebsocketSubProtocols::MQTT));
+    }
+
+    #[tokio::test]
+    async fn test_message_too_big() {
+        let url = raw_server(|key| handshake::response(key, None, None)).await;
+        let config = ClientConfig { max_frame_size: 8, ..ClientConfig::default() };
+        let mut client = WebsocketClient::connect_with(&url, config).await.unwrap();
+        client.text("short").await.unwrap();
+        assert_eq!(client.recv().await.unwrap(), Some(Message::Text("short".into())));
+        client.text("longer than eight").await.unwrap();
+        assert!(matches!(client.recv().await, Err(WebsocketError::MessageTooBig)));
+        // 超限后已发送 1009 关闭帧
+        assert!(matches!(client.text("late").await, Err(WebsocketError::ConnectionClosed)));
+
+        let url = raw_server(|key| handshake::response(key, None, None)).await;
+        let config = ClientConfig { max_message_size: 8, ..ClientConfig::default() };
+        let mut client = WebsocketClient::connect_with(&url, config).await.unwrap();
+        client.binary(vec![0; 16]).await.unwrap();
+        assert!(matches!(client.recv().await, Err(WebsocketError::MessageTooBig)));
+    }
+
+    #[tokio::test]
+    async fn test_rejected_handshake() {
+        let url = raw_server(|_| handshake::HandshakeError::UnsupportedVersion.response().unwrap()).await;
+        match WebsocketClient::connect(&url).await {
+            Err(WebsocketError::Handshake(msg)) => assert!(msg.contains("426")),
+            _ => panic!("handshake should fail"),
+        }
+    }
+}
@@ -0,0 +1,385 @@
+//!
+//! 参照 Autobahn TestSuite 的用例分类，对 websocket 服务端做协议一致性测试
+//! 每个用例通过本地 socket 发送原始帧，收集服务端的回应直到关闭帧与连接断开，
+//! 回应中的关闭帧只比较状态码。分类编号与 Autobahn 保持一致：
+//! 1 帧长度，2 Ping/Pong，3 保留位，4 操作码，5 分片，6 UTF-8，7 关闭，9 大小限制，12 压缩
+//!
+
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time;
+use crate::websocket::deflate::{DeflateConfig, Deflater, Inflater};
+use crate::websocket::frame::{self, FrameLimits, MessageDecoder};
+use crate::websocket::handler::{CloseFrame, EchoHandler, Message};
+use crate::websocket::server::{WebsocketServer, WebsocketServerBuilder};
+
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xa;
+const FIN: u8 = 0x80;
+const RSV_1: u8 = 0x40;
+
+///
+/// 模拟客户端，直接收发原始帧
+///
+struct Peer {
+    stream: TcpStream,
+    buf: Vec<u8>,
+    decoder: MessageDecoder,
+}
+
+impl Peer {
+    async fn connect(builder: WebsocketServerBuilder, deflate: bool) -> Peer {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = builder.build();
+        tokio::spawn(async move { server.serve(listener).await });
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let extensions = if deflate { "Sec-WebSocket-Extensions: permessage-deflate\r\n" } else { "" };
+        let request = format!(
+            "GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n{}\r\n",
+            extensions
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut head = vec![];
+        let mut byte = [0; 1];
+        while !head.ends_with(b"\r\n\r\n") {
+            assert_eq!(stream.read(&mut byte).await.unwrap(), 1);
+            head.push(byte[0]);
+        }
+        assert!(head.starts_with(b"HTTP/1.1 101 "));
+        let inflater = if deflate { Some(Inflater::new(false)) } else { None };
+        Peer { stream, buf: vec![], decoder: MessageDecoder::new(inflater, usize::MAX, false) }
+    }
+
+    ///
+    /// 按 chunk 大小分多次写出，服务端可能已经断开，忽略写错误
+    ///
+    async fn send(&mut self, frames: &[Vec<u8>], chunk: usize) {
+        let data = frames.concat();
+        for part in data.chunks(chunk.max(1)) {
+            if self.stream.write_all(part).await.is_err() {
+                return;
+            }
+            if chunk < data.len() {
+                self.stream.flush().await.unwrap();
+            }
+        }
+    }
+
+    ///
+    /// 收集服务端的回应直到关闭帧，之后连接必须被断开
+    ///
+    async fn replies(&mut self, id: &str) -> Vec<Message> {
+        let mut replies = vec![];
+        loop {
+            let frame = time::timeout(
+                Duration::from_secs(5),
+                frame::read_frame(&mut self.stream, &mut self.buf, &FrameLimits::default()),
+            ).await;
+            let frame = match frame {
+                Ok(Ok(Some(frame))) => frame,
+                Ok(_) => panic!("case {}: connection dropped before close, got {:?}", id, replies),
+                Err(_) => panic!("case {}: timed out waiting for close, got {:?}", id, replies),
+            };
+            match self.decoder.decode(frame) {
+                Ok(Some(Message::Close(close))) => {
+                    replies.push(Message::Close(close.map(|c| CloseFrame { code: c.code, reason: String::new() })));
+                    break;
+                }
+                Ok(Some(msg)) => replies.push(msg),
+                Ok(None) => {}
+                Err(e) => panic!("case {}: invalid frame from server: {}", id, e),
+            }
+        }
+        let mut rest = [0; 64];
+        let eof = time::timeout(Duration::from_secs(5), self.stream.read(&mut rest)).await;
+        assert!(matches!(eof, Ok(Ok(0)) | Ok(Err(_))), "case {}: connection not closed", id);
+        replies
+    }
+}
+
+///
+/// 客户端帧，使用固定掩码
+///
+fn frame(first: u8, payload: &[u8]) -> Vec<u8> {
+    frame::encode_frame(first, payload, Some([0x37, 0xfa, 0x21, 0x3d]))
+}
+
+fn text(value: &str) -> Vec<u8> {
+    frame(FIN | TEXT, value.as_bytes())
+}
+
+fn close_code(code: u16, reason: &[u8]) -> Vec<u8> {
+    let mut payload = code.to_be_bytes().to_vec();
+    payload.extend_from_slice(reason);
+    frame(FIN | CLOSE, &payload)
+}
+
+fn normal_close() -> Vec<u8> {
+    close_code(1000, b"")
+}
+
+fn closed(code: u16) -> Message {
+    Message::Close(Some(CloseFrame { code, reason: String::new() }))
+}
+
+fn echo_server() -> WebsocketServerBuilder {
+    WebsocketServer::builder().sync_handler(EchoHandler)
+}
+
+async fn run_chopped(id: &str, builder: WebsocketServerBuilder, frames: &[Vec<u8>], chunk: usize, expected: &[Message]) {
+    let mut peer = Peer::connect(builder, false).await;
+    peer.send(frames, chunk).await;
+    assert_eq!(peer.replies(id).await, expected, "case {}", id);
+}
+
+async fn run(id: &str, frames: &[Vec<u8>], expected: &[Message]) {
+    run_chopped(id, echo_server(), frames, usize::MAX, expected).await;
+}
+
+#[tokio::test]
+async fn test_framing() {
+    for (i, len) in [0, 125, 126, 127, 128, 65535, 65536].iter().enumerate() {
+        let value = "*".repeat(*len);
+        let id = format!("1.1.{}", i + 1);
+        run(&id, &[text(&value), normal_close()], &[Message::Text(value.clone()), closed(1000)]).await;
+
+        let data = vec![0xfe; *len];
+        let id = format!("1.2.{}", i + 1);
+        run(&id, &[frame(FIN | BINARY, &data), normal_close()], &[Message::Binary(data), closed(1000)]).await;
+    }
+    // 帧被拆成很小的块发送
+    let value = "*".repeat(65536);
+    let frames = [text(&value), normal_close()];
+    run_chopped("1.1.8", echo_server(), &frames, 997, &[Message::Text(value), closed(1000)]).await;
+}
+
+#[tokio::test]
+async fn test_pings() {
+    let pong = |data: &[u8]| Message::Pong(data.to_vec());
+    let binary: Vec<u8> = vec![0x00, 0xff, 0xfe, 0xfd, 0xfc, 0xfb, 0x00, 0xff];
+    run("2.1", &[frame(FIN | PING, b""), normal_close()], &[pong(b""), closed(1000)]).await;
+    run("2.2", &[frame(FIN | PING, b"Hello, world!"), normal_close()], &[pong(b"Hello, world!"), closed(1000)]).await;
+    run("2.3", &[frame(FIN | PING, &binary), normal_close()], &[pong(&binary), closed(1000)]).await;
+    run("2.4", &[frame(FIN | PING, &[0xfe; 125]), normal_close()], &[pong(&[0xfe; 125]), closed(1000)]).await;
+    run("2.5", &[frame(FIN | PING, &[0xfe; 126]), normal_close()], &[closed(1002)]).await;
+    let frames = [frame(FIN | PING, &[0xfe; 125]), normal_close()];
+    run_chopped("2.6", echo_server(), &frames, 1, &[pong(&[0xfe; 125]), closed(1000)]).await;
+    // 未请求的 Pong 被忽略
+    run("2.7", &[frame(FIN | PONG, b""), normal_close()], &[closed(1000)]).await;
+    run("2.8", &[frame(FIN | PONG, b"unsolicited"), normal_close()], &[closed(1000)]).await;
+    let frames = [frame(FIN | PONG, b"unsolicited"), frame(FIN | PING, b"solicited"), normal_close()];
+    run("2.9", &frames, &[pong(b"solicited"), closed(1000)]).await;
+
+    let mut frames: Vec<Vec<u8>> = (0..10).map(|i| frame(FIN | PING, format!("ping {}", i).as_bytes())).collect();
+    frames.push(normal_close());
+    let mut expected: Vec<Message> = (0..10).map(|i| pong(format!("ping {}", i).as_bytes())).collect();
+    expected.push(closed(1000));
+    run("2.10", &frames, &expected).await;
+}
+
+#[tokio::test]
+async fn test_reserved_bits() {
+    // RSV 取值 1 到 7，未协商扩展时都不允许设置
+    let opcodes = [TEXT, TEXT, TEXT, BINARY, PING, PONG, CLOSE];
+    for (rsv, opcode) in (1_u8..=7).zip(opcodes.iter()) {
+        let id = format!("3.{}", rsv);
+        let frames = [text("Hello, world!"), frame(FIN | (rsv << 4) | opcode, b""), frame(FIN | PING, b"")];
+        run(&id, &frames, &[Message::Text("Hello, world!".into()), closed(1002)]).await;
+    }
+}
+
+#[tokio::test]
+async fn test_opcodes() {
+    let reserved = [0x3, 0x4, 0x5, 0x6, 0x7, 0xb, 0xc, 0xd, 0xe, 0xf];
+    for (i, opcode) in reserved.iter().enumerate() {
+        let id = format!("4.{}", i + 1);
+        let frames = [text("Hello, world!"), frame(FIN | opcode, b"reserved"), frame(FIN | PING, b"")];
+        run(&id, &frames, &[Message::Text("Hello, world!".into()), closed(1002)]).await;
+    }
+}
+
+#[tokio::test]
+async fn test_fragmentation() {
+    let hello = || Message::Text("fragment1fragment2".into());
+    run("5.1", &[frame(PING, b"fragment1"), frame(FIN, b"fragment2")], &[closed(1002)]).await;
+    run("5.2", &[frame(PONG, b"fragment1"), frame(FIN, b"fragment2")], &[closed(1002)]).await;
+
+    let frames = [frame(TEXT, b"fragment1"), frame(FIN, b"fragment2"), normal_close()];
+    run("5.3", &frames, &[hello(), closed(1000)]).await;
+    run_chopped("5.5", echo_server(), &frames, 1, &[hello(), closed(1000)]).await;
+
+    let frames = [frame(BINARY, b"fragment1"), frame(FIN, b"fragment2"), normal_close()];
+    run("5.4", &frames, &[Message::Binary(b"fragment1fragment2".to_vec()), closed(1000)]).await;
+
+    // 控制帧可以插在分片之间
+    let frames = [frame(TEXT, b"fragment1"), frame(FIN | PING, b"ping"), frame(FIN, b"fragment2"), normal_close()];
+    run("5.6", &frames, &[Message::Pong(b"ping".to_vec()), hello(), closed(1000)]).await;
+    run_chopped("5.7", echo_server(), &frames, 1, &[Message::Pong(b"ping".to_vec()), hello(), closed(1000)]).await;
+
+    // 没有起始帧的延续帧
+    run("5.9", &[frame(FIN, b"non-continuation"), text("Hello, world!")], &[closed(1002)]).await;
+    run("5.10", &[frame(0, b"non-continuation"), text("Hello, world!")], &[closed(1002)]).await;
+    let frames = [frame(TEXT, b"fragment1"), frame(FIN, b"fragment2"), frame(0, b"fragment3"), frame(FIN | TEXT, b"fragment4")];
+    run("5.15", &frames, &[hello(), closed(1002)]).await;
+    // 分片未结束时开始新的数据消息
+    run("5.18", &[frame(TEXT, b"fragment1"), frame(FIN | TEXT, b"fragment2")], &[closed(1002)]).await;
+
+    let mut frames = vec![];
+    let mut expected = vec![];
+    for i in 1..=5 {
+        frames.push(frame(if i == 1 { TEXT } else { 0 }, format!("fragment{}", i).as_bytes()));
+        frames.push(frame(FIN | PING, format!("pong{}", i).as_bytes()));
+        expected.push(Message::Pong(format!("pong{}", i).into_bytes()));
+    }
+    frames.push(frame(FIN, b""));
+    frames.push(normal_close());
+    expected.push(Message::Text("fragment1fragment2fragment3fragment4fragment5".into()));
+    expected.push(closed(1000));
+    run("5.19", &frames, &expected).await;
+
+    // 空分片
+    let frames = [frame(TEXT, b""), frame(0, b"middle"), frame(FIN, b""), normal_close()];
+    run("6.1.3", &frames, &[Message::Text("middle".into()), closed(1000)]).await;
+}
+
+#[tokio::test]
+async fn test_utf8() {
+    let valid = [
+        "Hello-µ@ßöäüàá-UTF-8!!",
+        "κόσμε",
+        "\u{0}",
+        "\u{80}",
+        "\u{800}",
+        "\u{10000}",
+        "\u{7f}",
+        "\u{7ff}",
+        "\u{ffff}",
+        "\u{10ffff}",
+        "\u{d7ff}\u{e000}\u{fffd}",
+    ];
+    for (i, value) in valid.iter().enumerate() {
+        let id = format!("6.2.{}", i + 1);
+        run(&id, &[text(value), normal_close()], &[Message::Text(value.to_string()), closed(1000)]).await;
+
+        // 每个字节一个分片，字符被拆开
+        let bytes = value.as_bytes();
+        let mut frames: Vec<Vec<u8>> = bytes
+            .iter()
+            .enumerate()
+            .map(|(j, b)| {
+                let opcode = if j == 0 { TEXT } else { 0 };
+                let fin = if j + 1 == bytes.len() { FIN } else { 0 };
+                frame(fin | opcode, &[*b])
+            })
+            .collect();
+        frames.push(normal_close());
+        let id = format!("6.3.{}", i + 1);
+        run(&id, &frames, &[Message::Text(value.to_string()), closed(1000)]).await;
+    }
+
+    let invalid: [&[u8]; 10] = [
+        // UTF-16 代理项
+        &[0xce, 0xba, 0xe1, 0xbd, 0xb9, 0xcf, 0x83, 0xce, 0xbc, 0xce, 0xb5, 0xed, 0xa0, 0x80, 0x65, 0x64],
+        &[0xed, 0xbf, 0xbf],
+        // 超长编码
+        &[0xc0, 0xaf],
+        &[0xe0, 0x80, 0xaf],
+        // 超出 U+10FFFF
+        &[0xf4, 0x90, 0x80, 0x80],
+        &[0xf8, 0x88, 0x80, 0x80, 0x80],
+        // 非法字节与孤立的延续字节
+        &[0xfe],
+        &[0xff],
+        &[0x80, 0xbf],
+        // 结尾截断
+        &[0x48, 0x65, 0xe1, 0x80],
+    ];
+    for (i, bytes) in invalid.iter().enumerate() {
+        let id = format!("6.4.{}", i + 1);
+        run(&id, &[frame(FIN | TEXT, bytes), normal_close()], &[closed(1007)]).await;
+
+        let (head, tail) = bytes.split_at(bytes.len() / 2);
+        let id = format!("6.5.{}", i + 1);
+        run(&id, &[frame(TEXT, head), frame(FIN, tail), normal_close()], &[closed(1007)]).await;
+    }
+}
+
+#[tokio::test]
+async fn test_close() {
+    let hello = || Message::Text("Hello, world!".into());
+    run("7.1.1", &[text("Hello, world!"), normal_close()], &[hello(), closed(1000)]).await;
+    // 关闭之后的帧被忽略
+    run("7.1.2", &[normal_close(), normal_close()], &[closed(1000)]).await;
+    run("7.1.3", &[normal_close(), frame(FIN | PING, b"")], &[closed(1000)]).await;
+    run("7.1.4", &[normal_close(), text("Hello, world!")], &[closed(1000)]).await;
+    run("7.1.5", &[frame(TEXT, b"fragment1"), normal_close(), frame(FIN, b"fragment2")], &[closed(1000)]).await;
+
+    run("7.3.1", &[frame(FIN | CLOSE, b"")], &[Message::Close(None)]).await;
+    run("7.3.2", &[frame(FIN | CLOSE, &[0x03])], &[closed(1002)]).await;
+    run("7.3.3", &[normal_close()], &[closed(1000)]).await;
+    run("7.3.4", &[close_code(1000, b"Hello World!")], &[closed(1000)]).await;
+    run("7.3.5", &[close_code(1000, &[b'*'; 123])], &[closed(1000)]).await;
+    run("7.3.6", &[close_code(1000, &[b'*'; 124])], &[closed(1002)]).await;
+    run("7.5.1", &[close_code(1000, &[0xce, 0xba, 0xe1, 0xbd, 0xb9, 0xed, 0xa0, 0x80])], &[closed(1007)]).await;
+
+    let valid = [1000, 1001, 1002, 1003, 1007, 1008, 1009, 1010, 1011, 3000, 3999, 4000, 4999];
+    for (i, code) in valid.iter().enumerate() {
+        let id = format!("7.7.{}", i + 1);
+        run(&id, &[close_code(*code, b"")], &[closed(*code)]).await;
+    }
+    let invalid = [0, 999, 1004, 1005, 1006, 1015, 1016, 1100, 2000, 2999, 5000, 65535];
+    for (i, code) in invalid.iter().enumerate() {
+        let id = format!("7.9.{}", i + 1);
+        run(&id, &[close_code(*code, b"")], &[closed(1002)]).await;
+    }
+}
+
+#[tokio::test]
+async fn test_unmasked_frame() {
+    let frames = [frame::encode_frame(FIN | TEXT, b"unmasked", None)];
+    run("x.1", &frames, &[closed(1002)]).await;
+}
+
+#[tokio::test]
+async fn test_message_limits() {
+    let limited = || echo_server().max_message_size(1024);
+    let frames = [frame(TEXT, &[b'*'; 1000]), frame(FIN, &[b'*'; 24]), normal_close()];
+    run_chopped("9.1", limited(), &frames, usize::MAX, &[Message::Text("*".repeat(1024)), closed(1000)]).await;
+    // 分片累计超过限制
+    let frames = [frame(TEXT, &[b'*'; 1000]), frame(0, &[b'*'; 24]), frame(FIN, b"*"), normal_close()];
+    run_chopped("9.2", limited(), &frames, usize::MAX, &[closed(1009)]).await;
+}
+
+#[tokio::test]
+async fn test_compression() {
+    let deflating = || echo_server().permessage_deflate(DeflateConfig::default());
+    let mut deflater = Deflater::new(false);
+    let value = "Hello, compressed world! ".repeat(40);
+    let compressed = deflater.compress(value.as_bytes()).unwrap();
+
+    let mut peer = Peer::connect(deflating(), true).await;
+    peer.send(&[frame(FIN | RSV_1 | TEXT, &compressed), normal_close()], usize::MAX).await;
+    assert_eq!(peer.replies("12.1").await, vec![Message::Text(value.clone()), closed(1000)]);
+
+    // 压缩后的负载再分片，只有首帧设置 RSV1
+    let (head, tail) = compressed.split_at(compressed.len() / 2);
+    let mut peer = Peer::connect(deflating(), true).await;
+    peer.send(&[frame(RSV_1 | TEXT, head), frame(FIN, tail), normal_close()], usize::MAX).await;
+    assert_eq!(peer.replies("13.1").await, vec![Message::Text(value.clone()), closed(1000)]);
+
+    let mut peer = Peer::connect(deflating(), true).await;
+    peer.send(&[frame(RSV_1 | TEXT, head), frame(FIN | RSV_1, tail)], usize::MAX).await;
+    assert_eq!(peer.replies("13.2").await, vec![closed(1002)]);
+
+    // 压缩的控制帧
+    let mut peer = Peer::connect(deflating(), true).await;
+    peer.send(&[frame(FIN | RSV_1 | PING, b"")], usize::MAX).await;
+    assert_eq!(peer.replies("13.3").await, vec![closed(1002)]);
+}
@@ -0,0 +1,303 @@
+//!
+//! permessage-deflate 压缩扩展（RFC 7692）
+//! 扩展通过握手时的 Sec-WebSocket-Extensions 协商，压缩后的消息在首帧设置 RSV1 位。
+//!
+
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use crate::websocket::error::WebsocketError;
+
+pub const EXTENSION_NAME: &str = "permessage-deflate";
+
+///
+/// 每条压缩消息末尾被省略的 4 个字节
+///
+const DEFLATE_TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+///
+/// 服务端启用压缩扩展时的配置
+///
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DeflateConfig {
+    /// 服务端每条消息都重置压缩上下文
+    pub server_no_context_takeover: bool,
+    /// 要求客户端每条消息都重置压缩上下文
+    pub client_no_context_takeover: bool,
+}
+
+impl DeflateConfig {
+    ///
+    /// 客户端在握手请求中发出的扩展提议
+    ///
+    pub fn offer(&self) -> String {
+        DeflateParams {
+            server_no_context_takeover: self.server_no_context_takeover,
+            client_no_context_takeover: self.client_no_context_takeover,
+        }.header_value()
+    }
+}
+
+///
+/// 协商后双方确认的扩展参数
+///
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DeflateParams {
+    pub server_no_context_takeover: bool,
+    pub client_no_context_takeover: bool,
+}
+
+impl DeflateParams {
+    ///
+    /// 响应或请求中的 Sec-WebSocket-Extensions 取值
+    ///
+    pub fn header_value(&self) -> String {
+        let mut value = String::from(EXTENSION_NAME);
+        if self.server_no_context_takeover {
+            value.push_str("; server_no_context_takeover");
+        }
+        if self.client_no_context_takeover {
+            value.push_str("; client_no_context_takeover");
+        }
+        value
+    }
+
+    ///
+    /// 服务端解析客户端的单个扩展提议，不是 permessage-deflate 或参数不可接受时返回 None
+    ///
+    pub fn parse(offer: &str) -> Option<DeflateParams> {
+        DeflateParams::parse_with(offer, false)
+    }
+
+    ///
+    /// 客户端解析服务端响应中接受的扩展参数
+    ///
+    pub fn parse_response(value: &str) -> Option<DeflateParams> {
+        DeflateParams::parse_with(value, true)
+    }
+
+    fn parse_with(offer: &str, is_response: bool) -> Option<DeflateParams> {
+        let mut parts = offer.split(';').map(|p| p.trim());
+        if parts.next() != Some(EXTENSION_NAME) {
+            return None;
+        }
+        let mut params = DeflateParams::default();
+        let mut seen: Vec<&str> = vec![];
+        for part in parts {
+            let (name, value) = match part.find('=') {
+                Some(i) => (part[..i].trim(), Some(part[i + 1..].trim().trim_matches('"'))),
+                None => (part, None),
+            };
+            if seen.contains(&name) {
+                return None;
+            }
+            seen.push(name);
+            let valid_bits = matches!(value.map(|bits| bits.parse::<u8>()), Some(Ok(8..=15)));
+            match (name, value, is_response) {
+                ("server_no_context_takeover", None, _) => params.server_no_context_takeover = true,
+                ("client_no_context_takeover", None, _) => params.client_no_context_takeover = true,
+                // 压缩实现固定使用 15 位窗口，服务端只能接受不限制或限制为 15 的请求
+                ("server_max_window_bits", Some("15"), false) => {}
+                // 解压可以处理任意窗口大小
+                ("server_max_window_bits", Some(_), true) if valid_bits => {}
+                ("client_max_window_bits", None, false) => {}
+                ("client_max_window_bits", Some(_), false) if valid_bits => {}
+                _ => return None,
+            }
+        }
+        Some(params)
+    }
+}
+
+///
+/// 服务端从客户端的扩展列表中选择第一个可接受的 permessage-deflate 提议
+///
+pub fn negotiate(offers: &[&str], config: &DeflateConfig) -> Option<DeflateParams> {
+    offers
+        .iter()
+        .filter_map(|offer| DeflateParams::parse(offer))
+        .next()
+        .map(|offer| DeflateParams {
+            server_no_context_takeover: offer.server_no_context_takeover || config.server_no_context_takeover,
+            client_no_context_takeover: offer.client_no_context_takeover || config.client_no_context_takeover,
+        })
+}
+
+///
+/// 发送方向的压缩器
+///
+pub struct Deflater {
+    compress: Compress,
+    no_context_takeover: bool,
+}
+
+impl Deflater {
+    pub fn new(no_context_takeover: bool) -> Deflater {
+        Deflater {
+            compress: Compress::new(Compression::default(), false),
+            no_context_takeover,
+        }
+    }
+
+    pub fn compress(&mut self, payload: &[u8]) -> Result<Vec<u8>, WebsocketError> {
+        let mut out = Vec::with_capacity(payload.len() / 2 + 64);
+        let start = self.compress.total_in();
+        loop {
+            let consumed = (self.compress.total_in() - start) as usize;
+            if out.len() == out.capacity() {
+                out.reserve(out.capacity().max(64));
+            }
+            self.compress
+                .compress_vec(&payload[consumed..], &mut out, FlushCompress::Sync)
+                .map_err(|e| WebsocketError::Protocol(format!("deflate error: {}", e)))?;
+            let consumed = (self.compress.total_in() - start) as usize;
+            // 输入全部消耗且输出缓冲区未被填满，说明同步刷新已完成
+            if consumed == payload.len() && out.len() < out.capacity() {
+                break;
+            }
+        }
+        if out.ends_with(&DEFLATE_TAIL) {
+            out.truncate(out.len() - DEFLATE_TAIL.len());
+        }
+        if self.no_context_takeover {
+            self.compress.reset();
+        }
+        Ok(out)
+    }
+}
+
+///
+/// 接收方向的解压器
+///
+pub struct Inflater {
+    decompress: Decompress,
+    no_context_takeover: bool,
+}
+
+impl Inflater {
+    pub fn new(no_context_takeover: bool) -> Inflater {
+        Inflater {
+            decompress: Decompress::new(false),
+            no_context_takeover,
+        }
+    }
+
+    pub fn decompress(&mut self, payload: &[u8]) -> Result<Vec<u8>, WebsocketError> {
+        self.decompress_limited(payload, usize::MAX)
+    }
+
+    ///
+    /// 解压结果超过 limit 时提前返回 MessageTooBig，防止压缩炸弹耗尽内存
+    ///
+    pub fn decompress_limited(&mut self, payload: &[u8], limit: usize) -> Result<Vec<u8>, WebsocketError> {
+        let mut input = payload.to_vec();
+        input.extend_from_slice(&DEFLATE_TAIL);
+        let mut out = Vec::with_capacity((payload.len() * 2 + 64).min(limit.saturating_add(1)));
+        let start = self.decompress.total_in();
+        loop {
+            let consumed = (self.decompress.total_in() - start) as usize;
+            if out.len() == out.capacity() {
+                out.reserve(out.capacity());
+            }
+            let status = self.decompress
+                .decompress_vec(&input[consumed..], &mut out, FlushDecompress::Sync)
+                .map_err(|e| WebsocketError::Protocol(format!("inflate error: {}", e)))?;
+            if out.len() > limit {
+                return Err(WebsocketError::MessageTooBig);
+            }
+            let consumed = (self.decompress.total_in() - start) as usize;
+            if status == Status::StreamEnd || (consumed == input.len() && out.len() < out.capacity()) {
+                break;
+            }
+        }
+        if self.no_context_takeover {
+            self.decompress.reset(false);
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_offer() {
+        assert_eq!(DeflateParams::parse("permessage-deflate"), Some(DeflateParams::default()));
+        assert_eq!(
+            DeflateParams::parse("permessage-deflate; client_max_window_bits; server_no_context_takeover"),
+            Some(DeflateParams { server_no_context_takeover: true, client_no_context_takeover: false })
+        );
+        assert!(DeflateParams::parse("permessage-deflate; client_max_window_bits=\"10\"").is_some());
+        assert!(DeflateParams::parse("permessage-deflate; server_max_window_bits=10").is_none());
+        assert!(DeflateParams::parse("permessage-deflate; unknown").is_none());
+        assert!(DeflateParams::parse("permessage-deflate; server_no_context_takeover; server_no_context_takeover").is_none());
+        assert!(DeflateParams::parse("x-webkit-deflate-frame").is_none());
+
+        assert!(DeflateParams::parse_response("permessage-deflate; server_max_window_bits=10").is_some());
+        assert!(DeflateParams::parse_response("permessage-deflate; client_max_window_bits=10").is_none());
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let offers = ["permessage-deflate; server_max_window_bits=10", "permessage-deflate; client_no_context_takeover"];
+        let params = negotiate(&offers, &DeflateConfig::default()).unwrap();
+        assert_eq!(params.header_value(), "permessage-deflate; client_no_context_takeover");
+
+        let config = DeflateConfig { server_no_context_takeover: true, client_no_context_takeover: false };
+        let params = negotiate(&["permessage-deflate"], &config).unwrap();
+        assert_eq!(params.header_value(), "permessage-deflate; server_no_context_takeover");
+
+        assert!(negotiate(&["x-webkit-deflate-frame"], &config).is_none());
+    }
+
+    #[test]
+    fn test_rfc_example() {
+        // RFC 7692 7.2.3.1 中 "Hello" 的压缩结果
+        let mut inflater = Inflater::new(false);
+        let data = inflater.decompress(&[0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00]).unwrap();
+        assert_eq!(data, b"Hello");
+    }
+
+    #[test]
+    fn test_context_takeover() {
+        let snapshot = "{\"cpu\":0.5,\"memory\":1024,\"disk\":\"ok\"}".repeat(200);
+
+        let (mut deflater, mut inflater) = (Deflater::new(false), Inflater::new(false));
+        let first = deflater.compress(snapshot.as_bytes()).unwrap();
+        let second = deflater.compress(snapshot.as_bytes()).unwrap();
+        assert!(first.len() < snapshot.len() / 10);
+        // 复用上下文后，重复内容的压缩结果更短
+        assert!(second.len() < first.len());
+        assert_eq!(inflater.decompress(&first).unwrap(), snapshot.as_bytes());
+        assert_eq!(inflater.decompress(&second).unwrap(), snapshot.as_bytes());
+
+        let (mut deflater, mut inflater) = (Deflater::new(true), Inflater::new(true));
+        let first = deflater.compress(snapshot.as_bytes()).unwrap();
+        let second = deflater.compress(snapshot.as_bytes()).unwrap();
+        assert_eq!(first, second);
+        assert_eq!(inflater.decompress(&first).unwrap(), snapshot.as_bytes());
+        assert_eq!(inflater.decompress(&second).unwrap(), snapshot.as_bytes());
+    }
+
+    #[test]
+    fn test_decompress_limit() {
+        let mut deflater = Deflater::new(true);
+        let compressed = deflater.compress(&[0; 1 << 20]).unwrap();
+        assert!(compressed.len() < 2048);
+
+        let mut inflater = Inflater::new(true);
+        assert!(matches!(inflater.decompress_limited(&compressed, 4096), Err(WebsocketError::MessageTooBig)));
+        let mut inflater = Inflater::new(true);
+        assert_eq!(inflater.decompress_limited(&compressed, 1 << 20).unwrap().len(), 1 << 20);
+    }
+
+    #[test]
+    fn test_empty_and_random_payload() {
+        let (mut deflater, mut inflater) = (Deflater::new(false), Inflater::new(false));
+        let empty = deflater.compress(&[]).unwrap();
+        assert_eq!(inflater.decompress(&empty).unwrap(), Vec::<u8>::new());
+
+        let noise: Vec<u8> = (0..100_000).map(|_| rand::random::<u8>()).collect();
+        let compressed = deflater.compress(&noise).unwrap();
+        assert_eq!(inflater.decompress(&compressed).unwrap(), noise);
+    }
+}
@@ -0,0 +1,58 @@
+use std::fmt;
+
+///
+/// websocket 连接过程中产生的错误
+///
+#[derive(Debug)]
+pub enum WebsocketError {
+    /// 底层 socket 读写错误
+    Io(std::io::Error),
+    /// 对端发送了不符合协议的数据
+    Protocol(String),
+    /// 文本消息或关闭原因不是合法的 UTF-8
+    InvalidPayload(String),
+    /// 客户端握手失败
+    Handshake(String),
+    /// 帧或消息超过了允许的大小
+    MessageTooBig,
+    /// 发送队列已满，消息被丢弃
+    QueueFull,
+    /// 连接已经关闭，无法继续发送消息
+    ConnectionClosed,
+}
+
+impl fmt::Display for WebsocketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WebsocketError::Io(e) => write!(f, "io error: {}", e),
+            WebsocketError::Protocol(msg) => write!(f, "protocol error: {}", msg),
+            WebsocketError::InvalidPayload(msg) => write!(f, "invalid payload: {}", msg),
+            WebsocketError::Handshake(msg) => write!(f, "handshake failed: {}", msg),
+            WebsocketError::MessageTooBig => write!(f, "message too big"),
+            WebsocketError::QueueFull => write!(f, "outbound queue full"),
+            WebsocketError::ConnectionClosed => write!(f, "connection closed"),
+        }
+    }
+}
+
+impl WebsocketError {
+    ///
+    /// 因对端数据出错而断开时应当发送的关闭状态码，其他错误返回 None
+    ///
+    pub fn close_code(&self) -> Option<u16> {
+        match self {
+            WebsocketError::Protocol(_) => Some(1002),
+            WebsocketError::InvalidPayload(_) => Some(1007),
+            WebsocketError::MessageTooBig => Some(1009),
+            _ => None,
+        }
+    }
+}
+
+impl std::error::Error for WebsocketError {}
+
+impl From<std::io::Error> for WebsocketError {
+    fn from(e: std::io::Error) -> Self {
+        WebsocketError::Io(e)
+    }
+}
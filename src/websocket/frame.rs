@@ -0,0 +1,532 @@
+//!
+//! websocket 帧的编解码，服务端与客户端共用
+//!
+
+use rand::Rng;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use std::convert::{TryFrom, TryInto};
+use crate::websocket::deflate::{Deflater, Inflater};
+use crate::websocket::error::WebsocketError;
+use crate::websocket::handler::{CloseFrame, Message};
+
+pub(crate) struct FirstFrame {
+    pub(crate) fin: u8,
+    pub(crate) rsv_1: u8,
+    pub(crate) rsv_2: u8,
+    pub(crate) rsv_3: u8,
+    pub(crate) opcode: u8,
+}
+
+///
+/// 一个完整的帧，负载已经去掉掩码
+///
+pub(crate) struct Frame {
+    pub(crate) first: FirstFrame,
+    pub(crate) masked: bool,
+    pub(crate) payload: Vec<u8>,
+}
+
+///
+/// 读取与解码时的大小限制，超出时返回 MessageTooBig
+///
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct FrameLimits {
+    pub(crate) max_frame_size: usize,
+    pub(crate) max_message_size: usize,
+    pub(crate) read_buffer_size: usize,
+}
+
+impl Default for FrameLimits {
+    fn default() -> Self {
+        FrameLimits {
+            max_frame_size: usize::MAX,
+            max_message_size: usize::MAX,
+            read_buffer_size: 4096,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Opcode {
+    Extended = 0x0,
+    Text = 0x1,
+    Binary = 0x2,
+    Close = 0x8,
+    Ping = 0x9,
+    Pong = 0xA,
+}
+
+impl Opcode {
+    pub(crate) fn from_u8(opcode: u8) -> Option<Opcode> {
+        match opcode {
+            0x0 => Some(Opcode::Extended),
+            0x1 => Some(Opcode::Text),
+            0x2 => Some(Opcode::Binary),
+            0x8 => Some(Opcode::Close),
+            0x9 => Some(Opcode::Ping),
+            0xA => Some(Opcode::Pong),
+            _ => None,
+        }
+    }
+}
+
+pub(crate) fn get_first_frame(frame: u8) -> FirstFrame {
+    FirstFrame {
+        fin: (frame >> 7) & 1,
+        rsv_1: (frame >> 6) & 1,
+        rsv_2: (frame >> 5) & 1,
+        rsv_3: (frame >> 4) & 1,
+        opcode: get_low_4(frame),
+    }
+}
+
+fn get_low_4(frame: u8) -> u8 {
+    frame & 0x0f
+}
+
+///
+/// 从缓冲区解析一个完整的帧，数据不足时返回 None
+/// 成功时返回帧以及消耗的字节数
+///
+pub(crate) fn parse_frame(data: &[u8], max_frame_size: usize) -> Result<Option<(Frame, usize)>, WebsocketError> {
+    if data.len() < 2 {
+        return Ok(None);
+    }
+    let first = get_first_frame(data[0]);
+    let is_mask = (data[1] >> 7) & 1;
+    let (len, mut offset) = match data[1] & 127 {
+        126 => match data.get(2..4) {
+            Some(bytes) => (u16::from_be_bytes([bytes[0], bytes[1]]) as u64, 4),
+            None => return Ok(None),
+        },
+        127 => match data.get(2..10) {
+            Some(bytes) => (u64::from_be_bytes(bytes.try_into().expect("error parsing bytes to u64")), 10),
+            None => return Ok(None),
+        },
+        payload => (payload as u64, 2),
+    };
+    // 在负载到齐之前就拒绝超长的帧，避免缓存过多数据
+    if len > max_frame_size as u64 {
+        return Err(WebsocketError::MessageTooBig);
+    }
+    let mask = if is_mask == 1 {
+        match data.get(offset..offset + 4) {
+            Some(bytes) => {
+                offset += 4;
+                Some([bytes[0], bytes[1], bytes[2], bytes[3]])
+            }
+            None => return Ok(None),
+        }
+    } else {
+        None
+    };
+    let end = usize::try_from(len)
+        .ok()
+        .and_then(|len| len.checked_add(offset))
+        .ok_or_else(|| WebsocketError::Protocol("frame too large".into()))?;
+    let payload = match data.get(offset..end) {
+        Some(payload) => payload,
+        None => return Ok(None),
+    };
+    let payload = match mask {
+        Some(mask) => payload.iter().enumerate().map(|(i, v)| v ^ mask[i % 4]).collect(),
+        None => payload.to_vec(),
+    };
+    Ok(Some((Frame { first, masked: mask.is_some(), payload }, end)))
+}
+
+///
+/// 读取下一个帧，帧可以跨越多次 read，连接关闭时返回 None
+///
+pub(crate) async fn read_frame<R>(reader: &mut R, buf: &mut Vec<u8>, limits: &FrameLimits) -> Result<Option<Frame>, WebsocketError>
+    where
+        R: AsyncRead + Unpin
+{
+    let mut chunk = vec![0; limits.read_buffer_size.max(1)];
+    loop {
+        if let Some((frame, used)) = parse_frame(buf, limits.max_frame_size)? {
+            buf.drain(..used);
+            return Ok(Some(frame));
+        }
+        let n = reader.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(None);
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+///
+/// 正在接收的分片消息
+///
+struct Partial {
+    opcode: Opcode,
+    compressed: bool,
+    payload: Vec<u8>,
+}
+
+///
+/// 把帧还原为消息，负责分片重组、解压以及 RFC 6455 要求的各项校验
+/// 违反协议时返回 Protocol，文本或关闭原因不是合法 UTF-8 时返回 InvalidPayload
+///
+pub(crate) struct MessageDecoder {
+    inflater: Option<Inflater>,
+    max_message_size: usize,
+    /// 服务端要求帧带掩码，客户端要求帧不带掩码
+    masked: bool,
+    partial: Option<Partial>,
+}
+
+impl MessageDecoder {
+    pub(crate) fn new(inflater: Option<Inflater>, max_message_size: usize, masked: bool) -> MessageDecoder {
+        MessageDecoder { inflater, max_message_size, masked, partial: None }
+    }
+
+    ///
+    /// 处理一个帧，分片消息未接收完整时返回 None
+    /// 控制帧可以插在分片之间，会被立即返回
+    ///
+    pub(crate) fn decode(&mut self, frame: Frame) -> Result<Option<Message>, WebsocketError> {
+        if frame.masked != self.masked {
+            let reason = if self.masked { "client frames must be masked" } else { "server frames must not be masked" };
+            return Err(WebsocketError::Protocol(reason.into()));
+        }
+        let first = &frame.first;
+        if first.rsv_2 == 1 || first.rsv_3 == 1 {
+            return Err(WebsocketError::Protocol("reserved bits set".into()));
+        }
+        let opcode = Opcode::from_u8(first.opcode)
+            .ok_or_else(|| WebsocketError::Protocol(format!("reserved opcode {}", first.opcode)))?;
+        let (fin, rsv_1) = (first.fin == 1, first.rsv_1 == 1);
+        match opcode {
+            Opcode::Close | Opcode::Ping | Opcode::Pong => {
+                if !fin {
+                    return Err(WebsocketError::Protocol("fragmented control frame".into()));
+                }
+                if frame.payload.len() > 125 {
+                    return Err(WebsocketError::Protocol("control frame payload too long".into()));
+                }
+                if rsv_1 {
+                    return Err(WebsocketError::Protocol("unexpected rsv1 bit".into()));
+                }
+                let msg = match opcode {
+                    Opcode::Ping => Message::Ping(frame.payload),
+                    Opcode::Pong => Message::Pong(frame.payload),
+                    _ => Message::Close(decode_close_payload(&frame.payload)?),
+                };
+                return Ok(Some(msg));
+            }
+            Opcode::Text | Opcode::Binary => {
+                if self.partial.is_some() {
+                    return Err(WebsocketError::Protocol("expected continuation frame".into()));
+                }
+                // 只有协商了压缩扩展的消息才能在第一个帧设置 RSV1
+                if rsv_1 && self.inflater.is_none() {
+                    return Err(WebsocketError::Protocol("unexpected rsv1 bit".into()));
+                }
+                self.partial = Some(Partial { opcode, compressed: rsv_1, payload: vec![] });
+            }
+            Opcode::Extended => {
+                if self.partial.is_none() {
+                    return Err(WebsocketError::Protocol("unexpected continuation frame".into()));
+                }
+                if rsv_1 {
+                    return Err(WebsocketError::Protocol("unexpected rsv1 bit".into()));
+                }
+            }
+        }
+        let partial = self.partial.as_mut().expect("message in progress");
+        if partial.payload.len() + frame.payload.len() > self.max_message_size {
+            self.partial = None;
+            return Err(WebsocketError::MessageTooBig);
+        }
+        if partial.payload.is_empty() {
+            partial.payload = frame.payload;
+        } else {
+            partial.payload.extend_from_slice(&frame.payload);
+        }
+        if !fin {
+            return Ok(None);
+        }
+        let Partial { opcode, compressed, payload } = self.partial.take().expect("message in progress");
+        let payload = match (compressed, self.inflater.as_mut()) {
+            (true, Some(inflater)) => inflater.decompress_limited(&payload, self.max_message_size)?,
+            _ => payload,
+        };
+        let msg = match opcode {
+            Opcode::Text => match String::from_utf8(payload) {
+                Ok(text) => Message::Text(text),
+                Err(_) => return Err(WebsocketError::InvalidPayload("invalid utf-8 text".into())),
+            },
+            _ => Message::Binary(payload),
+        };
+        Ok(Some(msg))
+    }
+}
+
+///
+/// 编码一条消息，启用压缩时数据帧的负载会被压缩并设置 RSV1
+/// 客户端发送的帧必须加掩码，mask 为 true 时随机生成掩码
+///
+pub(crate) fn encode_message(msg: &Message, deflater: Option<&mut Deflater>, mask: bool) -> Result<Vec<u8>, WebsocketError> {
+    let (opcode, payload) = match msg {
+        Message::Text(text) => (Opcode::Text, text.as_bytes().to_vec()),
+        Message::Binary(data) => (Opcode::Binary, data.clone()),
+        Message::Ping(data) => (Opcode::Ping, data.clone()),
+        Message::Pong(data) => (Opcode::Pong, data.clone()),
+        Message::Close(frame) => (Opcode::Close, encode_close_payload(frame)),
+    };
+    let (rsv_1, payload) = match (deflater, opcode) {
+        (Some(deflater), Opcode::Text) | (Some(deflater), Opcode::Binary) => (0x40, deflater.compress(&payload)?),
+        _ => (0, payload),
+    };
+    let mask = if mask { Some(rand::thread_rng().gen()) } else { None };
+    Ok(encode_frame(0x80 | rsv_1 | opcode as u8, &payload, mask))
+}
+
+///
+/// 编码单个帧，负载长度按大小使用 7 位、16 位或 64 位表示
+///
+pub(crate) fn encode_frame(first: u8, payload: &[u8], mask: Option<[u8; 4]>) -> Vec<u8> {
+    let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+    let mut data = Vec::with_capacity(payload.len() + 14);
+    data.push(first);
+    match payload.len() {
+        len if len <= 125 => data.push(mask_bit | len as u8),
+        len if len <= u16::MAX as usize => {
+            data.push(mask_bit | 126);
+            data.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            data.push(mask_bit | 127);
+            data.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    match mask {
+        Some(key) => {
+            data.extend_from_slice(&key);
+            data.extend(payload.iter().enumerate().map(|(i, v)| v ^ key[i % 4]));
+        }
+        None => data.extend_from_slice(payload),
+    }
+    data
+}
+
+pub(crate) fn encode_close_payload(frame: &Option<CloseFrame>) -> Vec<u8> {
+    match frame {
+        Some(frame) => {
+            let mut payload = frame.code.to_be_bytes().to_vec();
+            payload.extend_from_slice(frame.reason.as_bytes());
+            payload
+        }
+        None => vec![],
+    }
+}
+
+///
+/// 解析关闭帧负载，负载只有 1 个字节、状态码不允许出现在帧中或原因不是合法 UTF-8 时返回错误
+///
+pub(crate) fn decode_close_payload(payload: &[u8]) -> Result<Option<CloseFrame>, WebsocketError> {
+    match payload.len() {
+        0 => return Ok(None),
+        1 => return Err(WebsocketError::Protocol("close payload too short".into())),
+        _ => {}
+    }
+    let code = u16::from_be_bytes([payload[0], payload[1]]);
+    if !is_valid_close_code(code) {
+        return Err(WebsocketError::Protocol(format!("invalid close code {}", code)));
+    }
+    let reason = String::from_utf8(payload[2..].to_vec())
+        .map_err(|_| WebsocketError::InvalidPayload("invalid utf-8 close reason".into()))?;
+    Ok(Some(CloseFrame { code, reason }))
+}
+
+///
+/// 1004、1005、1006 与 1015 是保留值，不能出现在关闭帧中
+///
+pub(crate) fn is_valid_close_code(code: u16) -> bool {
+    matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_frame_partial() {
+        let data = encode_message(&Message::Binary(vec![7; 10]), None, true).unwrap();
+        for i in 0..data.len() {
+            assert!(parse_frame(&data[..i], usize::MAX).unwrap().is_none());
+        }
+        let (frame, used) = parse_frame(&data, usize::MAX).unwrap().unwrap();
+        assert_eq!(frame.first.opcode, Opcode::Binary as u8);
+        assert!(frame.masked);
+        assert_eq!(frame.payload, vec![7; 10]);
+        assert_eq!(used, data.len());
+    }
+
+    #[test]
+    fn test_payload_length_round_trip() {
+        let sizes = [0, 1, 125, 126, 127, 65535, 65536, 65537, 1 << 20];
+        for size in sizes.iter().cloned() {
+            let payload: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+            let header_len = match size {
+                0..=125 => 2,
+                126..=65535 => 4,
+                _ => 10,
+            };
+            for mask in [None, Some([0x37, 0xfa, 0x21, 0x3d])] {
+                let data = encode_frame(0x82, &payload, mask);
+                let mask_len = if mask.is_some() { 4 } else { 0 };
+                assert_eq!(data.len(), header_len + mask_len + size, "size {}", size);
+
+                assert!(parse_frame(&data[..data.len() - 1], usize::MAX).unwrap().is_none());
+                let (frame, used) = parse_frame(&data, usize::MAX).unwrap().unwrap();
+                assert_eq!(used, data.len());
+                assert_eq!(frame.masked, mask.is_some());
+                assert_eq!(frame.payload, payload, "size {}", size);
+            }
+        }
+    }
+
+    #[test]
+    fn test_frame_limits() {
+        let data = encode_frame(0x82, &[0; 200], None);
+        assert!(parse_frame(&data, 200).unwrap().is_some());
+        // 只有帧头时就能判断超长
+        assert!(matches!(parse_frame(&data[..4], 199), Err(WebsocketError::MessageTooBig)));
+
+        let (frame, _) = parse_frame(&data, 200).unwrap().unwrap();
+        let mut decoder = MessageDecoder::new(None, 100, false);
+        assert!(matches!(decoder.decode(frame), Err(WebsocketError::MessageTooBig)));
+    }
+
+    #[test]
+    fn test_extended_length_encoding() {
+        assert_eq!(&encode_frame(0x81, &[0; 126], None)[..4], &[0x81, 126, 0x00, 0x7e]);
+        assert_eq!(&encode_frame(0x81, &[0; 65535], None)[..4], &[0x81, 126, 0xff, 0xff]);
+        assert_eq!(
+            &encode_frame(0x81, &vec![0; 65536], None)[..10],
+            &[0x81, 127, 0, 0, 0, 0, 0, 0x01, 0x00, 0x00]
+        );
+        assert_eq!(&encode_frame(0x81, &[0; 200], Some([1, 2, 3, 4]))[..8], &[0x81, 0xfe, 0x00, 0xc8, 1, 2, 3, 4]);
+    }
+
+    #[tokio::test]
+    async fn test_read_frame_large_payload() {
+        let payload = vec![0x5a; 70000];
+        let data = encode_message(&Message::Binary(payload.clone()), None, true).unwrap();
+        let mut reader = data.as_slice();
+        let mut buf = vec![];
+        let frame = read_frame(&mut reader, &mut buf, &FrameLimits::default()).await.unwrap().unwrap();
+        assert_eq!(frame.payload, payload);
+        assert!(read_frame(&mut reader, &mut buf, &FrameLimits::default()).await.unwrap().is_none());
+    }
+
+    #[test]
+    fn test_message_round_trip() {
+        let messages = [
+            Message::Text("hello".into()),
+            Message::Binary(vec![1, 2, 3]),
+            Message::Ping(vec![]),
+            Message::Pong(vec![9]),
+            Message::Close(Some(CloseFrame { code: 1000, reason: "bye".into() })),
+            Message::Close(None),
+        ];
+        for mask in [false, true] {
+            let mut decoder = MessageDecoder::new(None, usize::MAX, mask);
+            for msg in messages.iter() {
+                let data = encode_message(msg, None, mask).unwrap();
+                let (frame, _) = parse_frame(&data, usize::MAX).unwrap().unwrap();
+                assert_eq!(frame.masked, mask);
+                assert_eq!(decoder.decode(frame).unwrap().as_ref(), Some(msg));
+            }
+        }
+    }
+
+    #[test]
+    fn test_compressed_round_trip() {
+        let mut deflater = Deflater::new(false);
+        let mut decoder = MessageDecoder::new(Some(Inflater::new(false)), usize::MAX, true);
+        let msg = Message::Text("compressed".into());
+        let data = encode_message(&msg, Some(&mut deflater), true).unwrap();
+        let (frame, _) = parse_frame(&data, usize::MAX).unwrap().unwrap();
+        assert_eq!(frame.first.rsv_1, 1);
+        assert_eq!(decoder.decode(frame).unwrap(), Some(msg));
+
+        // 控制帧不压缩
+        let data = encode_message(&Message::Ping(vec![1]), Some(&mut deflater), false).unwrap();
+        assert_eq!(data, vec![0x89, 1, 1]);
+
+        let data = encode_message(&Message::Text("x".into()), Some(&mut deflater), false).unwrap();
+        let (frame, _) = parse_frame(&data, usize::MAX).unwrap().unwrap();
+        assert!(MessageDecoder::new(None, usize::MAX, false).decode(frame).is_err());
+    }
+
+    fn decode_all(decoder: &mut MessageDecoder, frames: &[Vec<u8>]) -> Result<Vec<Message>, WebsocketError> {
+        let mut messages = vec![];
+        for data in frames {
+            let (frame, _) = parse_frame(data, usize::MAX)?.expect("complete frame");
+            messages.extend(decoder.decode(frame)?);
+        }
+        Ok(messages)
+    }
+
+    #[test]
+    fn test_fragmented_message() {
+        let mut decoder = MessageDecoder::new(None, 8, false);
+        let frames = [
+            encode_frame(0x01, b"he", None),
+            encode_frame(0x89, b"p", None),
+            encode_frame(0x00, b"ll", None),
+            encode_frame(0x80, b"o", None),
+        ];
+        let messages = decode_all(&mut decoder, &frames).unwrap();
+        assert_eq!(messages, vec![Message::Ping(b"p".to_vec()), Message::Text("hello".into())]);
+
+        // 分片累计超过消息大小限制
+        let frames = [encode_frame(0x02, &[0; 5], None), encode_frame(0x80, &[0; 5], None)];
+        assert!(matches!(decode_all(&mut decoder, &frames), Err(WebsocketError::MessageTooBig)));
+
+        // UTF-8 字符被拆在两个分片中
+        let text = "κόσμε".as_bytes();
+        let frames = [encode_frame(0x01, &text[..3], None), encode_frame(0x80, &text[3..], None)];
+        let mut decoder = MessageDecoder::new(None, usize::MAX, false);
+        assert_eq!(decode_all(&mut decoder, &frames).unwrap(), vec![Message::Text("κόσμε".into())]);
+    }
+
+    #[test]
+    fn test_protocol_violations() {
+        let cases: Vec<(Vec<Vec<u8>>, u16)> = vec![
+            (vec![encode_frame(0x80, b"x", None)], 1002),
+            (vec![encode_frame(0x01, b"x", None), encode_frame(0x81, b"y", None)], 1002),
+            (vec![encode_frame(0x09, b"", None)], 1002),
+            (vec![encode_frame(0x89, &[0; 126], None)], 1002),
+            (vec![encode_frame(0xa1, b"x", None)], 1002),
+            (vec![encode_frame(0xc1, b"x", None)], 1002),
+            (vec![encode_frame(0x83, b"", None)], 1002),
+            (vec![encode_frame(0x8b, b"", None)], 1002),
+            (vec![encode_frame(0x81, b"x", Some([1, 2, 3, 4]))], 1002),
+            (vec![encode_frame(0x81, &[0xc0, 0x80], None)], 1007),
+            (vec![encode_frame(0x88, &[0x03], None)], 1002),
+            (vec![encode_frame(0x88, &[0x03, 0xed], None)], 1002),
+            (vec![encode_frame(0x88, &[0x03, 0xe8, 0xff], None)], 1007),
+        ];
+        for (frames, code) in cases {
+            let mut decoder = MessageDecoder::new(None, usize::MAX, false);
+            let err = decode_all(&mut decoder, &frames).unwrap_err();
+            assert_eq!(err.close_code(), Some(code), "{:?}", err);
+        }
+    }
+
+    #[test]
+    fn test_close_codes() {
+        for code in [1000, 1003, 1007, 1011, 1014, 3000, 4999] {
+            assert!(is_valid_close_code(code), "{}", code);
+        }
+        for code in [0, 999, 1004, 1005, 1006, 1015, 1016, 2999, 5000, 65535] {
+            assert!(!is_valid_close_code(code), "{}", code);
+        }
+    }
+}
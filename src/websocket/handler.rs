@@ -0,0 +1,321 @@
+use async_trait::async_trait;
+use std::any::Any;
+use std::sync::Arc;
+use crate::websocket::error::WebsocketError;
+use crate::websocket::handshake::{HandshakeRequest, Rejection};
+use crate::websocket::queue::QueueSender;
+use crate::websocket::registry::{ConnectionId, ConnectionRegistry};
+use crate::websocket::router::PathParams;
+use crate::websocket::server::WebsocketSubProtocols;
+
+///
+/// 关闭帧携带的状态码与原因
+///
+#[derive(Clone, Debug, PartialEq)]
+pub struct CloseFrame {
+    pub code: u16,
+    pub reason: String,
+}
+
+///
+/// 应用层可见的 websocket 消息
+///
+#[derive(Clone, Debug, PartialEq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    Close(Option<CloseFrame>),
+}
+
+///
+/// 升级前钩子附加到连接上的任意数据，例如认证后的用户信息
+///
+pub type UserData = Arc<dyn Any + Send + Sync>;
+
+///
+/// 每个连接独立的发送句柄
+/// 可以被 clone 并在任意任务中随时向客户端推送消息
+///
+#[derive(Clone)]
+pub struct WebsocketSender {
+    id: ConnectionId,
+    sender: QueueSender,
+    registry: ConnectionRegistry,
+    protocol: Option<WebsocketSubProtocols>,
+    user_data: Option<UserData>,
+    params: Arc<PathParams>,
+}
+
+impl WebsocketSender {
+    pub(crate) fn new(
+        id: ConnectionId,
+        sender: QueueSender,
+        registry: ConnectionRegistry,
+    ) -> WebsocketSender {
+        WebsocketSender { id, sender, registry, protocol: None, user_data: None, params: Arc::default() }
+    }
+
+    pub(crate) fn set_protocol(&mut self, protocol: Option<WebsocketSubProtocols>) {
+        self.protocol = protocol;
+    }
+
+    pub(crate) fn set_user_data(&mut self, user_data: Option<UserData>) {
+        self.user_data = user_data;
+    }
+
+    pub(crate) fn set_params(&mut self, params: PathParams) {
+        self.params = Arc::new(params);
+    }
+
+    pub fn id(&self) -> ConnectionId {
+        self.id
+    }
+
+    ///
+    /// 握手时协商出的子协议
+    ///
+    pub fn protocol(&self) -> Option<WebsocketSubProtocols> {
+        self.protocol
+    }
+
+    ///
+    /// 升级前钩子附加的数据，类型不匹配或没有数据时返回 None
+    ///
+    pub fn user_data<T: Any + Send + Sync>(&self) -> Option<&T> {
+        self.user_data.as_ref()?.downcast_ref::<T>()
+    }
+
+    ///
+    /// 路由匹配时提取的路径参数，例如 `/rooms/:room` 中的 room
+    ///
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.get(name)
+    }
+
+    pub fn params(&self) -> &PathParams {
+        &self.params
+    }
+
+    ///
+    /// 连接所在服务的注册表，可用于广播或向其他连接发送消息
+    ///
+    pub fn registry(&self) -> &ConnectionRegistry {
+        &self.registry
+    }
+
+    pub fn join(&self, room: &str) -> bool {
+        self.registry.join(self.id, room)
+    }
+
+    pub fn leave(&self, room: &str) -> bool {
+        self.registry.leave(self.id, room)
+    }
+
+    pub fn send(&self, msg: Message) -> Result<(), WebsocketError> {
+        self.sender.send(msg)
+    }
+
+    pub fn text<S: Into<String>>(&self, text: S) -> Result<(), WebsocketError> {
+        self.send(Message::Text(text.into()))
+    }
+
+    pub fn binary<B: Into<Vec<u8>>>(&self, data: B) -> Result<(), WebsocketError> {
+        self.send(Message::Binary(data.into()))
+    }
+
+    pub fn close(&self, code: u16, reason: &str) -> Result<(), WebsocketError> {
+        self.send(Message::Close(Some(CloseFrame { code, reason: reason.into() })))
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.sender.is_closed()
+    }
+
+    ///
+    /// 连接的读取结束后调用，写任务写完已入队的消息后退出，不必等待所有 sender 释放
+    ///
+    pub(crate) fn finish(&self) {
+        self.sender.finish();
+    }
+
+    ///
+    /// 发送队列中尚未写出的消息数
+    ///
+    pub fn queue_len(&self) -> usize {
+        self.sender.len()
+    }
+
+    ///
+    /// 因发送队列写满而丢弃的消息数
+    ///
+    pub fn dropped_messages(&self) -> u64 {
+        self.sender.dropped()
+    }
+}
+
+///
+/// 异步的应用处理器
+/// 同一个处理器会被所有连接共享，连接相关的状态通过 sender 区分
+///
+#[async_trait]
+pub trait WebsocketHandler: Send + Sync + 'static {
+    async fn on_open(&self, _sender: &WebsocketSender) {}
+
+    async fn on_message(&self, sender: &WebsocketSender, msg: Message);
+
+    async fn on_close(&self, _sender: &WebsocketSender, _frame: Option<CloseFrame>) {}
+
+    async fn on_error(&self, _sender: &WebsocketSender, _err: WebsocketError) {}
+}
+
+///
+/// 同步的应用处理器，回调中不能 await
+///
+pub trait SyncWebsocketHandler: Send + Sync + 'static {
+    fn on_open(&self, _sender: &WebsocketSender) {}
+
+    fn on_message(&self, sender: &WebsocketSender, msg: Message);
+
+    fn on_close(&self, _sender: &WebsocketSender, _frame: Option<CloseFrame>) {}
+
+    fn on_error(&self, _sender: &WebsocketSender, _err: WebsocketError) {}
+}
+
+///
+/// 把同步处理器适配为异步处理器
+///
+pub struct SyncHandler<H>(pub H);
+
+///
+/// 握手校验通过、发送 101 响应之前调用的钩子
+/// 可以检查 Origin、Cookie、Authorization 等请求头，返回 Err 拒绝升级，
+/// 或返回附加到连接上的数据，处理器通过 WebsocketSender::user_data 读取
+///
+#[async_trait]
+pub trait UpgradeHook: Send + Sync + 'static {
+    async fn before_upgrade(&self, request: &HandshakeRequest) -> Result<Option<UserData>, Rejection>;
+}
+
+#[async_trait]
+impl<F> UpgradeHook for F
+    where
+        F: Fn(&HandshakeRequest) -> Result<Option<UserData>, Rejection> + Send + Sync + 'static
+{
+    async fn before_upgrade(&self, request: &HandshakeRequest) -> Result<Option<UserData>, Rejection> {
+        self(request)
+    }
+}
+
+#[async_trait]
+impl<H: SyncWebsocketHandler> WebsocketHandler for SyncHandler<H> {
+    async fn on_open(&self, sender: &WebsocketSender) {
+        self.0.on_open(sender)
+    }
+
+    async fn on_message(&self, sender: &WebsocketSender, msg: Message) {
+        self.0.on_message(sender, msg)
+    }
+
+    async fn on_close(&self, sender: &WebsocketSender, frame: Option<CloseFrame>) {
+        self.0.on_close(sender, frame)
+    }
+
+    async fn on_error(&self, sender: &WebsocketSender, err: WebsocketError) {
+        self.0.on_error(sender, err)
+    }
+}
+
+///
+/// 默认处理器，原样返回收到的文本与二进制消息
+///
+pub struct EchoHandler;
+
+impl SyncWebsocketHandler for EchoHandler {
+    fn on_message(&self, sender: &WebsocketSender, msg: Message) {
+        match msg {
+            Message::Text(_) | Message::Binary(_) => {
+                let _ = sender.send(msg);
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::websocket::queue::{self, OverflowPolicy, QueueConfig};
+    use std::sync::Mutex;
+
+    struct Recorder {
+        events: Mutex<Vec<String>>,
+    }
+
+    impl SyncWebsocketHandler for Recorder {
+        fn on_open(&self, sender: &WebsocketSender) {
+            self.events.lock().unwrap().push("open".into());
+            sender.text("hello").unwrap();
+        }
+
+        fn on_message(&self, _sender: &WebsocketSender, msg: Message) {
+            self.events.lock().unwrap().push(format!("{:?}", msg));
+        }
+
+        fn on_close(&self, _sender: &WebsocketSender, _frame: Option<CloseFrame>) {
+            self.events.lock().unwrap().push("close".into());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sync_handler_adapter() {
+        let (tx, mut rx) = queue::channel(QueueConfig::default());
+        let sender = ConnectionRegistry::new().register(tx);
+        let handler = SyncHandler(Recorder { events: Mutex::new(vec![]) });
+
+        handler.on_open(&sender).await;
+        handler.on_message(&sender, Message::Text("ping".into())).await;
+        handler.on_close(&sender, None).await;
+
+        assert_eq!(sender.queue_len(), 1);
+        assert_eq!(rx.recv().await, Some(Message::Text("hello".into())));
+        let events = handler.0.events.lock().unwrap().clone();
+        assert_eq!(events, vec!["open", "Text(\"ping\")", "close"]);
+    }
+
+    #[tokio::test]
+    async fn test_echo_handler() {
+        let (tx, mut rx) = queue::channel(QueueConfig::default());
+        let sender = ConnectionRegistry::new().register(tx);
+        let handler = SyncHandler(EchoHandler);
+
+        handler.on_message(&sender, Message::Binary(vec![1, 2, 3])).await;
+        handler.on_message(&sender, Message::Pong(vec![])).await;
+        drop(sender);
+
+        assert_eq!(rx.recv().await, Some(Message::Binary(vec![1, 2, 3])));
+        assert_eq!(rx.recv().await, None);
+    }
+
+    #[test]
+    fn test_send_after_close() {
+        let (tx, rx) = queue::channel(QueueConfig::default());
+        let sender = ConnectionRegistry::new().register(tx);
+        drop(rx);
+        assert!(sender.is_closed());
+        assert!(matches!(sender.text("late"), Err(WebsocketError::ConnectionClosed)));
+    }
+
+    #[test]
+    fn test_send_to_full_queue() {
+        let (tx, _rx) = queue::channel(QueueConfig { capacity: 1, policy: OverflowPolicy::DropNewest });
+        let registry = ConnectionRegistry::new();
+        let sender = registry.register(tx);
+        sender.text("first").unwrap();
+        // 队列已满与连接关闭是不同的错误
+        assert!(matches!(sender.text("second"), Err(WebsocketError::QueueFull)));
+        assert!(matches!(registry.send_to(sender.id(), Message::Text("third".into())), Err(WebsocketError::QueueFull)));
+        assert!(!sender.is_closed());
+    }
+}
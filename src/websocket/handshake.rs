@@ -0,0 +1,574 @@
+//!
+//! websocket 握手阶段的 HTTP/1.1 请求解析与校验（RFC 6455 4.2）
+//!
+
+use std::fmt;
+use sha1::Digest;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+///
+/// 请求头部的最大长度，超过后返回 431
+///
+const MAX_HEAD_SIZE: usize = 8 * 1024;
+
+///
+/// 解析后的握手请求
+///
+#[derive(Debug, Clone)]
+pub struct HandshakeRequest {
+    method: String,
+    target: String,
+    version: String,
+    headers: Vec<(String, String)>,
+}
+
+#[derive(Debug)]
+pub enum HandshakeError {
+    /// 握手完成前对端关闭了连接
+    Closed,
+    Io(std::io::Error),
+    /// 请求头部过大
+    TooLarge,
+    /// 请求格式或字段不合法
+    BadRequest(String),
+    /// 不是 websocket 升级请求
+    UpgradeRequired,
+    /// 不支持的 Sec-WebSocket-Version
+    UnsupportedVersion,
+    /// 请求路径没有对应的处理器
+    NotFound(String),
+    /// Origin 不在允许的列表中
+    Forbidden(String),
+    /// 未能在限定时间内收到完整的请求
+    Timeout,
+    /// 连接数已达上限
+    ServiceUnavailable,
+    /// 被升级前的钩子拒绝
+    Rejected(Rejection),
+}
+
+///
+/// 升级前钩子拒绝握手时返回的 HTTP 状态与附加响应头
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rejection {
+    status: u16,
+    reason: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Rejection {
+    pub fn new<S: Into<String>>(status: u16, reason: S) -> Rejection {
+        Rejection { status, reason: reason.into(), headers: vec![] }
+    }
+
+    pub fn unauthorized<S: Into<String>>(reason: S) -> Rejection {
+        Rejection::new(401, reason)
+    }
+
+    pub fn forbidden<S: Into<String>>(reason: S) -> Rejection {
+        Rejection::new(403, reason)
+    }
+
+    ///
+    /// 附加响应头，例如 401 时的 WWW-Authenticate
+    ///
+    pub fn header<N: Into<String>, V: Into<String>>(mut self, name: N, value: V) -> Rejection {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    pub fn reason(&self) -> &str {
+        &self.reason
+    }
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandshakeError::Closed => write!(f, "connection closed during handshake"),
+            HandshakeError::Io(e) => write!(f, "io error: {}", e),
+            HandshakeError::TooLarge => write!(f, "request head too large"),
+            HandshakeError::BadRequest(msg) => write!(f, "bad request: {}", msg),
+            HandshakeError::UpgradeRequired => write!(f, "websocket upgrade required"),
+            HandshakeError::UnsupportedVersion => write!(f, "unsupported websocket version"),
+            HandshakeError::NotFound(path) => write!(f, "no websocket endpoint at {}", path),
+            HandshakeError::Forbidden(msg) => write!(f, "forbidden: {}", msg),
+            HandshakeError::Timeout => write!(f, "handshake timed out"),
+            HandshakeError::ServiceUnavailable => write!(f, "too many connections"),
+            HandshakeError::Rejected(rejection) => write!(f, "{}", rejection.reason),
+        }
+    }
+}
+
+impl std::error::Error for HandshakeError {}
+
+impl HandshakeError {
+    ///
+    /// 握手失败时返回给客户端的 HTTP 响应，连接已断开时为 None
+    ///
+    pub fn response(&self) -> Option<String> {
+        let (status, extra) = match self {
+            HandshakeError::Closed | HandshakeError::Io(_) => return None,
+            HandshakeError::TooLarge => (431, String::new()),
+            HandshakeError::BadRequest(_) => (400, String::new()),
+            HandshakeError::UpgradeRequired => (426, "Upgrade: websocket\r\nConnection: Upgrade\r\n".into()),
+            HandshakeError::UnsupportedVersion => (426, "Sec-WebSocket-Version: 13\r\n".into()),
+            HandshakeError::NotFound(_) => (404, String::new()),
+            HandshakeError::Forbidden(_) => (403, String::new()),
+            HandshakeError::Timeout => (408, String::new()),
+            HandshakeError::ServiceUnavailable => (503, String::new()),
+            HandshakeError::Rejected(rejection) => (
+                rejection.status,
+                rejection.headers.iter().map(|(name, value)| format!("{}: {}\r\n", name, value)).collect(),
+            ),
+        };
+        let status = format!("{} {}", status, reason_phrase(status));
+        let body = self.to_string();
+        Some(format!(
+            "HTTP/1.1 {}\r\n{}Content-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            extra,
+            body.len(),
+            body
+        ))
+    }
+}
+
+pub(crate) fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        426 => "Upgrade Required",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "Error",
+    }
+}
+
+impl HandshakeRequest {
+    ///
+    /// 解析以 \r\n\r\n 结尾的请求头部
+    ///
+    pub fn parse(head: &[u8]) -> Result<HandshakeRequest, HandshakeError> {
+        let text = std::str::from_utf8(head)
+            .map_err(|_| HandshakeError::BadRequest("request head is not utf-8".into()))?;
+        let mut lines = text.split("\r\n");
+
+        let request_line = lines.next().unwrap_or_default();
+        let mut parts = request_line.split(' ');
+        let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(m), Some(t), Some(v), None) if !m.is_empty() && !t.is_empty() => (m, t, v),
+            _ => return Err(HandshakeError::BadRequest("invalid request line".into())),
+        };
+        if !version.starts_with("HTTP/") {
+            return Err(HandshakeError::BadRequest("invalid http version".into()));
+        }
+
+        let headers = parse_headers(lines)?;
+
+        Ok(HandshakeRequest {
+            method: method.into(),
+            target: target.into(),
+            version: version.into(),
+            headers,
+        })
+    }
+
+    pub fn method(&self) -> &str {
+        &self.method
+    }
+
+    ///
+    /// 请求路径，不含查询字符串
+    ///
+    pub fn path(&self) -> &str {
+        match self.target.find('?') {
+            Some(i) => &self.target[..i],
+            None => &self.target,
+        }
+    }
+
+    pub fn query(&self) -> Option<&str> {
+        self.target.find('?').map(|i| &self.target[i + 1..])
+    }
+
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    pub fn headers(&self) -> &[(String, String)] {
+        &self.headers
+    }
+
+    ///
+    /// 按名称查找请求头，名称不区分大小写
+    ///
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+
+    ///
+    /// 判断逗号分隔的请求头中是否包含指定记号，不区分大小写
+    ///
+    pub fn header_contains(&self, name: &str, token: &str) -> bool {
+        header_tokens(&self.headers, name)
+            .iter()
+            .any(|t| t.eq_ignore_ascii_case(token))
+    }
+
+    ///
+    /// 客户端在 Sec-WebSocket-Protocol 中提供的子协议列表
+    ///
+    pub fn protocols(&self) -> Vec<&str> {
+        header_tokens(&self.headers, "Sec-WebSocket-Protocol")
+    }
+
+    ///
+    /// 客户端在 Sec-WebSocket-Extensions 中提供的扩展项，每项包含扩展名与参数
+    ///
+    pub fn extensions(&self) -> Vec<&str> {
+        header_tokens(&self.headers, "Sec-WebSocket-Extensions")
+    }
+
+    ///
+    /// 是否为 websocket 升级请求，不是时可以按普通 HTTP 请求处理
+    ///
+    pub fn is_upgrade(&self) -> bool {
+        self.header_contains("Upgrade", "websocket")
+    }
+
+    ///
+    /// 校验升级请求，成功时返回 Sec-WebSocket-Key
+    ///
+    pub fn validate(&self) -> Result<&str, HandshakeError> {
+        if self.method != "GET" {
+            return Err(HandshakeError::BadRequest(format!("method {} not allowed", self.method)));
+        }
+        if self.version != "HTTP/1.1" {
+            return Err(HandshakeError::BadRequest(format!("unsupported http version {}", self.version)));
+        }
+        if self.header("Host").is_none() {
+            return Err(HandshakeError::BadRequest("missing Host header".into()));
+        }
+        if !self.is_upgrade() {
+            return Err(HandshakeError::UpgradeRequired);
+        }
+        if !self.header_contains("Connection", "upgrade") {
+            return Err(HandshakeError::BadRequest("Connection header must contain upgrade".into()));
+        }
+        if self.header("Sec-WebSocket-Version") != Some("13") {
+            return Err(HandshakeError::UnsupportedVersion);
+        }
+        let key = self.header("Sec-WebSocket-Key")
+            .ok_or_else(|| HandshakeError::BadRequest("missing Sec-WebSocket-Key".into()))?;
+        match base64::decode(key) {
+            Ok(nonce) if nonce.len() == 16 => Ok(key),
+            _ => Err(HandshakeError::BadRequest("invalid Sec-WebSocket-Key".into())),
+        }
+    }
+}
+
+///
+/// 客户端收到的握手响应
+///
+#[derive(Debug, Clone)]
+pub struct HandshakeResponse {
+    status: u16,
+    headers: Vec<(String, String)>,
+}
+
+impl HandshakeResponse {
+    pub fn parse(head: &[u8]) -> Result<HandshakeResponse, HandshakeError> {
+        let text = std::str::from_utf8(head)
+            .map_err(|_| HandshakeError::BadRequest("response head is not utf-8".into()))?;
+        let mut lines = text.split("\r\n");
+
+        let status_line = lines.next().unwrap_or_default();
+        let mut parts = status_line.splitn(3, ' ');
+        let status = match (parts.next(), parts.next()) {
+            (Some(version), Some(status)) if version.starts_with("HTTP/") => status.parse::<u16>().ok(),
+            _ => None,
+        };
+        let status = status.ok_or_else(|| HandshakeError::BadRequest("invalid status line".into()))?;
+
+        Ok(HandshakeResponse { status, headers: parse_headers(lines)? })
+    }
+
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+
+    pub fn header_contains(&self, name: &str, token: &str) -> bool {
+        header_tokens(&self.headers, name)
+            .iter()
+            .any(|t| t.eq_ignore_ascii_case(token))
+    }
+
+    pub fn extensions(&self) -> Vec<&str> {
+        header_tokens(&self.headers, "Sec-WebSocket-Extensions")
+    }
+}
+
+fn parse_headers<'a, I>(lines: I) -> Result<Vec<(String, String)>, HandshakeError>
+    where
+        I: Iterator<Item=&'a str>
+{
+    let mut headers = vec![];
+    for line in lines {
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = match line.find(':') {
+            Some(i) => (&line[..i], &line[i + 1..]),
+            None => return Err(HandshakeError::BadRequest(format!("invalid header line: {}", line))),
+        };
+        if name.is_empty() || name.trim() != name {
+            return Err(HandshakeError::BadRequest(format!("invalid header name: {}", name)));
+        }
+        headers.push((name.to_string(), value.trim().to_string()));
+    }
+    Ok(headers)
+}
+
+fn find_header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_str())
+}
+
+///
+/// 同名请求头按逗号拆分后的全部取值
+///
+fn header_tokens<'a>(headers: &'a [(String, String)], name: &str) -> Vec<&'a str> {
+    headers
+        .iter()
+        .filter(|(k, _)| k.eq_ignore_ascii_case(name))
+        .flat_map(|(_, v)| v.split(','))
+        .map(|t| t.trim())
+        .filter(|t| !t.is_empty())
+        .collect()
+}
+
+///
+/// 从连接中读取完整的请求头部，可以跨越多次 read
+/// 头部之后已读到的多余字节保留在 buf 中
+///
+pub async fn read_request<R>(reader: &mut R, buf: &mut Vec<u8>) -> Result<HandshakeRequest, HandshakeError>
+    where
+        R: AsyncRead + Unpin
+{
+    let head = read_head(reader, buf).await?;
+    HandshakeRequest::parse(&head)
+}
+
+///
+/// 读取以 \r\n\r\n 结尾的 HTTP 头部
+///
+pub(crate) async fn read_head<R>(reader: &mut R, buf: &mut Vec<u8>) -> Result<Vec<u8>, HandshakeError>
+    where
+        R: AsyncRead + Unpin
+{
+    let mut chunk = [0; 1024];
+    loop {
+        if let Some(i) = find_head_end(buf) {
+            return Ok(buf.drain(..i).collect());
+        }
+        if buf.len() > MAX_HEAD_SIZE {
+            return Err(HandshakeError::TooLarge);
+        }
+        let n = reader.read(&mut chunk).await.map_err(HandshakeError::Io)?;
+        if n == 0 {
+            return Err(HandshakeError::Closed);
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+///
+/// 返回头部结束位置（包含 \r\n\r\n）
+///
+fn find_head_end(buf: &[u8]) -> Option<usize> {
+    buf.windows(4).position(|w| w == b"\r\n\r\n").map(|i| i + 4)
+}
+
+pub fn accept_key(key: &str) -> String {
+    let mut sha1 = sha1::Sha1::new();
+    sha1.update(key.to_string() + WEBSOCKET_GUID);
+    base64::encode(sha1.finalize())
+}
+
+///
+/// 握手成功的 101 响应，protocol 与 extensions 为协商出的子协议与扩展
+///
+pub fn response(key: &str, protocol: Option<&str>, extensions: Option<&str>) -> String {
+    let mut res: Vec<String> = vec![
+        "HTTP/1.1 101 Switching Protocols".into(),
+        "Connection: Upgrade".into(),
+        "Upgrade: websocket".into(),
+        format!("Sec-WebSocket-Accept: {}", accept_key(key)),
+    ];
+    if let Some(protocol) = protocol {
+        res.push(format!("Sec-WebSocket-Protocol: {}", protocol));
+    }
+    if let Some(extensions) = extensions {
+        res.push(format!("Sec-WebSocket-Extensions: {}", extensions));
+    }
+    res.join("\r\n") + "\r\n\r\n"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    const REQUEST: &str = "GET /chat?room=1 HTTP/1.1\r\n\
+        host: localhost\r\n\
+        upgrade: WebSocket\r\n\
+        connection: keep-alive, Upgrade\r\n\
+        sec-websocket-key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+        sec-websocket-version: 13\r\n\r\n";
+
+    fn parse(text: &str) -> HandshakeRequest {
+        HandshakeRequest::parse(text.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn test_parse_and_validate() {
+        let request = parse(REQUEST);
+        assert_eq!(request.method(), "GET");
+        assert_eq!(request.path(), "/chat");
+        assert_eq!(request.query(), Some("room=1"));
+        assert_eq!(request.header("Sec-WebSocket-Key"), Some("dGhlIHNhbXBsZSBub25jZQ=="));
+        assert!(request.header_contains("Connection", "upgrade"));
+        assert_eq!(request.validate().unwrap(), "dGhlIHNhbXBsZSBub25jZQ==");
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+        assert!(request.protocols().is_empty());
+        assert!(request.extensions().is_empty());
+    }
+
+    #[test]
+    fn test_protocols_and_response() {
+        let request = parse(&REQUEST.replace(
+            "\r\n\r\n",
+            "\r\nSec-WebSocket-Protocol: wamp, mqtt\r\nsec-websocket-protocol: soap\r\n\r\n",
+        ));
+        assert_eq!(request.protocols(), vec!["wamp", "mqtt", "soap"]);
+
+        let res = response("dGhlIHNhbXBsZSBub25jZQ==", Some("mqtt"), Some("permessage-deflate"));
+        assert!(res.contains("\r\nSec-WebSocket-Protocol: mqtt\r\n"));
+        assert!(res.contains("\r\nSec-WebSocket-Extensions: permessage-deflate\r\n"));
+        assert!(res.ends_with("\r\n\r\n"));
+        assert!(!response("dGhlIHNhbXBsZSBub25jZQ==", None, None).contains("Sec-WebSocket-Protocol"));
+    }
+
+    #[test]
+    fn test_validate_errors() {
+        let request = parse(&REQUEST.replace("GET", "POST"));
+        assert!(matches!(request.validate(), Err(HandshakeError::BadRequest(_))));
+
+        let request = parse(&REQUEST.replace("upgrade: WebSocket\r\n", ""));
+        assert!(matches!(request.validate(), Err(HandshakeError::UpgradeRequired)));
+
+        let request = parse(&REQUEST.replace("keep-alive, Upgrade", "keep-alive"));
+        assert!(matches!(request.validate(), Err(HandshakeError::BadRequest(_))));
+
+        let request = parse(&REQUEST.replace("version: 13", "version: 8"));
+        assert!(matches!(request.validate(), Err(HandshakeError::UnsupportedVersion)));
+
+        let request = parse(&REQUEST.replace("sec-websocket-key: dGhlIHNhbXBsZSBub25jZQ==\r\n", ""));
+        assert!(matches!(request.validate(), Err(HandshakeError::BadRequest(_))));
+
+        let request = parse(&REQUEST.replace("dGhlIHNhbXBsZSBub25jZQ==", "c2hvcnQ="));
+        assert!(matches!(request.validate(), Err(HandshakeError::BadRequest(_))));
+
+        assert!(HandshakeRequest::parse(b"GET\r\n\r\n").is_err());
+        assert!(HandshakeRequest::parse(b"GET / HTTP/1.1\r\nbad header\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn test_error_response() {
+        let res = HandshakeError::UnsupportedVersion.response().unwrap();
+        assert!(res.starts_with("HTTP/1.1 426 Upgrade Required\r\n"));
+        assert!(res.contains("Sec-WebSocket-Version: 13\r\n"));
+
+        let res = HandshakeError::BadRequest("missing key".into()).response().unwrap();
+        assert!(res.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert!(HandshakeError::Closed.response().is_none());
+
+        let rejection = Rejection::unauthorized("token required").header("WWW-Authenticate", "Bearer");
+        let res = HandshakeError::Rejected(rejection).response().unwrap();
+        assert!(res.starts_with("HTTP/1.1 401 Unauthorized\r\nWWW-Authenticate: Bearer\r\n"));
+        assert!(res.ends_with("\r\n\r\ntoken required"));
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let head = response("dGhlIHNhbXBsZSBub25jZQ==", Some("wamp"), None);
+        let res = HandshakeResponse::parse(head.as_bytes()).unwrap();
+        assert_eq!(res.status(), 101);
+        assert_eq!(res.header("sec-websocket-accept"), Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+        assert_eq!(res.header("Sec-WebSocket-Protocol"), Some("wamp"));
+        assert!(res.header_contains("connection", "Upgrade"));
+
+        let head = HandshakeError::UnsupportedVersion.response().unwrap();
+        assert_eq!(HandshakeResponse::parse(head.as_bytes()).unwrap().status(), 426);
+        assert!(HandshakeResponse::parse(b"HTTP/1.1 abc\r\n\r\n").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_request_split_across_reads() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        tokio::spawn(async move {
+            for chunk in REQUEST.as_bytes().chunks(7) {
+                client.write_all(chunk).await.unwrap();
+                tokio::task::yield_now().await;
+            }
+            client.write_all(&[0x81, 0x80]).await.unwrap();
+        });
+
+        let mut buf = vec![];
+        let request = read_request(&mut server, &mut buf).await.unwrap();
+        assert_eq!(request.path(), "/chat");
+        assert!(request.validate().is_ok());
+
+        // 头部之后的帧数据保留在缓冲区中
+        while buf.len() < 2 {
+            let mut chunk = [0; 16];
+            let n = server.read(&mut chunk).await.unwrap();
+            buf.extend_from_slice(&chunk[..n]);
+        }
+        assert_eq!(buf, vec![0x81, 0x80]);
+    }
+
+    #[tokio::test]
+    async fn test_read_request_too_large() {
+        let mut data = b"GET / HTTP/1.1\r\n".to_vec();
+        data.extend(vec![b'a'; MAX_HEAD_SIZE + 10]);
+        let mut reader = data.as_slice();
+        let mut buf = vec![];
+        assert!(matches!(read_request(&mut reader, &mut buf).await, Err(HandshakeError::TooLarge)));
+    }
+}
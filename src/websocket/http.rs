@@ -0,0 +1,125 @@
+//!
+//! 与 websocket 共用端口的普通 HTTP/1.1 请求处理
+//! 没有携带 `Upgrade: websocket` 的请求交给 HttpHandler，例如健康检查或测试页面。
+//! 每个连接只处理一个请求，响应后关闭连接，请求体不会被读取。
+//!
+
+use async_trait::async_trait;
+use crate::websocket::handshake::{self, HandshakeRequest};
+
+///
+/// 普通 HTTP 请求的响应
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpResponse {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl HttpResponse {
+    pub fn new(status: u16) -> HttpResponse {
+        HttpResponse { status, headers: vec![], body: vec![] }
+    }
+
+    pub fn text<S: Into<String>>(body: S) -> HttpResponse {
+        HttpResponse::new(200)
+            .header("Content-Type", "text/plain; charset=utf-8")
+            .body(body.into())
+    }
+
+    pub fn html<S: Into<String>>(body: S) -> HttpResponse {
+        HttpResponse::new(200)
+            .header("Content-Type", "text/html; charset=utf-8")
+            .body(body.into())
+    }
+
+    pub fn json(value: &serde_json::Value) -> HttpResponse {
+        HttpResponse::new(200)
+            .header("Content-Type", "application/json")
+            .body(value.to_string())
+    }
+
+    pub fn not_found() -> HttpResponse {
+        HttpResponse::new(404)
+            .header("Content-Type", "text/plain")
+            .body("not found")
+    }
+
+    ///
+    /// 附加响应头，Content-Length 与 Connection 由服务端生成
+    ///
+    pub fn header<N: Into<String>, V: Into<String>>(mut self, name: N, value: V) -> HttpResponse {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    pub fn body<B: Into<Vec<u8>>>(mut self, body: B) -> HttpResponse {
+        self.body = body.into();
+        self
+    }
+
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    pub fn body_bytes(&self) -> &[u8] {
+        &self.body
+    }
+
+    ///
+    /// 编码为完整的 HTTP 响应，HEAD 请求只返回头部
+    ///
+    pub(crate) fn encode(&self, head_only: bool) -> Vec<u8> {
+        let mut data = format!("HTTP/1.1 {} {}\r\n", self.status, handshake::reason_phrase(self.status));
+        for (name, value) in self.headers.iter() {
+            data.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        data.push_str(&format!("Content-Length: {}\r\nConnection: close\r\n\r\n", self.body.len()));
+        let mut data = data.into_bytes();
+        if !head_only {
+            data.extend_from_slice(&self.body);
+        }
+        data
+    }
+}
+
+///
+/// 处理非升级请求，未注册时这类请求返回 426
+///
+#[async_trait]
+pub trait HttpHandler: Send + Sync + 'static {
+    async fn handle(&self, request: &HandshakeRequest) -> HttpResponse;
+}
+
+#[async_trait]
+impl<F> HttpHandler for F
+    where
+        F: Fn(&HandshakeRequest) -> HttpResponse + Send + Sync + 'static
+{
+    async fn handle(&self, request: &HandshakeRequest) -> HttpResponse {
+        self(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_response() {
+        let response = HttpResponse::text("ok").header("Cache-Control", "no-cache");
+        let data = String::from_utf8(response.encode(false)).unwrap();
+        assert_eq!(
+            data,
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; charset=utf-8\r\nCache-Control: no-cache\r\n\
+             Content-Length: 2\r\nConnection: close\r\n\r\nok"
+        );
+        let head = String::from_utf8(response.encode(true)).unwrap();
+        assert!(head.ends_with("Content-Length: 2\r\nConnection: close\r\n\r\n"));
+
+        let data = HttpResponse::not_found().encode(false);
+        assert!(data.starts_with(b"HTTP/1.1 404 Not Found\r\n"));
+        assert!(data.ends_with(b"\r\n\r\nnot found"));
+    }
+}
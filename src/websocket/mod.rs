@@ -1 +1,16 @@
+pub mod client;
+#[cfg(test)]
+mod conformance;
+pub mod deflate;
+pub mod error;
+mod frame;
+pub mod handler;
+pub mod handshake;
+pub mod http;
+pub mod mqtt;
+pub mod queue;
+pub mod registry;
+pub mod router;
 pub mod server;
+pub mod session;
+pub mod wamp;
@@ -0,0 +1,1191 @@
+//!
+//! MQTT 3.1.1 broker，运行在协商了 mqtt 子协议的 websocket 连接上
+//! MQTT 控制报文放在二进制消息中传输，一条消息可以包含多个报文，一个报文也可以跨多条消息。
+//!
+//! 支持 CONNECT/CONNACK、带 `+` 与 `#` 通配符的 SUBSCRIBE、QoS 0 与 QoS 1 的 PUBLISH、
+//! 保留消息、遗嘱消息与 keepalive。QoS 2 的 PUBLISH 被视为协议错误，订阅请求 QoS 2 时授予 QoS 1。
+//! clean session 为 0 时会话的订阅与未确认的 QoS 1 消息在断线后保留，重连时重新发送。
+//!
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use async_trait::async_trait;
+use tokio::time::{self, Duration, Instant};
+use crate::websocket::handler::{CloseFrame, Message, WebsocketHandler, WebsocketSender};
+use crate::websocket::registry::ConnectionId;
+use crate::websocket::server::{ServerConfig, WebsocketSubProtocols};
+
+const CONNECT: u8 = 1;
+const CONNACK: u8 = 2;
+const PUBLISH: u8 = 3;
+const PUBACK: u8 = 4;
+const SUBSCRIBE: u8 = 8;
+const SUBACK: u8 = 9;
+const UNSUBSCRIBE: u8 = 10;
+const UNSUBACK: u8 = 11;
+const PINGREQ: u8 = 12;
+const PINGRESP: u8 = 13;
+const DISCONNECT: u8 = 14;
+
+///
+/// 剩余长度字段能表示的最大值
+///
+const MAX_REMAINING_LENGTH: usize = 268_435_455;
+
+///
+/// 每个会话最多保存的未确认 QoS 1 消息，超出后丢弃新消息
+///
+const MAX_INFLIGHT: usize = 1000;
+
+///
+/// CONNACK 的返回码
+///
+pub const ACCEPTED: u8 = 0;
+pub const UNACCEPTABLE_PROTOCOL_VERSION: u8 = 1;
+pub const IDENTIFIER_REJECTED: u8 = 2;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Publish {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub qos: u8,
+    pub retain: bool,
+    pub dup: bool,
+    /// QoS 大于 0 时才有报文标识符
+    pub packet_id: Option<u16>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Connect {
+    pub protocol_level: u8,
+    pub client_id: String,
+    pub clean_session: bool,
+    pub keep_alive: u16,
+    pub will: Option<Publish>,
+    pub username: Option<String>,
+    pub password: Option<Vec<u8>>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Packet {
+    Connect(Connect),
+    ConnAck { session_present: bool, code: u8 },
+    Publish(Publish),
+    PubAck(u16),
+    Subscribe { packet_id: u16, filters: Vec<(String, u8)> },
+    SubAck { packet_id: u16, codes: Vec<u8> },
+    Unsubscribe { packet_id: u16, filters: Vec<String> },
+    UnsubAck(u16),
+    PingReq,
+    PingResp,
+    Disconnect,
+}
+
+///
+/// 报文体的读取游标
+///
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn u8(&mut self) -> Result<u8, String> {
+        let (first, rest) = self.data.split_first().ok_or("packet too short")?;
+        self.data = rest;
+        Ok(*first)
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        Ok(u16::from_be_bytes([self.u8()?, self.u8()?]))
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>, String> {
+        let len = self.u16()? as usize;
+        if self.data.len() < len {
+            return Err("packet too short".into());
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes.to_vec())
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        String::from_utf8(self.bytes()?).map_err(|_| "invalid utf-8 string".to_string())
+    }
+
+    fn rest(&mut self) -> Vec<u8> {
+        let rest = self.data.to_vec();
+        self.data = &[];
+        rest
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+}
+
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    assert!(bytes.len() <= u16::MAX as usize, "mqtt string longer than 65535 bytes");
+    out.extend_from_slice(&(bytes.len() as u16).to_be_bytes());
+    out.extend_from_slice(bytes);
+}
+
+impl Packet {
+    ///
+    /// 从缓冲区开头解析一个报文，数据不完整时返回 None，成功时同时返回消耗的字节数
+    ///
+    pub fn decode(data: &[u8]) -> Result<Option<(Packet, usize)>, String> {
+        let (offset, len) = match Packet::fixed_header(data)? {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+        let body = match data.get(offset..offset + len) {
+            Some(body) => body,
+            None => return Ok(None),
+        };
+        let packet = Packet::decode_body(data[0] >> 4, data[0] & 0x0f, body)?;
+        Ok(Some((packet, offset + len)))
+    }
+
+    ///
+    /// 解析固定报头，返回报头的字节数与剩余长度，报头不完整时返回 None
+    ///
+    fn fixed_header(data: &[u8]) -> Result<Option<(usize, usize)>, String> {
+        if data.is_empty() {
+            return Ok(None);
+        }
+        let mut len = 0;
+        let mut offset = 1;
+        loop {
+            let byte = match data.get(offset) {
+                Some(byte) => *byte,
+                None => return Ok(None),
+            };
+            len += ((byte & 0x7f) as usize) << (7 * (offset - 1));
+            offset += 1;
+            if byte & 0x80 == 0 {
+                break;
+            }
+            if offset > 4 {
+                return Err("malformed remaining length".into());
+            }
+        }
+        Ok(Some((offset, len)))
+    }
+
+    fn decode_body(kind: u8, flags: u8, body: &[u8]) -> Result<Packet, String> {
+        let expected_flags = match kind {
+            PUBLISH => flags,
+            SUBSCRIBE | UNSUBSCRIBE => 0b0010,
+            _ => 0,
+        };
+        if flags != expected_flags {
+            return Err(format!("invalid flags for packet type {}", kind));
+        }
+        let mut reader = Reader { data: body };
+        let packet = match kind {
+            CONNECT => Packet::Connect(decode_connect(&mut reader)?),
+            CONNACK => {
+                let session_present = reader.u8()? & 1 == 1;
+                Packet::ConnAck { session_present, code: reader.u8()? }
+            }
+            PUBLISH => {
+                let qos = (flags >> 1) & 0b11;
+                if qos == 3 {
+                    return Err("invalid qos 3".into());
+                }
+                let topic = reader.string()?;
+                let packet_id = if qos > 0 { Some(reader.u16()?) } else { None };
+                Packet::Publish(Publish {
+                    topic,
+                    payload: reader.rest(),
+                    qos,
+                    retain: flags & 1 == 1,
+                    dup: flags & 0b1000 != 0,
+                    packet_id,
+                })
+            }
+            PUBACK => Packet::PubAck(reader.u16()?),
+            SUBSCRIBE => {
+                let packet_id = reader.u16()?;
+                let mut filters = vec![];
+                while !reader.is_empty() {
+                    filters.push((reader.string()?, reader.u8()?));
+                }
+                if filters.is_empty() {
+                    return Err("SUBSCRIBE without topic filters".into());
+                }
+                Packet::Subscribe { packet_id, filters }
+            }
+            SUBACK => {
+                let packet_id = reader.u16()?;
+                Packet::SubAck { packet_id, codes: reader.rest() }
+            }
+            UNSUBSCRIBE => {
+                let packet_id = reader.u16()?;
+                let mut filters = vec![];
+                while !reader.is_empty() {
+                    filters.push(reader.string()?);
+                }
+                if filters.is_empty() {
+                    return Err("UNSUBSCRIBE without topic filters".into());
+                }
+                Packet::Unsubscribe { packet_id, filters }
+            }
+            UNSUBACK => Packet::UnsubAck(reader.u16()?),
+            PINGREQ => Packet::PingReq,
+            PINGRESP => Packet::PingResp,
+            DISCONNECT => Packet::Disconnect,
+            _ => return Err(format!("unsupported packet type {}", kind)),
+        };
+        if !reader.is_empty() {
+            return Err(format!("unexpected bytes after packet type {}", kind));
+        }
+        Ok(packet)
+    }
+
+    ///
+    /// 编码报文，报文体超过剩余长度的上限或字符串超过 65535 字节时 panic，
+    /// 调用方需要先拒绝这样的报文
+    ///
+    pub fn encode(&self) -> Vec<u8> {
+        let mut body = vec![];
+        let (kind, flags) = match self {
+            Packet::Connect(connect) => {
+                put_bytes(&mut body, b"MQTT");
+                body.push(connect.protocol_level);
+                let mut connect_flags = (connect.clean_session as u8) << 1;
+                if let Some(will) = &connect.will {
+                    connect_flags |= 0b100 | will.qos << 3 | (will.retain as u8) << 5;
+                }
+                connect_flags |= (connect.password.is_some() as u8) << 6;
+                connect_flags |= (connect.username.is_some() as u8) << 7;
+                body.push(connect_flags);
+                body.extend_from_slice(&connect.keep_alive.to_be_bytes());
+                put_bytes(&mut body, connect.client_id.as_bytes());
+                if let Some(will) = &connect.will {
+                    put_bytes(&mut body, will.topic.as_bytes());
+                    put_bytes(&mut body, &will.payload);
+                }
+                if let Some(username) = &connect.username {
+                    put_bytes(&mut body, username.as_bytes());
+                }
+                if let Some(password) = &connect.password {
+                    put_bytes(&mut body, password);
+                }
+                (CONNECT, 0)
+            }
+            Packet::ConnAck { session_present, code } => {
+                body.extend_from_slice(&[*session_present as u8, *code]);
+                (CONNACK, 0)
+            }
+            Packet::Publish(publish) => {
+                put_bytes(&mut body, publish.topic.as_bytes());
+                if let Some(packet_id) = publish.packet_id {
+                    body.extend_from_slice(&packet_id.to_be_bytes());
+                }
+                body.extend_from_slice(&publish.payload);
+                let flags = (publish.dup as u8) << 3 | publish.qos << 1 | publish.retain as u8;
+                (PUBLISH, flags)
+            }
+            Packet::PubAck(packet_id) => {
+                body.extend_from_slice(&packet_id.to_be_bytes());
+                (PUBACK, 0)
+            }
+            Packet::Subscribe { packet_id, filters } => {
+                body.extend_from_slice(&packet_id.to_be_bytes());
+                for (filter, qos) in filters {
+                    put_bytes(&mut body, filter.as_bytes());
+                    body.push(*qos);
+                }
+                (SUBSCRIBE, 0b0010)
+            }
+            Packet::SubAck { packet_id, codes } => {
+                body.extend_from_slice(&packet_id.to_be_bytes());
+                body.extend_from_slice(codes);
+                (SUBACK, 0)
+            }
+            Packet::Unsubscribe { packet_id, filters } => {
+                body.extend_from_slice(&packet_id.to_be_bytes());
+                for filter in filters {
+                    put_bytes(&mut body, filter.as_bytes());
+                }
+                (UNSUBSCRIBE, 0b0010)
+            }
+            Packet::UnsubAck(packet_id) => {
+                body.extend_from_slice(&packet_id.to_be_bytes());
+                (UNSUBACK, 0)
+            }
+            Packet::PingReq => (PINGREQ, 0),
+            Packet::PingResp => (PINGRESP, 0),
+            Packet::Disconnect => (DISCONNECT, 0),
+        };
+        let mut out = vec![kind << 4 | flags];
+        assert!(body.len() <= MAX_REMAINING_LENGTH, "mqtt packet too large");
+        let mut len = body.len();
+        loop {
+            let byte = (len % 128) as u8;
+            len /= 128;
+            if len == 0 {
+                out.push(byte);
+                break;
+            }
+            out.push(byte | 0x80);
+        }
+        out.extend(body);
+        out
+    }
+}
+
+fn decode_connect(reader: &mut Reader) -> Result<Connect, String> {
+    if reader.string()? != "MQTT" {
+        return Err("invalid protocol name".into());
+    }
+    let protocol_level = reader.u8()?;
+    let flags = reader.u8()?;
+    if flags & 1 != 0 {
+        return Err("reserved connect flag is set".into());
+    }
+    // 没有遗嘱时遗嘱 QoS 与 retain 必须为 0，没有用户名时不能带密码
+    if flags & 0b100 == 0 && flags & 0b11_1000 != 0 {
+        return Err("will qos or retain set without will flag".into());
+    }
+    if flags & 0x80 == 0 && flags & 0x40 != 0 {
+        return Err("password flag set without username flag".into());
+    }
+    let keep_alive = reader.u16()?;
+    let client_id = reader.string()?;
+    let will = if flags & 0b100 != 0 {
+        let qos = (flags >> 3) & 0b11;
+        if qos == 3 {
+            return Err("invalid will qos".into());
+        }
+        let topic = reader.string()?;
+        let payload = reader.bytes()?;
+        Some(Publish { topic, payload, qos, retain: flags & 0b10_0000 != 0, dup: false, packet_id: None })
+    } else {
+        None
+    };
+    let username = if flags & 0x80 != 0 { Some(reader.string()?) } else { None };
+    let password = if flags & 0x40 != 0 { Some(reader.bytes()?) } else { None };
+    Ok(Connect {
+        protocol_level,
+        client_id,
+        clean_session: flags & 0b10 != 0,
+        keep_alive,
+        will,
+        username,
+        password,
+    })
+}
+
+///
+/// 主题过滤器的合法性：`#` 只能单独出现在最后一级，`+` 只能单独占一级
+///
+pub fn is_valid_filter(filter: &str) -> bool {
+    let levels: Vec<&str> = filter.split('/').collect();
+    !filter.is_empty() && levels.iter().enumerate().all(|(i, level)| match *level {
+        "#" => i == levels.len() - 1,
+        "+" => true,
+        level => !level.contains('#') && !level.contains('+'),
+    })
+}
+
+///
+/// 主题是否匹配过滤器，`$` 开头的主题不会被通配符开头的过滤器匹配
+///
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+    let mut topic_levels = topic.split('/');
+    for level in filter.split('/') {
+        match (level, topic_levels.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => {}
+            (level, Some(topic_level)) if level == topic_level => {}
+            _ => return false,
+        }
+    }
+    topic_levels.next().is_none()
+}
+
+struct Connection {
+    sender: WebsocketSender,
+    buf: Vec<u8>,
+    /// CONNECT 之后才有值
+    client_id: Option<String>,
+    will: Option<Publish>,
+    last_seen: Instant,
+}
+
+#[derive(Default)]
+struct Session {
+    subscriptions: HashMap<String, u8>,
+    /// 已发送但尚未收到 PUBACK 的 QoS 1 消息
+    inflight: BTreeMap<u16, Publish>,
+    next_packet_id: u16,
+    connection: Option<ConnectionId>,
+    clean: bool,
+}
+
+impl Session {
+    fn next_packet_id(&mut self) -> u16 {
+        loop {
+            self.next_packet_id = self.next_packet_id.checked_add(1).unwrap_or(1);
+            if !self.inflight.contains_key(&self.next_packet_id) {
+                return self.next_packet_id;
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    connections: HashMap<ConnectionId, Connection>,
+    sessions: HashMap<String, Session>,
+    retained: BTreeMap<String, Publish>,
+}
+
+impl State {
+    fn send(&self, id: ConnectionId, packet: &Packet) {
+        if let Some(connection) = self.connections.get(&id) {
+            let _ = connection.sender.binary(packet.encode());
+        }
+    }
+
+    ///
+    /// 把消息投递给所有匹配的会话，重叠的订阅只投递一次并取最高的 QoS
+    ///
+    fn route(&mut self, publish: &Publish) {
+        if publish.retain {
+            if publish.payload.is_empty() {
+                self.retained.remove(&publish.topic);
+            } else {
+                self.retained.insert(publish.topic.clone(), Publish { dup: false, packet_id: None, ..publish.clone() });
+            }
+        }
+        let targets: Vec<(String, u8)> = self.sessions
+            .iter()
+            .filter_map(|(client_id, session)| {
+                session.subscriptions
+                    .iter()
+                    .filter(|(filter, _)| topic_matches(filter, &publish.topic))
+                    .map(|(_, qos)| *qos)
+                    .max()
+                    .map(|qos| (client_id.clone(), qos.min(publish.qos)))
+            })
+            .collect();
+        for (client_id, qos) in targets {
+            let message = Publish { qos, retain: false, dup: false, packet_id: None, ..publish.clone() };
+            self.deliver(&client_id, message);
+        }
+    }
+
+    ///
+    /// QoS 1 的消息在会话离线时也会保存，等待重连后发送
+    ///
+    fn deliver(&mut self, client_id: &str, mut publish: Publish) {
+        let session = match self.sessions.get_mut(client_id) {
+            Some(session) => session,
+            None => return,
+        };
+        if publish.qos > 0 {
+            if session.inflight.len() >= MAX_INFLIGHT {
+                return;
+            }
+            let packet_id = session.next_packet_id();
+            publish.packet_id = Some(packet_id);
+            session.inflight.insert(packet_id, publish.clone());
+        }
+        if let Some(id) = session.connection {
+            self.send(id, &Packet::Publish(publish));
+        }
+    }
+
+    ///
+    /// 移除连接，publish_will 为 true 时发布遗嘱消息
+    ///
+    fn disconnect(&mut self, id: ConnectionId, publish_will: bool) {
+        let connection = match self.connections.remove(&id) {
+            Some(connection) => connection,
+            None => return,
+        };
+        if let Some(client_id) = &connection.client_id {
+            let remove = match self.sessions.get_mut(client_id) {
+                Some(session) if session.connection == Some(id) => {
+                    session.connection = None;
+                    session.clean
+                }
+                _ => false,
+            };
+            if remove {
+                self.sessions.remove(client_id);
+            }
+        }
+        if let (true, Some(will)) = (publish_will, connection.will) {
+            self.route(&will);
+        }
+    }
+}
+
+///
+/// MQTT broker，可以 clone 后在服务外部调用 publish
+///
+#[derive(Clone)]
+pub struct MqttBroker {
+    state: Arc<Mutex<State>>,
+    max_packet_size: usize,
+}
+
+impl Default for MqttBroker {
+    fn default() -> Self {
+        MqttBroker {
+            state: Arc::default(),
+            max_packet_size: ServerConfig::default().max_message_size,
+        }
+    }
+}
+
+impl MqttBroker {
+    pub fn new() -> MqttBroker {
+        MqttBroker::default()
+    }
+
+    ///
+    /// 单个报文（含固定报头）的最大字节数，默认与服务端的单条消息上限相同
+    /// 报文可以跨多条 websocket 消息，服务端的消息上限无法限制报文的大小
+    ///
+    pub fn max_packet_size(mut self, size: usize) -> MqttBroker {
+        self.max_packet_size = size;
+        self
+    }
+
+    ///
+    /// 由服务端发布消息，与客户端发布的消息走相同的投递流程
+    /// 主题超过 65535 字节或报文超过剩余长度的上限时返回 Err
+    ///
+    pub fn publish<T: Into<String>, P: Into<Vec<u8>>>(&self, topic: T, payload: P, qos: u8, retain: bool) -> Result<(), String> {
+        let publish = Publish {
+            topic: topic.into(),
+            payload: payload.into(),
+            qos: qos.min(1),
+            retain,
+            dup: false,
+            packet_id: None,
+        };
+        if publish.topic.len() > u16::MAX as usize {
+            return Err("topic too long".into());
+        }
+        // 主题长度与报文标识符各占两个字节
+        if 2 + publish.topic.len() + 2 + publish.payload.len() > MAX_REMAINING_LENGTH {
+            return Err("packet too large".into());
+        }
+        self.state.lock().unwrap().route(&publish);
+        Ok(())
+    }
+
+    pub fn retained(&self, topic: &str) -> Option<Vec<u8>> {
+        self.state.lock().unwrap().retained.get(topic).map(|publish| publish.payload.clone())
+    }
+
+    ///
+    /// 已完成 CONNECT 的客户端数
+    ///
+    pub fn client_count(&self) -> usize {
+        self.state.lock().unwrap().connections.values().filter(|c| c.client_id.is_some()).count()
+    }
+
+    fn handle(&self, sender: &WebsocketSender, packet: Packet) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        let id = sender.id();
+        let connected = match state.connections.get_mut(&id) {
+            Some(connection) => {
+                connection.last_seen = Instant::now();
+                connection.client_id.is_some()
+            }
+            None => return Ok(()),
+        };
+        match packet {
+            Packet::Connect(connect) if !connected => {
+                let keep_alive = connect.keep_alive;
+                if self.connect(&mut state, id, connect) && keep_alive > 0 {
+                    self.watch_keep_alive(sender.clone(), keep_alive);
+                }
+                Ok(())
+            }
+            _ if !connected => Err("first packet must be CONNECT".into()),
+            Packet::Connect(_) => Err("duplicate CONNECT".into()),
+            Packet::Publish(publish) => {
+                if publish.qos > 1 {
+                    return Err("qos 2 is not supported".into());
+                }
+                if publish.topic.is_empty() || publish.topic.contains(['+', '#']) {
+                    return Err("invalid topic name".into());
+                }
+                let ack = publish.packet_id.map(Packet::PubAck);
+                state.route(&publish);
+                if let Some(ack) = ack {
+                    state.send(id, &ack);
+                }
+                Ok(())
+            }
+            Packet::PubAck(packet_id) => {
+                if let Some(session) = session_of(&mut state, id) {
+                    session.inflight.remove(&packet_id);
+                }
+                Ok(())
+            }
+            Packet::Subscribe { packet_id, filters } => {
+                // MQTT-3.8.3-4：请求的 QoS 大于 2 是协议错误，需要断开连接
+                if filters.iter().any(|(_, qos)| *qos > 2) {
+                    return Err("invalid subscription qos".into());
+                }
+                let mut codes = vec![];
+                let mut retained = vec![];
+                for (filter, qos) in filters {
+                    if !is_valid_filter(&filter) {
+                        codes.push(0x80);
+                        continue;
+                    }
+                    let granted = qos.min(1);
+                    codes.push(granted);
+                    retained.extend(
+                        state.retained
+                            .values()
+                            .filter(|publish| topic_matches(&filter, &publish.topic))
+                            .map(|publish| Publish { qos: publish.qos.min(granted), ..publish.clone() }),
+                    );
+                    if let Some(session) = session_of(&mut state, id) {
+                        session.subscriptions.insert(filter, granted);
+                    }
+                }
+                state.send(id, &Packet::SubAck { packet_id, codes });
+                // 保留消息在 SUBACK 之后发送，并保留 retain 标志
+                let client_id = state.connections[&id].client_id.clone().expect("connected");
+                for publish in retained {
+                    state.deliver(&client_id, publish);
+                }
+                Ok(())
+            }
+            Packet::Unsubscribe { packet_id, filters } => {
+                if let Some(session) = session_of(&mut state, id) {
+                    for filter in filters {
+                        session.subscriptions.remove(&filter);
+                    }
+                }
+                state.send(id, &Packet::UnsubAck(packet_id));
+                Ok(())
+            }
+            Packet::PingReq => {
+                state.send(id, &Packet::PingResp);
+                Ok(())
+            }
+            Packet::Disconnect => {
+                state.disconnect(id, false);
+                let _ = sender.close(1000, "");
+                Ok(())
+            }
+            _ => Err("unexpected packet from client".into()),
+        }
+    }
+
+    ///
+    /// 处理 CONNECT，返回是否接受了连接
+    ///
+    fn connect(&self, state: &mut State, id: ConnectionId, connect: Connect) -> bool {
+        let reject = if connect.protocol_level != 4 {
+            Some(UNACCEPTABLE_PROTOCOL_VERSION)
+        } else if connect.client_id.is_empty() && !connect.clean_session {
+            Some(IDENTIFIER_REJECTED)
+        } else {
+            None
+        };
+        if let Some(code) = reject {
+            state.send(id, &Packet::ConnAck { session_present: false, code });
+            let _ = state.connections[&id].sender.close(1000, "");
+            return false;
+        }
+        let client_id = if connect.client_id.is_empty() {
+            format!("auto-{}", uuid::Uuid::new_v4())
+        } else {
+            connect.client_id
+        };
+
+        // 同一个客户端标识已在线时断开旧连接
+        if let Some(old) = state.sessions.get(&client_id).and_then(|session| session.connection) {
+            if let Some(connection) = state.connections.get(&old) {
+                let _ = connection.sender.close(1000, "session taken over");
+            }
+            state.disconnect(old, false);
+        }
+        if connect.clean_session {
+            state.sessions.remove(&client_id);
+        }
+        let session_present = state.sessions.contains_key(&client_id);
+        let session = state.sessions.entry(client_id.clone()).or_default();
+        session.connection = Some(id);
+        session.clean = connect.clean_session;
+        let pending: Vec<Publish> = session.inflight
+            .values()
+            .map(|publish| Publish { dup: true, ..publish.clone() })
+            .collect();
+
+        let connection = state.connections.get_mut(&id).expect("connection exists");
+        connection.client_id = Some(client_id);
+        connection.will = connect.will;
+        state.send(id, &Packet::ConnAck { session_present, code: ACCEPTED });
+        for publish in pending {
+            state.send(id, &Packet::Publish(publish));
+        }
+        true
+    }
+
+    ///
+    /// 超过 1.5 倍 keepalive 没有收到任何报文时断开连接并发布遗嘱
+    ///
+    fn watch_keep_alive(&self, sender: WebsocketSender, keep_alive: u16) {
+        let state = self.state.clone();
+        let timeout = Duration::from_millis(keep_alive as u64 * 1500);
+        tokio::spawn(async move {
+            loop {
+                let deadline = match state.lock().unwrap().connections.get(&sender.id()) {
+                    Some(connection) => connection.last_seen + timeout,
+                    None => return,
+                };
+                if Instant::now() >= deadline {
+                    state.lock().unwrap().disconnect(sender.id(), true);
+                    let _ = sender.close(1000, "keepalive timeout");
+                    return;
+                }
+                time::sleep_until(deadline).await;
+            }
+        });
+    }
+
+    fn fail(&self, sender: &WebsocketSender, reason: &str) {
+        self.state.lock().unwrap().disconnect(sender.id(), true);
+        let _ = sender.close(1002, reason);
+    }
+}
+
+fn session_of(state: &mut State, id: ConnectionId) -> Option<&mut Session> {
+    let client_id = state.connections.get(&id)?.client_id.as_ref()?;
+    state.sessions.get_mut(client_id)
+}
+
+#[async_trait]
+impl WebsocketHandler for MqttBroker {
+    async fn on_open(&self, sender: &WebsocketSender) {
+        if sender.protocol() != Some(WebsocketSubProtocols::MQTT) {
+            let _ = sender.close(1002, "mqtt subprotocol required");
+            return;
+        }
+        self.state.lock().unwrap().connections.insert(sender.id(), Connection {
+            sender: sender.clone(),
+            buf: vec![],
+            client_id: None,
+            will: None,
+            last_seen: Instant::now(),
+        });
+    }
+
+    async fn on_message(&self, sender: &WebsocketSender, msg: Message) {
+        let data = match msg {
+            Message::Binary(data) => data,
+            _ => return self.fail(sender, "mqtt requires binary messages"),
+        };
+        let mut buf = match self.state.lock().unwrap().connections.get_mut(&sender.id()) {
+            Some(connection) => {
+                connection.buf.extend(data);
+                std::mem::take(&mut connection.buf)
+            }
+            None => return,
+        };
+        let mut used = 0;
+        loop {
+            // 读到固定报头后立即检查大小，不等待整个报文到达
+            match Packet::fixed_header(&buf[used..]) {
+                Ok(Some((header, len))) if header + len > self.max_packet_size => {
+                    return self.fail(sender, "packet too large");
+                }
+                Ok(_) => {}
+                Err(reason) => return self.fail(sender, &reason),
+            }
+            match Packet::decode(&buf[used..]) {
+                Ok(Some((packet, len))) => {
+                    used += len;
+                    if let Err(reason) = self.handle(sender, packet) {
+                        return self.fail(sender, &reason);
+                    }
+                }
+                Ok(None) => break,
+                Err(reason) => return self.fail(sender, &reason),
+            }
+        }
+        buf.drain(..used);
+        if let Some(connection) = self.state.lock().unwrap().connections.get_mut(&sender.id()) {
+            connection.buf = buf;
+        }
+    }
+
+    async fn on_close(&self, sender: &WebsocketSender, _frame: Option<CloseFrame>) {
+        // 没有先发送 DISCONNECT 的断开视为异常断开，需要发布遗嘱
+        self.state.lock().unwrap().disconnect(sender.id(), true);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::websocket::client::{ClientConfig, WebsocketClient};
+    use crate::websocket::queue::{self, QueueConfig, QueueReceiver};
+    use crate::websocket::registry::ConnectionRegistry;
+    use crate::websocket::server::{ProtocolPolicy, WebsocketServer};
+    use tokio::net::TcpListener;
+
+    fn connect_packet(client_id: &str, clean_session: bool, keep_alive: u16) -> Connect {
+        Connect {
+            protocol_level: 4,
+            client_id: client_id.into(),
+            clean_session,
+            keep_alive,
+            will: None,
+            username: None,
+            password: None,
+        }
+    }
+
+    fn publish(topic: &str, payload: &[u8], qos: u8, retain: bool, packet_id: Option<u16>) -> Publish {
+        Publish { topic: topic.into(), payload: payload.to_vec(), qos, retain, dup: false, packet_id }
+    }
+
+    #[test]
+    fn test_packet_round_trip() {
+        let packets = vec![
+            Packet::Connect(Connect {
+                will: Some(publish("status/a", b"offline", 1, true, None)),
+                username: Some("user".into()),
+                password: Some(b"secret".to_vec()),
+                ..connect_packet("a", true, 30)
+            }),
+            Packet::ConnAck { session_present: true, code: ACCEPTED },
+            Packet::Publish(publish("a/b", b"payload", 0, false, None)),
+            Packet::Publish(Publish { dup: true, ..publish("a/b", &[0; 300], 1, true, Some(7)) }),
+            Packet::PubAck(7),
+            Packet::Subscribe { packet_id: 1, filters: vec![("a/+".into(), 1), ("#".into(), 0)] },
+            Packet::SubAck { packet_id: 1, codes: vec![1, 0x80] },
+            Packet::Unsubscribe { packet_id: 2, filters: vec!["a/+".into()] },
+            Packet::UnsubAck(2),
+            Packet::PingReq,
+            Packet::PingResp,
+            Packet::Disconnect,
+        ];
+        for packet in packets {
+            let data = packet.encode();
+            assert!(Packet::decode(&data[..data.len() - 1]).unwrap().is_none());
+            assert_eq!(Packet::decode(&data).unwrap(), Some((packet, data.len())));
+        }
+        // 300 字节的负载需要两个字节表示剩余长度
+        let data = Packet::Publish(publish("t", &[0; 300], 0, false, None)).encode();
+        assert_eq!(&data[..3], &[0x30, 0xaf, 0x02]);
+
+        assert!(Packet::decode(&[0x82, 0x02, 0x00, 0x01]).is_err());
+        assert!(Packet::decode(&[0x36, 0x00]).is_err());
+        assert!(Packet::decode(&[0xc1, 0x00]).is_err());
+        assert!(Packet::decode(&[0x30, 0xff, 0xff, 0xff, 0xff, 0x01]).is_err());
+    }
+
+    #[test]
+    fn test_malformed_connect_flags() {
+        // 除 flags 外都合法的 CONNECT，tail 为 client id 之后的字段
+        let connect = |flags: u8, tail: &[u8]| {
+            let mut body = vec![0, 4, b'M', b'Q', b'T', b'T', 4, flags, 0, 60, 0, 1, b'a'];
+            body.extend_from_slice(tail);
+            let mut data = vec![0x10, body.len() as u8];
+            data.extend(body);
+            Packet::decode(&data)
+        };
+        let will = [0, 1, b't', 0, 1, b'x'];
+        let password = [0, 1, b'p'];
+        assert!(connect(0b0000_1110, &will).unwrap().is_some());
+        assert!(connect(0b1100_0010, &[0, 1, b'u', 0, 1, b'p']).unwrap().is_some());
+
+        // 没有遗嘱标志时遗嘱 QoS 或 retain 被置位
+        assert!(connect(0b0000_1010, &[]).is_err());
+        assert!(connect(0b0001_0010, &[]).is_err());
+        assert!(connect(0b0010_0010, &[]).is_err());
+        // 遗嘱 QoS 为 3
+        assert!(connect(0b0001_1110, &will).is_err());
+        // 只有密码没有用户名
+        assert!(connect(0b0100_0010, &password).is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "mqtt packet too large")]
+    fn test_encode_oversized_packet() {
+        Packet::Publish(publish("t", &vec![0; MAX_REMAINING_LENGTH], 0, false, None)).encode();
+    }
+
+    #[test]
+    fn test_topic_matching() {
+        assert!(topic_matches("sport/tennis/#", "sport/tennis/player1/ranking"));
+        assert!(topic_matches("sport/tennis/#", "sport/tennis"));
+        assert!(topic_matches("sport/+/player1", "sport/tennis/player1"));
+        assert!(topic_matches("+/+", "/finance"));
+        assert!(topic_matches("#", "sport"));
+        assert!(!topic_matches("sport/+", "sport/tennis/player1"));
+        assert!(!topic_matches("sport/tennis", "sport/tennis/player1"));
+        assert!(!topic_matches("#", "$SYS/uptime"));
+        assert!(topic_matches("$SYS/#", "$SYS/uptime"));
+
+        assert!(is_valid_filter("sport/+/player1/#"));
+        assert!(!is_valid_filter("sport/tennis#"));
+        assert!(!is_valid_filter("sport/#/ranking"));
+        assert!(!is_valid_filter("sport+"));
+        assert!(!is_valid_filter(""));
+    }
+
+    async fn serve(broker: MqttBroker) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = WebsocketServer::builder()
+            .protocols(vec![WebsocketSubProtocols::MQTT])
+            .protocol_policy(ProtocolPolicy::Reject)
+            .route("/mqtt", broker)
+            .build();
+        tokio::spawn(async move { server.serve(listener).await });
+        format!("ws://{}/mqtt", addr)
+    }
+
+    async fn send(client: &mut WebsocketClient, packet: Packet) {
+        client.binary(packet.encode()).await.unwrap();
+    }
+
+    async fn recv(client: &mut WebsocketClient) -> Packet {
+        match client.recv().await.unwrap() {
+            Some(Message::Binary(data)) => Packet::decode(&data).unwrap().unwrap().0,
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    async fn connect(url: &str, connect: Connect) -> (WebsocketClient, bool) {
+        let config = ClientConfig { protocols: vec![WebsocketSubProtocols::MQTT], ..ClientConfig::default() };
+        let mut client = WebsocketClient::connect_with(url, config).await.unwrap();
+        send(&mut client, Packet::Connect(connect)).await;
+        match recv(&mut client).await {
+            Packet::ConnAck { session_present, code: ACCEPTED } => (client, session_present),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    async fn subscribe(client: &mut WebsocketClient, filter: &str, qos: u8) {
+        send(client, Packet::Subscribe { packet_id: 1, filters: vec![(filter.into(), qos)] }).await;
+        assert!(matches!(recv(client).await, Packet::SubAck { packet_id: 1, .. }));
+    }
+
+    #[tokio::test]
+    async fn test_publish_subscribe() {
+        let broker = MqttBroker::new();
+        let url = serve(broker.clone()).await;
+        let (mut subscriber, _) = connect(&url, connect_packet("sub", true, 0)).await;
+        let (mut publisher, _) = connect(&url, connect_packet("pub", true, 0)).await;
+        assert_eq!(broker.client_count(), 2);
+
+        send(&mut subscriber, Packet::Subscribe {
+            packet_id: 3,
+            filters: vec![("sensors/+/temperature".into(), 1), ("sensors/#".into(), 0), ("bad/#/x".into(), 0)],
+        }).await;
+        assert_eq!(recv(&mut subscriber).await, Packet::SubAck { packet_id: 3, codes: vec![1, 0, 0x80] });
+
+        // 重叠的订阅只投递一次，QoS 取两者较高值与发布 QoS 中较小者
+        send(&mut publisher, Packet::Publish(publish("sensors/kitchen/temperature", b"21", 1, false, Some(9)))).await;
+        assert_eq!(recv(&mut publisher).await, Packet::PubAck(9));
+        let delivered = match recv(&mut subscriber).await {
+            Packet::Publish(delivered) => delivered,
+            other => panic!("unexpected {:?}", other),
+        };
+        assert_eq!((delivered.topic.as_str(), delivered.payload.as_slice(), delivered.qos), ("sensors/kitchen/temperature", &b"21"[..], 1));
+        send(&mut subscriber, Packet::PubAck(delivered.packet_id.unwrap())).await;
+
+        send(&mut publisher, Packet::Publish(publish("sensors/kitchen/humidity", b"40", 0, false, None))).await;
+        assert_eq!(recv(&mut subscriber).await, Packet::Publish(publish("sensors/kitchen/humidity", b"40", 0, false, None)));
+
+        send(&mut subscriber, Packet::Unsubscribe { packet_id: 4, filters: vec!["sensors/#".into()] }).await;
+        assert_eq!(recv(&mut subscriber).await, Packet::UnsubAck(4));
+        send(&mut subscriber, Packet::PingReq).await;
+        assert_eq!(recv(&mut subscriber).await, Packet::PingResp);
+
+        broker.publish("sensors/hall/temperature", "19", 0, false).unwrap();
+        assert_eq!(recv(&mut subscriber).await, Packet::Publish(publish("sensors/hall/temperature", b"19", 0, false, None)));
+        assert!(broker.publish("sensors/hall/temperature", vec![0; MAX_REMAINING_LENGTH], 0, false).is_err());
+        assert!(broker.publish("a".repeat(70_000), "19", 0, false).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_retained_messages() {
+        let broker = MqttBroker::new();
+        let url = serve(broker.clone()).await;
+        let (mut publisher, _) = connect(&url, connect_packet("pub", true, 0)).await;
+        send(&mut publisher, Packet::Publish(publish("config/mode", b"eco", 0, true, None))).await;
+        send(&mut publisher, Packet::Publish(publish("config/level", b"3", 1, true, Some(1)))).await;
+        assert_eq!(recv(&mut publisher).await, Packet::PubAck(1));
+        assert_eq!(broker.retained("config/mode"), Some(b"eco".to_vec()));
+
+        let (mut subscriber, _) = connect(&url, connect_packet("sub", true, 0)).await;
+        subscribe(&mut subscriber, "config/+", 0).await;
+        let mut received = vec![];
+        for _ in 0..2 {
+            match recv(&mut subscriber).await {
+                Packet::Publish(publish) => {
+                    assert!(publish.retain);
+                    assert_eq!(publish.qos, 0);
+                    received.push(publish.topic);
+                }
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+        received.sort();
+        assert_eq!(received, vec!["config/level", "config/mode"]);
+
+        // 空负载的保留消息清除该主题的保留消息
+        send(&mut publisher, Packet::Publish(publish("config/mode", b"", 0, true, None))).await;
+        assert_eq!(recv(&mut subscriber).await, Packet::Publish(publish("config/mode", b"", 0, false, None)));
+        assert_eq!(broker.retained("config/mode"), None);
+    }
+
+    #[tokio::test]
+    async fn test_persistent_session() {
+        let url = serve(MqttBroker::new()).await;
+        let (mut subscriber, present) = connect(&url, connect_packet("device", false, 0)).await;
+        assert!(!present);
+        subscribe(&mut subscriber, "commands/device", 1).await;
+        send(&mut subscriber, Packet::Disconnect).await;
+        assert!(matches!(subscriber.recv().await.unwrap(), Some(Message::Close(_))));
+
+        // 离线期间的 QoS 1 消息在重连后发送
+        let (mut publisher, _) = connect(&url, connect_packet("pub", true, 0)).await;
+        send(&mut publisher, Packet::Publish(publish("commands/device", b"reboot", 1, false, Some(1)))).await;
+        assert_eq!(recv(&mut publisher).await, Packet::PubAck(1));
+
+        let (mut subscriber, present) = connect(&url, connect_packet("device", false, 0)).await;
+        assert!(present);
+        match recv(&mut subscriber).await {
+            Packet::Publish(publish) => {
+                assert_eq!(publish.payload, b"reboot");
+                assert!(publish.dup);
+                send(&mut subscriber, Packet::PubAck(publish.packet_id.unwrap())).await;
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let (_, present) = connect(&url, connect_packet("device", true, 0)).await;
+        assert!(!present);
+    }
+
+    async fn open(broker: &MqttBroker, registry: &ConnectionRegistry) -> (WebsocketSender, QueueReceiver) {
+        let (tx, rx) = queue::channel(QueueConfig::default());
+        let mut sender = registry.register(tx);
+        sender.set_protocol(Some(WebsocketSubProtocols::MQTT));
+        broker.on_open(&sender).await;
+        (sender, rx)
+    }
+
+    async fn feed(broker: &MqttBroker, sender: &WebsocketSender, packet: Packet) {
+        broker.on_message(sender, Message::Binary(packet.encode())).await;
+    }
+
+    async fn next(rx: &mut QueueReceiver) -> Packet {
+        match rx.recv().await {
+            Some(Message::Binary(data)) => Packet::decode(&data).unwrap().unwrap().0,
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_keep_alive_and_will() {
+        let broker = MqttBroker::new();
+        let registry = ConnectionRegistry::new();
+        let (watcher, mut watcher_rx) = open(&broker, &registry).await;
+        feed(&broker, &watcher, Packet::Connect(connect_packet("watcher", true, 0))).await;
+        feed(&broker, &watcher, Packet::Subscribe { packet_id: 1, filters: vec![("status/#".into(), 0)] }).await;
+        assert!(matches!(next(&mut watcher_rx).await, Packet::ConnAck { .. }));
+        assert!(matches!(next(&mut watcher_rx).await, Packet::SubAck { .. }));
+
+        let will = Connect {
+            will: Some(publish("status/sensor", b"offline", 0, false, None)),
+            ..connect_packet("sensor", true, 1)
+        };
+        let (sensor, mut sensor_rx) = open(&broker, &registry).await;
+        let started = Instant::now();
+        feed(&broker, &sensor, Packet::Connect(will)).await;
+        assert!(matches!(next(&mut sensor_rx).await, Packet::ConnAck { .. }));
+
+        // 收到报文后重新计时，之后 1.5 秒内没有任何报文时断开连接并发布遗嘱
+        time::advance(Duration::from_millis(1000)).await;
+        feed(&broker, &sensor, Packet::PingReq).await;
+        assert_eq!(next(&mut sensor_rx).await, Packet::PingResp);
+        match sensor_rx.recv().await {
+            Some(Message::Close(Some(frame))) => assert_eq!(frame.reason, "keepalive timeout"),
+            other => panic!("unexpected {:?}", other),
+        }
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(2500) && elapsed < Duration::from_millis(2510), "{:?}", elapsed);
+        assert_eq!(next(&mut watcher_rx).await, Packet::Publish(publish("status/sensor", b"offline", 0, false, None)));
+        assert_eq!(broker.client_count(), 1);
+
+        // 正常 DISCONNECT 不发布遗嘱
+        let will = Connect {
+            will: Some(publish("status/other", b"offline", 0, false, None)),
+            ..connect_packet("other", true, 0)
+        };
+        let (other, mut other_rx) = open(&broker, &registry).await;
+        feed(&broker, &other, Packet::Connect(will)).await;
+        feed(&broker, &other, Packet::Disconnect).await;
+        assert!(matches!(next(&mut other_rx).await, Packet::ConnAck { .. }));
+        assert!(matches!(other_rx.recv().await, Some(Message::Close(_))));
+        broker.on_close(&other, None).await;
+        assert!(watcher_rx.try_recv().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_protocol_errors() {
+        let url = serve(MqttBroker::new()).await;
+        let config = ClientConfig { protocols: vec![WebsocketSubProtocols::MQTT], ..ClientConfig::default() };
+
+        let mut client = WebsocketClient::connect_with(&url, config.clone()).await.unwrap();
+        send(&mut client, Packet::PingReq).await;
+        assert!(matches!(client.recv().await.unwrap(), Some(Message::Close(Some(frame))) if frame.code == 1002));
+
+        let mut client = WebsocketClient::connect_with(&url, config.clone()).await.unwrap();
+        send(&mut client, Packet::Connect(Connect { protocol_level: 3, ..connect_packet("old", true, 0) })).await;
+        assert_eq!(recv(&mut client).await, Packet::ConnAck { session_present: false, code: UNACCEPTABLE_PROTOCOL_VERSION });
+
+        let (mut client, _) = connect(&url, connect_packet("qos", true, 0)).await;
+        send(&mut client, Packet::Subscribe { packet_id: 1, filters: vec![("a".into(), 0), ("b".into(), 3)] }).await;
+        assert!(matches!(client.recv().await.unwrap(), Some(Message::Close(Some(frame))) if frame.code == 1002));
+
+        // 报文可以跨多条 websocket 消息
+        let mut client = WebsocketClient::connect_with(&url, config).await.unwrap();
+        let data = Packet::Connect(connect_packet("split", true, 0)).encode();
+        client.binary(data[..3].to_vec()).await.unwrap();
+        client.binary(data[3..].to_vec()).await.unwrap();
+        assert_eq!(recv(&mut client).await, Packet::ConnAck { session_present: false, code: ACCEPTED });
+    }
+
+    #[tokio::test]
+    async fn test_packet_size_limit() {
+        let url = serve(MqttBroker::new().max_packet_size(64)).await;
+        let (mut client, _) = connect(&url, connect_packet("small", true, 0)).await;
+        send(&mut client, Packet::Publish(publish("a", &[0; 32], 0, false, None))).await;
+        send(&mut client, Packet::PingReq).await;
+        assert_eq!(recv(&mut client).await, Packet::PingResp);
+
+        // 只收到声明了 1000 字节的固定报头就断开，不再缓存后续数据
+        client.binary(vec![0x30, 0xe8, 0x07, 0x00]).await.unwrap();
+        match client.recv().await.unwrap() {
+            Some(Message::Close(Some(frame))) => assert_eq!((frame.code, frame.reason.as_str()), (1002, "packet too large")),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
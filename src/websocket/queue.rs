@@ -0,0 +1,288 @@
+//!
+//! 每个连接的有界发送队列
+//! 处理器与广播只负责把消息放入队列，由连接的写任务取出后写入 socket，
+//! 客户端读取过慢导致队列写满时按 OverflowPolicy 处理，避免无限制地占用内存。
+//!
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+use crate::websocket::error::WebsocketError;
+use crate::websocket::handler::{CloseFrame, Message};
+
+///
+/// 队列写满时的处理方式
+///
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OverflowPolicy {
+    /// 丢弃队列中最早的消息，适合只关心最新状态的推送
+    DropOldest,
+    /// 丢弃新消息，发送方得到 QueueFull 错误
+    DropNewest,
+    /// 清空队列并以 1008 关闭连接
+    Disconnect,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QueueConfig {
+    pub capacity: usize,
+    pub policy: OverflowPolicy,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig { capacity: 1024, policy: OverflowPolicy::Disconnect }
+    }
+}
+
+struct State {
+    queue: VecDeque<Message>,
+    senders: usize,
+    receiver_alive: bool,
+    /// 关闭帧已经入队，之后的消息不再接受
+    closing: bool,
+    /// 连接的读取已经结束，写任务取完剩余的消息后退出
+    finished: bool,
+    dropped: u64,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    notify: Notify,
+    config: QueueConfig,
+}
+
+pub fn channel(config: QueueConfig) -> (QueueSender, QueueReceiver) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            queue: VecDeque::new(),
+            senders: 1,
+            receiver_alive: true,
+            closing: false,
+            finished: false,
+            dropped: 0,
+        }),
+        notify: Notify::new(),
+        config,
+    });
+    (QueueSender { shared: shared.clone() }, QueueReceiver { shared })
+}
+
+pub struct QueueSender {
+    shared: Arc<Shared>,
+}
+
+impl QueueSender {
+    ///
+    /// 消息入队，关闭帧不受容量限制
+    ///
+    pub fn send(&self, msg: Message) -> Result<(), WebsocketError> {
+        let mut state = self.shared.state.lock().unwrap();
+        if !state.receiver_alive || state.closing || state.finished {
+            return Err(WebsocketError::ConnectionClosed);
+        }
+        let is_close = matches!(msg, Message::Close(_));
+        if !is_close && state.queue.len() >= self.shared.config.capacity {
+            state.dropped += 1;
+            match self.shared.config.policy {
+                OverflowPolicy::DropOldest => {
+                    state.queue.pop_front();
+                }
+                OverflowPolicy::DropNewest => return Err(WebsocketError::QueueFull),
+                OverflowPolicy::Disconnect => {
+                    state.dropped += state.queue.len() as u64;
+                    state.queue.clear();
+                    state.queue.push_back(Message::Close(Some(CloseFrame {
+                        code: 1008,
+                        reason: "outbound queue overflow".into(),
+                    })));
+                    state.closing = true;
+                    self.shared.notify.notify_one();
+                    return Err(WebsocketError::QueueFull);
+                }
+            }
+        }
+        if is_close {
+            state.closing = true;
+        }
+        state.queue.push_back(msg);
+        self.shared.notify.notify_one();
+        Ok(())
+    }
+
+    ///
+    /// 队列中等待写出的消息数
+    ///
+    pub fn len(&self) -> usize {
+        self.shared.state.lock().unwrap().queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    ///
+    /// 因队列写满而丢弃的消息总数
+    ///
+    pub fn dropped(&self) -> u64 {
+        self.shared.state.lock().unwrap().dropped
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.shared.config.capacity
+    }
+
+    ///
+    /// 写任务已经退出或关闭帧已经入队
+    ///
+    pub fn is_closed(&self) -> bool {
+        let state = self.shared.state.lock().unwrap();
+        !state.receiver_alive || state.closing || state.finished
+    }
+
+    ///
+    /// 不再接受新消息，接收端取完剩余的消息后返回 None，即使还有发送端存活
+    ///
+    pub fn finish(&self) {
+        self.shared.state.lock().unwrap().finished = true;
+        self.shared.notify.notify_one();
+    }
+}
+
+impl Clone for QueueSender {
+    fn clone(&self) -> Self {
+        self.shared.state.lock().unwrap().senders += 1;
+        QueueSender { shared: self.shared.clone() }
+    }
+}
+
+impl Drop for QueueSender {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.senders -= 1;
+        if state.senders == 0 {
+            self.shared.notify.notify_one();
+        }
+    }
+}
+
+pub struct QueueReceiver {
+    shared: Arc<Shared>,
+}
+
+impl QueueReceiver {
+    ///
+    /// 等待下一条消息，队列为空且所有发送端都被释放或已调用 finish 时返回 None
+    ///
+    pub async fn recv(&mut self) -> Option<Message> {
+        loop {
+            {
+                let mut state = self.shared.state.lock().unwrap();
+                if let Some(msg) = state.queue.pop_front() {
+                    return Some(msg);
+                }
+                if state.senders == 0 || state.finished {
+                    return None;
+                }
+            }
+            self.shared.notify.notified().await;
+        }
+    }
+
+    pub fn try_recv(&mut self) -> Option<Message> {
+        self.shared.state.lock().unwrap().queue.pop_front()
+    }
+}
+
+impl Drop for QueueReceiver {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.receiver_alive = false;
+        state.queue.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(i: usize) -> Message {
+        Message::Text(i.to_string())
+    }
+
+    fn fill(policy: OverflowPolicy) -> (QueueSender, QueueReceiver, Vec<bool>) {
+        let (tx, rx) = channel(QueueConfig { capacity: 3, policy });
+        let results = (0..5).map(|i| tx.send(text(i)).is_ok()).collect();
+        (tx, rx, results)
+    }
+
+    fn drain(rx: &mut QueueReceiver) -> Vec<Message> {
+        std::iter::from_fn(|| rx.try_recv()).collect()
+    }
+
+    #[test]
+    fn test_overflow_policies() {
+        let (tx, mut rx, results) = fill(OverflowPolicy::DropOldest);
+        assert_eq!(results, vec![true; 5]);
+        assert_eq!((tx.len(), tx.dropped()), (3, 2));
+        assert_eq!(drain(&mut rx), vec![text(2), text(3), text(4)]);
+
+        let (tx, mut rx, results) = fill(OverflowPolicy::DropNewest);
+        assert_eq!(results, vec![true, true, true, false, false]);
+        assert_eq!(tx.dropped(), 2);
+        assert_eq!(drain(&mut rx), vec![text(0), text(1), text(2)]);
+
+        let (tx, mut rx, results) = fill(OverflowPolicy::Disconnect);
+        assert_eq!(results, vec![true, true, true, false, false]);
+        assert!(tx.is_closed());
+        let close = CloseFrame { code: 1008, reason: "outbound queue overflow".into() };
+        assert_eq!(drain(&mut rx), vec![Message::Close(Some(close))]);
+    }
+
+    #[test]
+    fn test_close_bypasses_capacity() {
+        let (tx, mut rx) = channel(QueueConfig { capacity: 1, policy: OverflowPolicy::DropNewest });
+        tx.send(text(0)).unwrap();
+        tx.send(Message::Close(None)).unwrap();
+        assert!(matches!(tx.send(text(1)), Err(WebsocketError::ConnectionClosed)));
+        assert_eq!(drain(&mut rx), vec![text(0), Message::Close(None)]);
+    }
+
+    #[tokio::test]
+    async fn test_recv_until_senders_dropped() {
+        let (tx, mut rx) = channel(QueueConfig::default());
+        let other = tx.clone();
+        let task = tokio::spawn(async move {
+            let mut received = vec![];
+            while let Some(msg) = rx.recv().await {
+                received.push(msg);
+            }
+            received
+        });
+        tx.send(text(0)).unwrap();
+        drop(tx);
+        other.send(text(1)).unwrap();
+        drop(other);
+        assert_eq!(task.await.unwrap(), vec![text(0), text(1)]);
+    }
+
+    #[tokio::test]
+    async fn test_recv_until_finished() {
+        let (tx, mut rx) = channel(QueueConfig::default());
+        tx.send(text(0)).unwrap();
+        tx.finish();
+        assert!(tx.is_closed());
+        assert!(matches!(tx.send(text(1)), Err(WebsocketError::ConnectionClosed)));
+        // 发送端仍然存活，剩余的消息取完后结束
+        assert_eq!(rx.recv().await, Some(text(0)));
+        assert_eq!(rx.recv().await, None);
+    }
+
+    #[test]
+    fn test_receiver_dropped() {
+        let (tx, rx) = channel(QueueConfig::default());
+        drop(rx);
+        assert!(tx.is_closed());
+        assert!(matches!(tx.send(text(0)), Err(WebsocketError::ConnectionClosed)));
+    }
+}
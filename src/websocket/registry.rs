@@ -0,0 +1,244 @@
+//!
+//! 连接注册表，借鉴 design_patterns::architectural::publishers_subscribers 中的发布-订阅思想。
+//! 房间（room）相当于消息的类别，连接加入房间即订阅该类别，向房间广播即发布消息，
+//! 发布者无需了解房间中具体有哪些连接。
+//!
+
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use crate::websocket::error::WebsocketError;
+use crate::websocket::handler::{Message, WebsocketSender};
+use crate::websocket::queue::QueueSender;
+
+pub type ConnectionId = u64;
+
+#[derive(Default)]
+struct Inner {
+    connections: HashMap<ConnectionId, QueueSender>,
+    rooms: HashMap<String, HashSet<ConnectionId>>,
+}
+
+///
+/// 所有连接共享的注册表，可以跨任务 clone 使用
+///
+#[derive(Clone, Default)]
+pub struct ConnectionRegistry {
+    next_id: Arc<AtomicU64>,
+    inner: Arc<RwLock<Inner>>,
+}
+
+impl ConnectionRegistry {
+    pub fn new() -> ConnectionRegistry {
+        ConnectionRegistry::default()
+    }
+
+    ///
+    /// 登记新连接并生成它的发送句柄
+    ///
+    pub(crate) fn register(&self, sender: QueueSender) -> WebsocketSender {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        self.inner.write().unwrap().connections.insert(id, sender.clone());
+        WebsocketSender::new(id, sender, self.clone())
+    }
+
+    ///
+    /// 移除连接，同时退出它加入的所有房间
+    ///
+    pub(crate) fn unregister(&self, id: ConnectionId) {
+        let mut inner = self.inner.write().unwrap();
+        inner.connections.remove(&id);
+        inner.rooms.retain(|_, members| {
+            members.remove(&id);
+            !members.is_empty()
+        });
+    }
+
+    pub fn join(&self, id: ConnectionId, room: &str) -> bool {
+        let mut inner = self.inner.write().unwrap();
+        if !inner.connections.contains_key(&id) {
+            return false;
+        }
+        inner.rooms.entry(room.into()).or_default().insert(id)
+    }
+
+    pub fn leave(&self, id: ConnectionId, room: &str) -> bool {
+        let mut inner = self.inner.write().unwrap();
+        let (removed, empty) = match inner.rooms.get_mut(room) {
+            Some(members) => (members.remove(&id), members.is_empty()),
+            None => return false,
+        };
+        if empty {
+            inner.rooms.remove(room);
+        }
+        removed
+    }
+
+    pub fn send_to(&self, id: ConnectionId, msg: Message) -> Result<(), WebsocketError> {
+        let inner = self.inner.read().unwrap();
+        let sender = inner.connections.get(&id).ok_or(WebsocketError::ConnectionClosed)?;
+        sender.send(msg)
+    }
+
+    ///
+    /// 向所有连接广播，返回成功投递的连接数
+    ///
+    pub fn broadcast(&self, msg: Message) -> usize {
+        let inner = self.inner.read().unwrap();
+        inner.connections
+            .values()
+            .filter(|sender| sender.send(msg.clone()).is_ok())
+            .count()
+    }
+
+    ///
+    /// 向房间内的连接广播，返回成功投递的连接数
+    ///
+    pub fn broadcast_to_room(&self, room: &str, msg: Message) -> usize {
+        let inner = self.inner.read().unwrap();
+        match inner.rooms.get(room) {
+            Some(members) => members
+                .iter()
+                .filter_map(|id| inner.connections.get(id))
+                .filter(|sender| sender.send(msg.clone()).is_ok())
+                .count(),
+            None => 0,
+        }
+    }
+
+    ///
+    /// 连接发送队列中尚未写出的消息数，连接不存在时返回 None
+    ///
+    pub fn queue_len(&self, id: ConnectionId) -> Option<usize> {
+        self.inner.read().unwrap().connections.get(&id).map(|sender| sender.len())
+    }
+
+    pub fn connections(&self) -> Vec<ConnectionId> {
+        self.inner.read().unwrap().connections.keys().cloned().collect()
+    }
+
+    pub fn room_members(&self, room: &str) -> Vec<ConnectionId> {
+        self.inner.read().unwrap()
+            .rooms
+            .get(room)
+            .map(|members| members.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn rooms_of(&self, id: ConnectionId) -> Vec<String> {
+        self.inner.read().unwrap()
+            .rooms
+            .iter()
+            .filter(|(_, members)| members.contains(&id))
+            .map(|(room, _)| room.clone())
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.read().unwrap().connections.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::websocket::queue::{self, OverflowPolicy, QueueConfig, QueueReceiver};
+
+    fn connect(registry: &ConnectionRegistry) -> (WebsocketSender, QueueReceiver) {
+        connect_with(registry, QueueConfig::default())
+    }
+
+    fn connect_with(registry: &ConnectionRegistry, config: QueueConfig) -> (WebsocketSender, QueueReceiver) {
+        let (tx, rx) = queue::channel(config);
+        (registry.register(tx), rx)
+    }
+
+    #[test]
+    fn test_rooms_and_broadcast() {
+        let registry = ConnectionRegistry::new();
+        let (a, mut rx_a) = connect(&registry);
+        let (b, mut rx_b) = connect(&registry);
+        let (c, mut rx_c) = connect(&registry);
+        assert_eq!(registry.len(), 3);
+
+        assert!(a.join("chat"));
+        assert!(b.join("chat"));
+        assert!(!b.join("chat"));
+        assert!(c.join("news"));
+
+        assert_eq!(registry.broadcast_to_room("chat", Message::Text("hi".into())), 2);
+        assert_eq!(rx_a.try_recv().unwrap(), Message::Text("hi".into()));
+        assert_eq!(rx_b.try_recv().unwrap(), Message::Text("hi".into()));
+        assert!(rx_c.try_recv().is_none());
+
+        assert!(b.leave("chat"));
+        assert_eq!(registry.room_members("chat"), vec![a.id()]);
+
+        assert_eq!(registry.broadcast(Message::Text("all".into())), 3);
+        assert_eq!(rx_c.try_recv().unwrap(), Message::Text("all".into()));
+
+        registry.send_to(c.id(), Message::Text("direct".into())).unwrap();
+        assert_eq!(rx_c.try_recv().unwrap(), Message::Text("direct".into()));
+    }
+
+    #[test]
+    fn test_unregister_leaves_rooms() {
+        let registry = ConnectionRegistry::new();
+        let (a, _rx_a) = connect(&registry);
+        a.join("chat");
+        a.join("news");
+        assert_eq!(registry.rooms_of(a.id()).len(), 2);
+
+        registry.unregister(a.id());
+        assert!(registry.is_empty());
+        assert!(registry.room_members("chat").is_empty());
+        assert!(!registry.join(a.id(), "chat"));
+        assert!(matches!(
+            registry.send_to(a.id(), Message::Text("gone".into())),
+            Err(WebsocketError::ConnectionClosed)
+        ));
+    }
+
+    #[test]
+    fn test_broadcast_with_full_queue() {
+        let registry = ConnectionRegistry::new();
+        let (_a, _rx_a) = connect(&registry);
+        let (b, _rx_b) = connect_with(&registry, QueueConfig { capacity: 1, policy: OverflowPolicy::DropNewest });
+
+        assert_eq!(registry.broadcast(Message::Text("first".into())), 2);
+        // b 的队列已满，第二条消息只投递给 a
+        assert_eq!(registry.broadcast(Message::Text("second".into())), 1);
+        assert_eq!(registry.queue_len(b.id()), Some(1));
+        assert_eq!(b.dropped_messages(), 1);
+        assert_eq!(registry.queue_len(0), None);
+    }
+
+    #[test]
+    fn test_registry_across_threads() {
+        let registry = ConnectionRegistry::new();
+        let (a, mut rx_a) = connect(&registry);
+        a.join("chat");
+
+        let handles: Vec<_> = (0..4)
+            .map(|i| {
+                let registry = registry.clone();
+                std::thread::spawn(move || {
+                    registry.broadcast_to_room("chat", Message::Text(i.to_string()))
+                })
+            })
+            .collect();
+        for handle in handles {
+            assert_eq!(handle.join().unwrap(), 1);
+        }
+
+        let mut received = 0;
+        while rx_a.try_recv().is_some() {
+            received += 1;
+        }
+        assert_eq!(received, 4);
+    }
+}
@@ -0,0 +1,209 @@
+//!
+//! 按请求路径把 websocket 连接分发给不同的处理器
+//! 路径模式由 `/` 分隔，`:name` 段匹配任意一段并作为路径参数，例如 `/rooms/:room`
+//!
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use crate::websocket::handler::{SyncHandler, SyncWebsocketHandler, WebsocketHandler};
+
+///
+/// 匹配路径时提取出的参数
+///
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PathParams {
+    params: HashMap<String, String>,
+}
+
+impl PathParams {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.params.get(name).map(|value| value.as_str())
+    }
+
+    pub fn len(&self) -> usize {
+        self.params.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.params.is_empty()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Segment {
+    Static(String),
+    Param(String),
+}
+
+#[derive(Clone)]
+struct Route {
+    segments: Vec<Segment>,
+    handler: Arc<dyn WebsocketHandler>,
+}
+
+impl Route {
+    fn matches(&self, parts: &[&str]) -> Option<PathParams> {
+        if self.segments.len() != parts.len() {
+            return None;
+        }
+        let mut params = PathParams::default();
+        for (segment, part) in self.segments.iter().zip(parts) {
+            match segment {
+                Segment::Static(name) if name == part => {}
+                Segment::Static(_) => return None,
+                Segment::Param(name) => {
+                    params.params.insert(name.clone(), percent_decode(part)?);
+                }
+            }
+        }
+        Some(params)
+    }
+
+    ///
+    /// 静态段越靠前越优先，`/rooms/lobby` 先于 `/rooms/:room`
+    ///
+    fn priority(&self) -> Vec<bool> {
+        self.segments.iter().map(|segment| matches!(segment, Segment::Param(_))).collect()
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Router {
+    routes: Vec<Route>,
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router::default()
+    }
+
+    pub fn route<H: WebsocketHandler>(&mut self, pattern: &str, handler: H) -> &mut Router {
+        self.add(pattern, Arc::new(handler))
+    }
+
+    pub fn sync_route<H: SyncWebsocketHandler>(&mut self, pattern: &str, handler: H) -> &mut Router {
+        self.add(pattern, Arc::new(SyncHandler(handler)))
+    }
+
+    fn add(&mut self, pattern: &str, handler: Arc<dyn WebsocketHandler>) -> &mut Router {
+        let segments = split_path(pattern)
+            .into_iter()
+            .map(|part| match part.strip_prefix(':') {
+                Some(name) => Segment::Param(name.into()),
+                None => Segment::Static(part.into()),
+            })
+            .collect();
+        self.routes.push(Route { segments, handler });
+        self
+    }
+
+    ///
+    /// 查找路径对应的处理器，path 不包含查询字符串
+    ///
+    pub fn find(&self, path: &str) -> Option<(Arc<dyn WebsocketHandler>, PathParams)> {
+        let parts = split_path(path);
+        self.routes
+            .iter()
+            .filter_map(|route| route.matches(&parts).map(|params| (route, params)))
+            .min_by_key(|(route, _)| route.priority())
+            .map(|(route, params)| (route.handler.clone(), params))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+}
+
+///
+/// 忽略多余及末尾的 `/`，`/chat/` 与 `/chat` 等价
+///
+fn split_path(path: &str) -> Vec<&str> {
+    path.split('/').filter(|part| !part.is_empty()).collect()
+}
+
+fn percent_decode(part: &str) -> Option<String> {
+    let bytes = part.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = bytes.get(i + 1..i + 3).filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))?;
+            out.push(u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::websocket::handler::{EchoHandler, Message, WebsocketSender};
+    use crate::websocket::queue::{self, QueueConfig};
+    use crate::websocket::registry::ConnectionRegistry;
+
+    struct Named(&'static str);
+
+    impl SyncWebsocketHandler for Named {
+        fn on_open(&self, sender: &WebsocketSender) {
+            sender.text(self.0).unwrap();
+        }
+
+        fn on_message(&self, _sender: &WebsocketSender, _msg: Message) {}
+    }
+
+    async fn name_of(router: &Router, path: &str) -> Option<(String, PathParams)> {
+        let (handler, params) = router.find(path)?;
+        let (tx, mut rx) = queue::channel(QueueConfig::default());
+        let sender = ConnectionRegistry::new().register(tx);
+        handler.on_open(&sender).await;
+        match rx.try_recv() {
+            Some(Message::Text(name)) => Some((name, params)),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_route_matching() {
+        let mut router = Router::new();
+        router
+            .sync_route("/chat", Named("chat"))
+            .sync_route("/rooms/:room", Named("room"))
+            .sync_route("/rooms/lobby", Named("lobby"))
+            .sync_route("/users/:user/posts/:post", Named("post"));
+
+        assert_eq!(name_of(&router, "/chat").await.unwrap().0, "chat");
+        assert_eq!(name_of(&router, "/chat/").await.unwrap().0, "chat");
+        assert!(router.find("/").is_none());
+        assert!(router.find("/chat/extra").is_none());
+        assert!(router.find("/Chat").is_none());
+
+        let (name, params) = name_of(&router, "/rooms/rust%20lang").await.unwrap();
+        assert_eq!(name, "room");
+        assert_eq!(params.get("room"), Some("rust lang"));
+
+        // 静态段优先于参数段，与注册顺序无关
+        let (name, params) = name_of(&router, "/rooms/lobby").await.unwrap();
+        assert_eq!(name, "lobby");
+        assert!(params.is_empty());
+
+        let (_, params) = name_of(&router, "/users/7/posts/42").await.unwrap();
+        assert_eq!((params.get("user"), params.get("post")), (Some("7"), Some("42")));
+        assert_eq!(params.len(), 2);
+
+        assert!(router.find("/rooms/%zz").is_none());
+        assert!(router.find("/rooms/%+1").is_none());
+    }
+
+    #[test]
+    fn test_empty_router() {
+        let mut router = Router::new();
+        assert!(router.is_empty());
+        router.sync_route("/", EchoHandler);
+        assert!(router.find("/").is_some());
+        assert!(router.find("/echo").is_none());
+    }
+}
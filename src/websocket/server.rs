@@ -1,34 +1,123 @@
-use regex::Regex;
-use sha1::Digest;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpListener;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::net::tcp::OwnedWriteHalf;
 use tokio::runtime::Runtime;
-use std::convert::TryInto;
+use tokio::sync::Semaphore;
+use tokio::time;
+use std::sync::Arc;
+use std::time::Duration;
+use crate::websocket::deflate::{self, DeflateConfig, DeflateParams, Deflater, Inflater};
+use crate::websocket::frame::{self, FrameLimits, MessageDecoder};
+use crate::websocket::handshake::{self, HandshakeError, HandshakeRequest};
+use crate::websocket::http::HttpHandler;
+use crate::websocket::handler::{
+    CloseFrame, EchoHandler, Message, SyncHandler, SyncWebsocketHandler, UpgradeHook, UserData,
+    WebsocketHandler, WebsocketSender,
+};
+use crate::websocket::queue::{self, OverflowPolicy, QueueConfig, QueueReceiver};
+use crate::websocket::registry::ConnectionRegistry;
+use crate::websocket::router::{PathParams, Router};
 
 pub struct WebsocketServer {
     host: String,
     port: u16,
     runtime: Option<Runtime>,
+    handler: Arc<dyn WebsocketHandler>,
+    registry: ConnectionRegistry,
+    protocols: Vec<WebsocketSubProtocols>,
+    protocol_policy: ProtocolPolicy,
+    deflate: Option<DeflateConfig>,
+    config: ServerConfig,
+    upgrade_hook: Option<Arc<dyn UpgradeHook>>,
+    router: Router,
+    http_handler: Option<Arc<dyn HttpHandler>>,
 }
 
-struct FirstFrame {
-    fin: u8,
-    rsv_1: u8,
-    rsv_2: u8,
-    rsv_3: u8,
-    opcode: u8,
+///
+/// 服务端的资源限制
+///
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    /// 单个帧负载的最大字节数
+    pub max_frame_size: usize,
+    /// 单条消息（解压后）的最大字节数
+    pub max_message_size: usize,
+    /// 同时保持的最大连接数，None 表示不限制，超出时返回 503
+    pub max_connections: Option<usize>,
+    /// 从建立 TCP 连接到收到完整握手请求的最长时间，超时返回 408
+    pub handshake_timeout: Duration,
+    /// 每次从 socket 读取的字节数
+    pub read_buffer_size: usize,
+    /// 允许的 Origin，为空时不校验；非空时缺少 Origin 的请求同样被拒绝
+    pub allowed_origins: Vec<String>,
+    /// 每个连接的发送队列容量与写满时的处理方式
+    pub outbound_queue: QueueConfig,
 }
 
-enum Opcode {
-    Extended = 0x0,
-    Text = 0x1,
-    Binary = 0x2,
-    Close = 0x8,
-    Ping = 0x9,
-    Pong = 0xA,
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            max_frame_size: 16 * 1024 * 1024,
+            max_message_size: 64 * 1024 * 1024,
+            max_connections: None,
+            handshake_timeout: Duration::from_secs(10),
+            read_buffer_size: 4096,
+            allowed_origins: vec![],
+            outbound_queue: QueueConfig::default(),
+        }
+    }
+}
+
+impl ServerConfig {
+    fn frame_limits(&self) -> FrameLimits {
+        FrameLimits {
+            max_frame_size: self.max_frame_size,
+            max_message_size: self.max_message_size,
+            read_buffer_size: self.read_buffer_size,
+        }
+    }
+
+    fn origin_allowed(&self, origin: Option<&str>) -> bool {
+        if self.allowed_origins.is_empty() {
+            return true;
+        }
+        match origin {
+            Some(origin) => self.allowed_origins.iter().any(|allowed| allowed.eq_ignore_ascii_case(origin)),
+            None => false,
+        }
+    }
+}
+
+///
+/// 所有连接共享的服务配置
+///
+struct ServerContext {
+    handler: Arc<dyn WebsocketHandler>,
+    registry: ConnectionRegistry,
+    protocols: Vec<WebsocketSubProtocols>,
+    protocol_policy: ProtocolPolicy,
+    deflate: Option<DeflateConfig>,
+    config: ServerConfig,
+    upgrade_hook: Option<Arc<dyn UpgradeHook>>,
+    router: Router,
+    http_handler: Option<Arc<dyn HttpHandler>>,
+    /// 连接数上限对应的许可，握手成功后持有到连接结束
+    connections: Option<Arc<Semaphore>>,
+}
+
+///
+/// 握手通过后协商出的连接参数
+///
+struct Upgrade {
+    key: String,
+    handler: Arc<dyn WebsocketHandler>,
+    params: PathParams,
+    protocol: Option<WebsocketSubProtocols>,
+    deflate: Option<DeflateParams>,
+    user_data: Option<UserData>,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum WebsocketSubProtocols {
     MQTT,
     SOAP,
@@ -40,17 +129,55 @@ impl WebsocketSubProtocols {
         match self {
             WebsocketSubProtocols::MQTT => { "mqtt" }
             WebsocketSubProtocols::SOAP => { "soap" }
-            WebsocketSubProtocols::WAMP => { "wamp" }
+            WebsocketSubProtocols::WAMP => { "wamp.2.json" }
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<WebsocketSubProtocols> {
+        match name {
+            "mqtt" => Some(WebsocketSubProtocols::MQTT),
+            "soap" => Some(WebsocketSubProtocols::SOAP),
+            "wamp.2.json" => Some(WebsocketSubProtocols::WAMP),
+            _ => None,
         }
     }
 }
 
+///
+/// 客户端只提供了不支持的子协议时的处理方式
+///
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProtocolPolicy {
+    /// 返回 400 拒绝握手
+    Reject,
+    /// 不选择子协议继续握手，由客户端决定是否断开
+    Accept,
+}
+
 impl WebsocketServer {
     pub fn new(host: String, port: u16) -> WebsocketServer {
         WebsocketServer {
             host,
             port,
             runtime: None,
+            handler: Arc::new(SyncHandler(EchoHandler)),
+            registry: ConnectionRegistry::new(),
+            protocols: vec![],
+            protocol_policy: ProtocolPolicy::Accept,
+            deflate: None,
+            config: ServerConfig::default(),
+            upgrade_hook: None,
+            router: Router::new(),
+            http_handler: None,
+        }
+    }
+
+    ///
+    /// 以 127.0.0.1:7878 和默认配置开始构建服务
+    ///
+    pub fn builder() -> WebsocketServerBuilder {
+        WebsocketServerBuilder {
+            server: WebsocketServer::new(String::from("127.0.0.1"), 7878),
         }
     }
 
@@ -62,159 +189,419 @@ impl WebsocketServer {
         self
     }
 
+    ///
+    /// 注册异步处理器
+    ///
+    pub fn handler<H: WebsocketHandler>(&mut self, handler: H) -> &mut WebsocketServer {
+        self.handler = Arc::new(handler);
+        self
+    }
+
+    ///
+    /// 注册同步处理器
+    ///
+    pub fn sync_handler<H: SyncWebsocketHandler>(&mut self, handler: H) -> &mut WebsocketServer {
+        self.handler = Arc::new(SyncHandler(handler));
+        self
+    }
+
+    ///
+    /// 为路径注册异步处理器，路径可以包含 `:name` 参数
+    /// 注册了任意路由后，未匹配的路径在升级前返回 404，handler 设置的处理器不再使用
+    ///
+    pub fn route<H: WebsocketHandler>(&mut self, path: &str, handler: H) -> &mut WebsocketServer {
+        self.router.route(path, handler);
+        self
+    }
+
+    ///
+    /// 为路径注册同步处理器
+    ///
+    pub fn sync_route<H: SyncWebsocketHandler>(&mut self, path: &str, handler: H) -> &mut WebsocketServer {
+        self.router.sync_route(path, handler);
+        self
+    }
+
+    ///
+    /// 服务支持的子协议，按优先级从高到低排列
+    ///
+    pub fn protocols(&mut self, protocols: Vec<WebsocketSubProtocols>) -> &mut WebsocketServer {
+        self.protocols = protocols;
+        self
+    }
+
+    pub fn protocol_policy(&mut self, policy: ProtocolPolicy) -> &mut WebsocketServer {
+        self.protocol_policy = policy;
+        self
+    }
+
+    ///
+    /// 启用 permessage-deflate 压缩扩展，仅在客户端提出时生效
+    ///
+    pub fn permessage_deflate(&mut self, config: DeflateConfig) -> &mut WebsocketServer {
+        self.deflate = Some(config);
+        self
+    }
+
+    ///
+    /// 注册升级前钩子，用于鉴权或附加连接数据
+    ///
+    pub fn before_upgrade<H: UpgradeHook>(&mut self, hook: H) -> &mut WebsocketServer {
+        self.upgrade_hook = Some(Arc::new(hook));
+        self
+    }
+
+    ///
+    /// 处理同一端口上的普通 HTTP 请求，例如健康检查
+    ///
+    pub fn http_handler<H: HttpHandler>(&mut self, handler: H) -> &mut WebsocketServer {
+        self.http_handler = Some(Arc::new(handler));
+        self
+    }
+
+    pub fn config(&mut self, config: ServerConfig) -> &mut WebsocketServer {
+        self.config = config;
+        self
+    }
+
+    ///
+    /// 服务内所有连接共享的注册表，可在服务外部用于广播
+    ///
+    pub fn registry(&self) -> ConnectionRegistry {
+        self.registry.clone()
+    }
+
+    ///
+    /// 在内部创建的 Runtime 上阻塞运行服务
+    ///
     pub fn start(&mut self) {
-        if self.runtime.is_none() {
-            return;
-        }
-
-        self.runtime.as_ref().expect("runtime data is None").block_on(async {
-            let listener = TcpListener::bind(format!("{}:{}", self.host, self.port))
-                .await
-                .unwrap();
-            println!("Server has started on 127.0.0.1:7878.\r\nWaiting for a connection...");
-            loop {
-                let (mut socket, _) = listener.accept().await.expect("listener accept error");
-                tokio::spawn(async move {
-                    let mut buf = [0; 1024];
-                    loop {
-                        let n = match socket.read(&mut buf).await {
-                            // socket closed
-                            Ok(n) if n == 0 => return,
-                            Ok(n) => {
-                                let byte_data = buf.get(0..n).expect("get data bytes error");
-                                let frame = get_first_frame(byte_data[0]);
-                                if frame.opcode == 8 {
-                                    break;
-                                }
-                                let str_data =
-                                    String::from_utf8_lossy(byte_data).replace("\r\n", "\n");
-                                println!("{}", str_data);
-                                let get = Regex::new(r"^GET").expect("regex match error");
-                                if get.is_match(str_data.as_str()) {
-                                    let response = connect(str_data);
-                                    println!("{:?}", response);
-                                    println!("{:?}", response.as_bytes());
-                                    socket.write_all(response.as_bytes()).await;
-                                    let welcome_msg = "{\"data\":\"welcome!\"}";
-                                    let welcome_msg_head = [129 as u8, welcome_msg.len() as u8];
-                                    socket.write_all(&welcome_msg_head).await;
-                                    socket.write_all(welcome_msg.as_bytes()).await;
-                                } else {
-                                    println!("data length: {}", byte_data.len());
-                                    let result = decoded(byte_data);
-                                    if let Some(msg) = result {
-                                        println!("result content: {}", msg);
-                                        if msg.len() > 0 {
-                                            let mut msg_head = [0_u8; 2];
-                                            msg_head[0] = byte_data[0];
-                                            msg_head[1] = msg.len() as u8;
-                                            socket.write_all(&msg_head).await;
-                                            socket.write_all(msg.as_bytes()).await;
-                                        }
-                                    } else {
-                                        break;
-                                    }
-                                }
-                                n
-                            }
-                            Err(e) => {
-                                println!("failed to read from socket; err = {:?}", e);
-                                return;
-                            }
-                        };
-                    }
-                    println!("line end;")
-                });
-                println!("wait connect;")
+        self.init();
+        let runtime = match &self.runtime {
+            Some(runtime) => runtime,
+            None => {
+                println!("failed to create runtime");
+                return;
             }
-        })
-    }
-}
-
-fn get_first_frame(frame: u8) -> FirstFrame {
-    FirstFrame {
-        fin: (frame >> 7) & 1,
-        rsv_1: (frame >> 6) & 1,
-        rsv_2: (frame >> 5) & 1,
-        rsv_3: (frame >> 4) & 1,
-        opcode: get_low_4(frame),
-    }
-}
-
-fn get_low_4(frame: u8) -> u8 {
-    frame & 0x0f
-}
-
-fn get_height_4(frame: u8) -> u8 {
-    frame >> 4
-}
-
-fn connect(data: String) -> String {
-    let sec_key_text = Regex::new(r"Sec-WebSocket-Key: (.*)").expect("get sec_key_text error");
-    let group = sec_key_text.captures(data.as_str()).expect("captures data error");
-    let sec_key = group.get(1)
-        .expect("group match error")
-        .as_str()
-        .to_string() + "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
-    let mut sha1 = sha1::Sha1::new();
-    sha1.update(sec_key);
-    let sec_accept = base64::encode(sha1.finalize());
-    let mut res: Vec<String> = vec![
-        "HTTP/1.1 101 Switching Protocols".into(),
-        "Connection: Upgrade".into(),
-        "Upgrade: websocket".into(),
-        format!("Sec-WebSocket-Accept: {}\r\n\r\n", sec_accept),
-    ];
-    return res.join("\r\n");
-}
-
-fn decoded(data: &[u8]) -> Option<String> {
-    println!("{:?}", data);
-    let payload = data[1] & 127;
-    let is_mask = (data[1] >> 7) & 1;
-    let (maks, decoded, len) = if payload <= 125 {
-        if is_mask == 1 {
-            (data.get(2..6 as usize), data.get(6 as usize..), payload as u64)
-        } else {
-            (None, data.get(2..), payload as u64)
-        }
-    } else if payload == 126 {
-        let len_bytes = data.get(2..4).expect("126 data len error").to_vec();
-        let len = u16::from_le_bytes(len_bytes.try_into().expect("error parsing bytes to u16"));
-        if is_mask == 1 {
-            (data.get(4..8 as usize), data.get(8 as usize..), len as u64)
-        } else {
-            (None, data.get(4..), len as u64)
-        }
-    } else if payload == 127 {
-        let len_bytes = data.get(2..10).expect("127 data len error").to_vec();
-        let len = u64::from_le_bytes(len_bytes.try_into().expect("error parsing bytes to u64"));
-        if is_mask == 1 {
-            (data.get(10..14 as usize), data.get(14 as usize..), len as u64)
-        } else {
-            (None, data.get(10..), len as u64)
+        };
+        if let Err(e) = runtime.block_on(self.run()) {
+            println!("server stopped; err = {}", e);
         }
-    } else {
-        (None, None, 0)
+    }
+
+    ///
+    /// 绑定配置的地址并运行服务，可以直接在已有的 tokio runtime 中 await
+    ///
+    pub async fn run(&self) -> std::io::Result<()> {
+        let listener = TcpListener::bind(format!("{}:{}", self.host, self.port)).await?;
+        self.serve(listener).await
+    }
+
+    ///
+    /// 在已绑定的 listener 上接受连接
+    ///
+    pub async fn serve(&self, listener: TcpListener) -> std::io::Result<()> {
+        let context = Arc::new(ServerContext {
+            handler: self.handler.clone(),
+            registry: self.registry.clone(),
+            protocols: self.protocols.clone(),
+            protocol_policy: self.protocol_policy,
+            deflate: self.deflate,
+            config: self.config.clone(),
+            upgrade_hook: self.upgrade_hook.clone(),
+            router: self.router.clone(),
+            http_handler: self.http_handler.clone(),
+            connections: self.config.max_connections.map(|max| Arc::new(Semaphore::new(max))),
+        });
+
+        println!("Server has started on {}.\r\nWaiting for a connection...", listener.local_addr()?);
+        loop {
+            let (socket, _) = listener.accept().await?;
+            let context = context.clone();
+            tokio::spawn(handle_connection(socket, context));
+        }
+    }
+}
+
+///
+/// WebsocketServer 的构建器
+///
+pub struct WebsocketServerBuilder {
+    server: WebsocketServer,
+}
+
+impl WebsocketServerBuilder {
+    pub fn bind<S: Into<String>>(mut self, host: S, port: u16) -> Self {
+        self.server.host = host.into();
+        self.server.port = port;
+        self
+    }
+
+    pub fn handler<H: WebsocketHandler>(mut self, handler: H) -> Self {
+        self.server.handler(handler);
+        self
+    }
+
+    pub fn sync_handler<H: SyncWebsocketHandler>(mut self, handler: H) -> Self {
+        self.server.sync_handler(handler);
+        self
+    }
+
+    pub fn route<H: WebsocketHandler>(mut self, path: &str, handler: H) -> Self {
+        self.server.route(path, handler);
+        self
+    }
+
+    pub fn sync_route<H: SyncWebsocketHandler>(mut self, path: &str, handler: H) -> Self {
+        self.server.sync_route(path, handler);
+        self
+    }
+
+    pub fn protocols(mut self, protocols: Vec<WebsocketSubProtocols>) -> Self {
+        self.server.protocols(protocols);
+        self
+    }
+
+    pub fn protocol_policy(mut self, policy: ProtocolPolicy) -> Self {
+        self.server.protocol_policy(policy);
+        self
+    }
+
+    pub fn permessage_deflate(mut self, config: DeflateConfig) -> Self {
+        self.server.permessage_deflate(config);
+        self
+    }
+
+    pub fn before_upgrade<H: UpgradeHook>(mut self, hook: H) -> Self {
+        self.server.before_upgrade(hook);
+        self
+    }
+
+    pub fn http_handler<H: HttpHandler>(mut self, handler: H) -> Self {
+        self.server.http_handler(handler);
+        self
+    }
+
+    pub fn registry(mut self, registry: ConnectionRegistry) -> Self {
+        self.server.registry = registry;
+        self
+    }
+
+    pub fn max_frame_size(mut self, size: usize) -> Self {
+        self.server.config.max_frame_size = size;
+        self
+    }
+
+    pub fn max_message_size(mut self, size: usize) -> Self {
+        self.server.config.max_message_size = size;
+        self
+    }
+
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.server.config.max_connections = Some(max);
+        self
+    }
+
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.server.config.handshake_timeout = timeout;
+        self
+    }
+
+    pub fn read_buffer_size(mut self, size: usize) -> Self {
+        self.server.config.read_buffer_size = size;
+        self
+    }
+
+    pub fn allowed_origins<I, S>(mut self, origins: I) -> Self
+        where
+            I: IntoIterator<Item=S>,
+            S: Into<String>
+    {
+        self.server.config.allowed_origins = origins.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn outbound_queue(mut self, capacity: usize, policy: OverflowPolicy) -> Self {
+        self.server.config.outbound_queue = QueueConfig { capacity, policy };
+        self
+    }
+
+    pub fn build(self) -> WebsocketServer {
+        self.server
+    }
+}
+
+async fn handle_connection(socket: TcpStream, context: Arc<ServerContext>) {
+    let registry = &context.registry;
+    let limits = context.config.frame_limits();
+    let (mut reader, mut writer) = socket.into_split();
+    let mut buf = Vec::new();
+
+    let request = time::timeout(
+        context.config.handshake_timeout,
+        handshake::read_request(&mut reader, &mut buf),
+    ).await;
+    let permit = context.connections.clone().map(|limit| limit.try_acquire_owned());
+    let upgrade = match (request, &permit) {
+        (Err(_), _) => Err(HandshakeError::Timeout),
+        (Ok(Err(e)), _) => Err(e),
+        (Ok(Ok(_)), Some(Err(_))) => Err(HandshakeError::ServiceUnavailable),
+        (Ok(Ok(request)), _) => match &context.http_handler {
+            Some(http) if !request.is_upgrade() => {
+                let response = http.handle(&request).await;
+                let _ = writer.write_all(&response.encode(request.method() == "HEAD")).await;
+                return;
+            }
+            _ => upgrade(&request, &context).await,
+        },
+    };
+    let response = match &upgrade {
+        Ok(upgrade) => handshake::response(
+            &upgrade.key,
+            upgrade.protocol.map(|p| p.as_str()),
+            upgrade.deflate.map(|d| d.header_value()).as_deref(),
+        ),
+        Err(e) => match e.response() {
+            Some(response) => response,
+            None => return,
+        },
+    };
+    if writer.write_all(response.as_bytes()).await.is_err() {
+        return;
+    }
+    let upgrade = match upgrade {
+        Ok(upgrade) => upgrade,
+        Err(_) => return,
     };
 
-    if is_mask == 1 {
-        if let (Some(maks_bytes), Some(decoded_bytes)) = (maks, decoded) {
-            let mut coded = Vec::with_capacity(decoded_bytes.len());
-            for (i, v) in decoded_bytes.iter().enumerate() {
-                coded.push(v ^ maks_bytes[i % 4])
+    let (tx, rx) = queue::channel(context.config.outbound_queue);
+    let mut sender = registry.register(tx);
+    sender.set_protocol(upgrade.protocol);
+    sender.set_user_data(upgrade.user_data);
+    sender.set_params(upgrade.params);
+    let handler = &upgrade.handler;
+    let deflater = upgrade.deflate.map(|d| Deflater::new(d.server_no_context_takeover));
+    let inflater = upgrade.deflate.map(|d| Inflater::new(d.client_no_context_takeover));
+    let mut decoder = MessageDecoder::new(inflater, limits.max_message_size, true);
+    tokio::spawn(write_messages(writer, rx, deflater, handler.clone(), sender.clone()));
+    handler.on_open(&sender).await;
+
+    let mut close_frame = None;
+    loop {
+        let msg = match frame::read_frame(&mut reader, &mut buf, &limits).await {
+            Ok(Some(frame)) => decoder.decode(frame),
+            // socket closed
+            Ok(None) => break,
+            Err(e) => Err(e),
+        };
+        match msg {
+            Ok(Some(Message::Ping(payload))) => {
+                let _ = sender.send(Message::Pong(payload));
+            }
+            Ok(Some(Message::Pong(_))) | Ok(None) => {}
+            Ok(Some(Message::Close(frame))) => {
+                close_frame = frame;
+                // 回应关闭帧，若服务端已主动关闭则忽略发送失败
+                let _ = sender.send(Message::Close(close_frame.clone()));
+                break;
+            }
+            Ok(Some(msg)) => handler.on_message(&sender, msg).await,
+            Err(e) => {
+                // 对端违反协议时先以对应的状态码关闭连接
+                if let Some(code) = e.close_code() {
+                    let close = CloseFrame { code, reason: e.to_string() };
+                    let _ = sender.send(Message::Close(Some(close)));
+                }
+                handler.on_error(&sender, e).await;
+                break;
             }
-            return String::from_utf8(coded).ok();
         }
+    }
+    // 写任务自身也持有 sender，不能等待所有 sender 释放后才退出
+    sender.finish();
+    registry.unregister(sender.id());
+    handler.on_close(&sender, close_frame).await;
+}
+
+///
+/// 校验握手请求，通过后交给升级前钩子决定是否接受
+///
+async fn upgrade(request: &HandshakeRequest, context: &ServerContext) -> Result<Upgrade, HandshakeError> {
+    let mut upgrade = accept(request, context)?;
+    if let Some(hook) = &context.upgrade_hook {
+        upgrade.user_data = hook.before_upgrade(request).await.map_err(HandshakeError::Rejected)?;
+    }
+    Ok(upgrade)
+}
+
+///
+/// 校验握手请求并协商子协议
+///
+fn accept(request: &HandshakeRequest, context: &ServerContext) -> Result<Upgrade, HandshakeError> {
+    let key = request.validate()?.to_string();
+    let (handler, params) = if context.router.is_empty() {
+        (context.handler.clone(), PathParams::default())
     } else {
-        return String::from_utf8(decoded.expect("decoded data is None").to_vec()).ok();
+        context.router
+            .find(request.path())
+            .ok_or_else(|| HandshakeError::NotFound(request.path().into()))?
+    };
+    if !context.config.origin_allowed(request.header("Origin")) {
+        return Err(HandshakeError::Forbidden(format!(
+            "origin not allowed: {}",
+            request.header("Origin").unwrap_or("<none>")
+        )));
+    }
+    let offered = request.protocols();
+    let protocol = offered
+        .iter()
+        .filter_map(|name| WebsocketSubProtocols::from_name(name))
+        .filter(|p| context.protocols.contains(p))
+        .min_by_key(|p| context.protocols.iter().position(|s| s == p));
+    if protocol.is_none() && !offered.is_empty() && context.protocol_policy == ProtocolPolicy::Reject {
+        return Err(HandshakeError::BadRequest(format!(
+            "unsupported subprotocols: {}",
+            offered.join(", ")
+        )));
+    }
+    let deflate = context.deflate
+        .as_ref()
+        .and_then(|config| deflate::negotiate(&request.extensions(), config));
+    Ok(Upgrade { key, handler, params, protocol, deflate, user_data: None })
+}
+
+///
+/// 把发送队列中的消息写入 socket，编码失败时通过 on_error 通知处理器
+///
+async fn write_messages(
+    mut writer: OwnedWriteHalf,
+    mut receiver: QueueReceiver,
+    mut deflater: Option<Deflater>,
+    handler: Arc<dyn WebsocketHandler>,
+    sender: WebsocketSender,
+) {
+    while let Some(msg) = receiver.recv().await {
+        let is_close = matches!(msg, Message::Close(_));
+        let data = match frame::encode_message(&msg, deflater.as_mut(), false) {
+            Ok(data) => data,
+            Err(e) => {
+                handler.on_error(&sender, e).await;
+                break;
+            }
+        };
+        if writer.write_all(&data).await.is_err() || is_close {
+            break;
+        }
     }
-    None
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::websocket::client::{ClientConfig, WebsocketClient};
+    use crate::websocket::handshake::Rejection;
+    use crate::websocket::http::HttpResponse;
+    use sha1::Digest;
+    use tokio::io::AsyncReadExt;
 
     #[test]
     fn test() {
@@ -227,4 +614,523 @@ mod tests {
         let sec_accept = base64::encode(result);
         println!("{}", sec_accept);
     }
+
+    const UPGRADE: &str = "GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n";
+
+    fn echo_context() -> ServerContext {
+        ServerContext {
+            handler: Arc::new(SyncHandler(EchoHandler)),
+            registry: ConnectionRegistry::new(),
+            protocols: vec![],
+            protocol_policy: ProtocolPolicy::Accept,
+            deflate: None,
+            config: ServerConfig::default(),
+            upgrade_hook: None,
+            router: Router::new(),
+            http_handler: None,
+            connections: None,
+        }
+    }
+
+    ///
+    /// 在随机端口上接受一个连接并交给 handle_connection 处理
+    ///
+    async fn serve_once(context: ServerContext) -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            handle_connection(socket, Arc::new(context)).await;
+        });
+        addr
+    }
+
+    async fn read_head(client: &mut TcpStream) -> String {
+        let mut head = vec![];
+        let mut byte = [0; 1];
+        while !head.ends_with(b"\r\n\r\n") {
+            assert_eq!(client.read(&mut byte).await.unwrap(), 1);
+            head.push(byte[0]);
+        }
+        String::from_utf8(head).unwrap()
+    }
+
+    fn mask_frame(head: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [1_u8, 2, 3, 4];
+        let mut data = vec![head, 0x80 | payload.len() as u8];
+        data.extend_from_slice(&mask);
+        data.extend(payload.iter().enumerate().map(|(i, v)| v ^ mask[i % 4]));
+        data
+    }
+
+    #[tokio::test]
+    async fn test_handler_receives_messages() {
+        let addr = serve_once(echo_context()).await;
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(UPGRADE.as_bytes()).await.unwrap();
+        let response = read_head(&mut client).await;
+        assert!(response.starts_with("HTTP/1.1 101"));
+        let mut buf = [0; 1024];
+
+        client.write_all(&mask_frame(0x81, b"hello")).await.unwrap();
+        let n = client.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[0..n], &[0x81, 5, b'h', b'e', b'l', b'l', b'o']);
+
+        client.write_all(&mask_frame(0x88, &1000_u16.to_be_bytes())).await.unwrap();
+        let n = client.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[0..n], &[0x88, 2, 0x03, 0xe8]);
+    }
+
+    #[tokio::test]
+    async fn test_handshake_split_across_reads() {
+        let addr = serve_once(echo_context()).await;
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\nhost: localhost\r\nupgrade: websocket\r\nconnection: Upgrade\r\nsec-websocket-").await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        let mut data = b"key: dGhlIHNhbXBsZSBub25jZQ==\r\nsec-websocket-version: 13\r\n\r\n".to_vec();
+        data.extend(mask_frame(0x81, b"hi"));
+        client.write_all(&data).await.unwrap();
+
+        let mut response = vec![];
+        let mut buf = [0; 1024];
+        while !response.ends_with(&[0x81, 2, b'h', b'i']) {
+            let n = client.read(&mut buf).await.unwrap();
+            assert!(n > 0);
+            response.extend_from_slice(&buf[0..n]);
+        }
+        assert!(response.starts_with(b"HTTP/1.1 101 Switching Protocols\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_handshake_rejected() {
+        let addr = serve_once(echo_context()).await;
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Version: 13\r\n\r\n").await.unwrap();
+        let mut response = vec![];
+        client.read_to_end(&mut response).await.unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 400 Bad Request\r\n"));
+    }
+
+    struct ProtocolReporter;
+
+    impl SyncWebsocketHandler for ProtocolReporter {
+        fn on_open(&self, sender: &WebsocketSender) {
+            let name = sender.protocol().map(|p| p.as_str()).unwrap_or("none");
+            sender.text(name).unwrap();
+        }
+
+        fn on_message(&self, _sender: &WebsocketSender, _msg: Message) {}
+    }
+
+    fn protocol_context(policy: ProtocolPolicy) -> ServerContext {
+        ServerContext {
+            handler: Arc::new(SyncHandler(ProtocolReporter)),
+            protocols: vec![WebsocketSubProtocols::WAMP, WebsocketSubProtocols::MQTT],
+            protocol_policy: policy,
+            ..echo_context()
+        }
+    }
+
+    async fn upgrade_with_protocols(context: ServerContext, offered: &str) -> (String, Vec<u8>) {
+        let addr = serve_once(context).await;
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let request = UPGRADE.replace("\r\n\r\n", &format!("\r\nSec-WebSocket-Protocol: {}\r\n\r\n", offered));
+        client.write_all(request.as_bytes()).await.unwrap();
+        let head = read_head(&mut client).await;
+        let mut rest = vec![];
+        if head.starts_with("HTTP/1.1 101") {
+            let mut buf = [0; 2];
+            client.read_exact(&mut buf).await.unwrap();
+            rest.resize(buf[1] as usize, 0);
+            client.read_exact(&mut rest).await.unwrap();
+        }
+        (head, rest)
+    }
+
+    #[tokio::test]
+    async fn test_subprotocol_negotiation() {
+        // 按服务端的优先级选择
+        let (head, name) = upgrade_with_protocols(protocol_context(ProtocolPolicy::Reject), "soap, mqtt, wamp.2.json").await;
+        assert!(head.contains("\r\nSec-WebSocket-Protocol: wamp.2.json\r\n"));
+        assert_eq!(name, b"wamp.2.json");
+
+        let (head, name) = upgrade_with_protocols(protocol_context(ProtocolPolicy::Reject), "chat, mqtt").await;
+        assert!(head.contains("\r\nSec-WebSocket-Protocol: mqtt\r\n"));
+        assert_eq!(name, b"mqtt");
+    }
+
+    #[tokio::test]
+    async fn test_unknown_subprotocol_policy() {
+        let (head, _) = upgrade_with_protocols(protocol_context(ProtocolPolicy::Reject), "chat, soap").await;
+        assert!(head.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+
+        let (head, name) = upgrade_with_protocols(protocol_context(ProtocolPolicy::Accept), "chat, soap").await;
+        assert!(head.starts_with("HTTP/1.1 101"));
+        assert!(!head.contains("Sec-WebSocket-Protocol"));
+        assert_eq!(name, b"none");
+    }
+
+    #[tokio::test]
+    async fn test_permessage_deflate() {
+        let context = ServerContext {
+            deflate: Some(DeflateConfig::default()),
+            ..echo_context()
+        };
+        let addr = serve_once(context).await;
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let request = UPGRADE.replace(
+            "\r\n\r\n",
+            "\r\nSec-WebSocket-Extensions: permessage-deflate; client_max_window_bits, permessage-deflate\r\n\r\n",
+        );
+        client.write_all(request.as_bytes()).await.unwrap();
+        let head = read_head(&mut client).await;
+        assert!(head.contains("\r\nSec-WebSocket-Extensions: permessage-deflate\r\n"));
+
+        let mut deflater = Deflater::new(false);
+        let compressed = deflater.compress(b"Hello").unwrap();
+        client.write_all(&mask_frame(0xc1, &compressed)).await.unwrap();
+
+        let mut head = [0; 2];
+        client.read_exact(&mut head).await.unwrap();
+        assert_eq!(head[0], 0xc1);
+        let mut payload = vec![0; head[1] as usize];
+        client.read_exact(&mut payload).await.unwrap();
+        let mut inflater = Inflater::new(false);
+        assert_eq!(inflater.decompress(&payload).unwrap(), b"Hello");
+    }
+
+    #[tokio::test]
+    async fn test_rsv1_without_extension() {
+        let addr = serve_once(echo_context()).await;
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(UPGRADE.as_bytes()).await.unwrap();
+        let head = read_head(&mut client).await;
+        assert!(!head.contains("Sec-WebSocket-Extensions"));
+
+        client.write_all(&mask_frame(0xc1, &[0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00])).await.unwrap();
+        let mut rest = vec![];
+        client.read_to_end(&mut rest).await.unwrap();
+        assert!(!rest.starts_with(&[0xc1]));
+    }
+
+    #[tokio::test]
+    async fn test_with_client() {
+        let context = ServerContext {
+            protocols: vec![WebsocketSubProtocols::WAMP],
+            deflate: Some(DeflateConfig::default()),
+            ..echo_context()
+        };
+        let addr = serve_once(context).await;
+        let config = ClientConfig {
+            protocols: vec![WebsocketSubProtocols::MQTT, WebsocketSubProtocols::WAMP],
+            deflate: Some(DeflateConfig::default()),
+            ..ClientConfig::default()
+        };
+        let mut client = WebsocketClient::connect_with(&format!("ws://{}/", addr), config).await.unwrap();
+        assert_eq!(client.protocol(), Some(WebsocketSubProtocols::WAMP));
+        assert!(client.is_compressed());
+
+        for text in ["first", "second", "third"] {
+            client.text(text).await.unwrap();
+            assert_eq!(client.recv().await.unwrap(), Some(Message::Text(text.into())));
+        }
+        assert!(client.close(1000, "").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_large_messages() {
+        let addr = serve_once(echo_context()).await;
+        let mut client = WebsocketClient::connect(&format!("ws://{}/", addr)).await.unwrap();
+        for size in [0, 125, 126, 65535, 65536, 200_000] {
+            let text = "x".repeat(size);
+            client.text(text.clone()).await.unwrap();
+            assert_eq!(client.recv().await.unwrap(), Some(Message::Text(text)));
+        }
+    }
+
+    async fn serve_built(builder: WebsocketServerBuilder) -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = builder.build();
+        tokio::spawn(async move { server.serve(listener).await });
+        addr
+    }
+
+    async fn upgrade_status(addr: std::net::SocketAddr, request: &str) -> String {
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(request.as_bytes()).await.unwrap();
+        let head = read_head(&mut client).await;
+        head.lines().next().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn test_allowed_origins() {
+        let builder = WebsocketServer::builder().allowed_origins(vec!["https://example.com"]);
+        let addr = serve_built(builder).await;
+
+        let with_origin = |origin: &str| UPGRADE.replace("\r\n\r\n", &format!("\r\nOrigin: {}\r\n\r\n", origin));
+        assert_eq!(upgrade_status(addr, &with_origin("https://EXAMPLE.com")).await, "HTTP/1.1 101 Switching Protocols");
+        assert_eq!(upgrade_status(addr, &with_origin("https://evil.com")).await, "HTTP/1.1 403 Forbidden");
+        assert_eq!(upgrade_status(addr, UPGRADE).await, "HTTP/1.1 403 Forbidden");
+    }
+
+    #[tokio::test]
+    async fn test_max_connections() {
+        let addr = serve_built(WebsocketServer::builder().max_connections(1)).await;
+        let url = format!("ws://{}/", addr);
+
+        let mut first = WebsocketClient::connect(&url).await.unwrap();
+        assert_eq!(upgrade_status(addr, UPGRADE).await, "HTTP/1.1 503 Service Unavailable");
+
+        first.close(1000, "").await.unwrap();
+        drop(first);
+        // 连接任务退出后许可才会归还
+        let mut second = None;
+        for _ in 0..50 {
+            if let Ok(client) = WebsocketClient::connect(&url).await {
+                second = Some(client);
+                break;
+            }
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        let mut second = second.expect("connection slot was not released");
+        second.text("again").await.unwrap();
+        assert_eq!(second.recv().await.unwrap(), Some(Message::Text("again".into())));
+    }
+
+    #[tokio::test]
+    async fn test_handshake_timeout() {
+        let builder = WebsocketServer::builder().handshake_timeout(Duration::from_millis(50));
+        let addr = serve_built(builder).await;
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n").await.unwrap();
+        let mut response = vec![];
+        client.read_to_end(&mut response).await.unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 408 Request Timeout\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_frame_and_message_limits() {
+        let builder = WebsocketServer::builder()
+            .max_frame_size(1024)
+            .max_message_size(4096)
+            .read_buffer_size(16)
+            .permessage_deflate(DeflateConfig::default());
+        let addr = serve_built(builder).await;
+        let url = format!("ws://{}/", addr);
+
+        // 小于限制的消息照常回显，较小的读缓冲区需要多次读取
+        let mut client = WebsocketClient::connect(&url).await.unwrap();
+        client.binary(vec![7; 1024]).await.unwrap();
+        assert_eq!(client.recv().await.unwrap(), Some(Message::Binary(vec![7; 1024])));
+
+        // 只发送帧头，服务端不必等待负载就应以 1009 关闭
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(UPGRADE.as_bytes()).await.unwrap();
+        read_head(&mut client).await;
+        client.write_all(&[0x82, 0xfe, 0x04, 0x01, 1, 2, 3, 4]).await.unwrap();
+        let mut rest = vec![];
+        client.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(&rest[..4], &[0x88, 17, 0x03, 0xf1]);
+
+        // 压缩后很小、解压后超过限制的消息同样被拒绝
+        let config = ClientConfig { deflate: Some(DeflateConfig::default()), ..ClientConfig::default() };
+        let mut client = WebsocketClient::connect_with(&url, config).await.unwrap();
+        client.binary(vec![0; 8192]).await.unwrap();
+        match client.recv().await.unwrap() {
+            Some(Message::Close(Some(frame))) => assert_eq!(frame.code, 1009),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    struct User {
+        name: String,
+    }
+
+    struct Greeter;
+
+    impl SyncWebsocketHandler for Greeter {
+        fn on_open(&self, sender: &WebsocketSender) {
+            let name = sender.user_data::<User>().map(|user| user.name.as_str()).unwrap_or("anonymous");
+            sender.text(format!("hello {}", name)).unwrap();
+        }
+
+        fn on_message(&self, sender: &WebsocketSender, _msg: Message) {
+            assert!(sender.user_data::<String>().is_none());
+            sender.text(sender.user_data::<User>().unwrap().name.clone()).unwrap();
+        }
+    }
+
+    fn authenticate(request: &HandshakeRequest) -> Result<Option<UserData>, Rejection> {
+        if request.header("Authorization") != Some("Bearer secret") {
+            return Err(Rejection::unauthorized("invalid token").header("WWW-Authenticate", "Bearer"));
+        }
+        let name = request
+            .query()
+            .and_then(|query| query.strip_prefix("name="))
+            .unwrap_or("anonymous");
+        Ok(Some(Arc::new(User { name: name.into() })))
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_hook() {
+        let builder = WebsocketServer::builder().sync_handler(Greeter).before_upgrade(authenticate);
+        let addr = serve_built(builder).await;
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(UPGRADE.as_bytes()).await.unwrap();
+        let mut response = vec![];
+        client.read_to_end(&mut response).await.unwrap();
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+        assert!(response.contains("\r\nWWW-Authenticate: Bearer\r\n"));
+
+        let config = ClientConfig {
+            headers: vec![("Authorization".into(), "Bearer secret".into())],
+            ..ClientConfig::default()
+        };
+        let url = format!("ws://{}/chat?name=alice", addr);
+        let mut client = WebsocketClient::connect_with(&url, config).await.unwrap();
+        assert_eq!(client.recv().await.unwrap(), Some(Message::Text("hello alice".into())));
+        client.text("who").await.unwrap();
+        assert_eq!(client.recv().await.unwrap(), Some(Message::Text("alice".into())));
+    }
+
+    struct RoomGreeter;
+
+    impl SyncWebsocketHandler for RoomGreeter {
+        fn on_open(&self, sender: &WebsocketSender) {
+            let room = sender.param("room").unwrap();
+            sender.join(room);
+            sender.text(format!("joined {}", room)).unwrap();
+        }
+
+        fn on_message(&self, _sender: &WebsocketSender, _msg: Message) {}
+    }
+
+    #[tokio::test]
+    async fn test_path_routing() {
+        let builder = WebsocketServer::builder()
+            .sync_route("/chat", EchoHandler)
+            .sync_route("/rooms/:room", RoomGreeter);
+        let addr = serve_built(builder).await;
+
+        let mut chat = WebsocketClient::connect(&format!("ws://{}/chat?token=1", addr)).await.unwrap();
+        chat.text("echo").await.unwrap();
+        assert_eq!(chat.recv().await.unwrap(), Some(Message::Text("echo".into())));
+
+        let mut room = WebsocketClient::connect(&format!("ws://{}/rooms/rust", addr)).await.unwrap();
+        assert_eq!(room.recv().await.unwrap(), Some(Message::Text("joined rust".into())));
+
+        assert_eq!(upgrade_status(addr, UPGRADE).await, "HTTP/1.1 404 Not Found");
+        let request = UPGRADE.replace("GET / ", "GET /rooms ");
+        assert_eq!(upgrade_status(addr, &request).await, "HTTP/1.1 404 Not Found");
+    }
+
+    struct Flood {
+        depth: Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl SyncWebsocketHandler for Flood {
+        fn on_open(&self, sender: &WebsocketSender) {
+            // 测试运行在单线程 runtime 上，on_open 返回前写任务不会取走消息
+            for i in 0..10 {
+                let _ = sender.text(i.to_string());
+            }
+            self.depth.store(sender.queue_len(), std::sync::atomic::Ordering::SeqCst);
+        }
+
+        fn on_message(&self, sender: &WebsocketSender, msg: Message) {
+            let _ = sender.send(msg);
+        }
+    }
+
+    async fn flood(policy: OverflowPolicy) -> (Vec<Message>, usize) {
+        let depth = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let builder = WebsocketServer::builder()
+            .outbound_queue(4, policy)
+            .sync_handler(Flood { depth: depth.clone() });
+        let addr = serve_built(builder).await;
+        let mut client = WebsocketClient::connect(&format!("ws://{}/", addr)).await.unwrap();
+        let mut received = vec![];
+        for _ in 0..4 {
+            let msg = client.recv().await.unwrap().unwrap();
+            let is_close = matches!(msg, Message::Close(_));
+            received.push(msg);
+            if is_close {
+                break;
+            }
+        }
+        if received.len() == 4 {
+            client.text("end").await.unwrap();
+            received.push(client.recv().await.unwrap().unwrap());
+        }
+        (received, depth.load(std::sync::atomic::Ordering::SeqCst))
+    }
+
+    fn texts(items: &[&str]) -> Vec<Message> {
+        items.iter().map(|text| Message::Text(text.to_string())).collect()
+    }
+
+    #[tokio::test]
+    async fn test_outbound_queue_policies() {
+        let (received, depth) = flood(OverflowPolicy::DropOldest).await;
+        assert_eq!(received, texts(&["6", "7", "8", "9", "end"]));
+        assert_eq!(depth, 4);
+
+        let (received, depth) = flood(OverflowPolicy::DropNewest).await;
+        assert_eq!(received, texts(&["0", "1", "2", "3", "end"]));
+        assert_eq!(depth, 4);
+
+        let (received, depth) = flood(OverflowPolicy::Disconnect).await;
+        let close = CloseFrame { code: 1008, reason: "outbound queue overflow".into() };
+        assert_eq!(received, vec![Message::Close(Some(close))]);
+        assert_eq!(depth, 1);
+    }
+
+    fn site(request: &HandshakeRequest) -> HttpResponse {
+        match request.path() {
+            "/health" => HttpResponse::json(&serde_json::json!({ "status": "ok" })),
+            "/" => HttpResponse::html("<h1>websocket test page</h1>"),
+            _ => HttpResponse::not_found(),
+        }
+    }
+
+    async fn http_get(addr: std::net::SocketAddr, request: &str) -> String {
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(request.as_bytes()).await.unwrap();
+        let mut response = vec![];
+        client.read_to_end(&mut response).await.unwrap();
+        String::from_utf8(response).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_plain_http_on_same_port() {
+        let addr = serve_built(WebsocketServer::builder().http_handler(site)).await;
+
+        let response = http_get(addr, "GET /health HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("\r\nContent-Type: application/json\r\n"));
+        assert!(response.ends_with("\r\n\r\n{\"status\":\"ok\"}"));
+
+        let response = http_get(addr, "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert!(response.ends_with("<h1>websocket test page</h1>"));
+        let response = http_get(addr, "HEAD / HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("Connection: close\r\n\r\n"));
+        let response = http_get(addr, "GET /missing HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        // 升级请求仍然进入 websocket 处理
+        assert_eq!(upgrade_status(addr, UPGRADE).await, "HTTP/1.1 101 Switching Protocols");
+
+        // 未注册 HttpHandler 时普通请求返回 426
+        let addr = serve_built(WebsocketServer::builder()).await;
+        let response = http_get(addr, "GET /health HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 426 Upgrade Required\r\n"));
+    }
 }
@@ -0,0 +1,191 @@
+//!
+//! 基于 actor 的 websocket 会话
+//! 每个连接对应一个 Session actor，它持有连接的发送端，其他 actor 向它的地址
+//! 发送 Message 即可推送给客户端；收到的消息与连接的打开、关闭以 SessionEvent
+//! 依次投递给处理 actor。SessionHandler 把这种模型适配为 WebsocketHandler。
+//!
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use async_trait::async_trait;
+use crate::actor_model::actor::{self, Actor};
+use crate::actor_model::addr::Addr;
+use crate::actor_model::context::Context;
+use crate::websocket::error::WebsocketError;
+use crate::websocket::handler::{CloseFrame, Message, WebsocketHandler, WebsocketSender};
+use crate::websocket::registry::ConnectionId;
+
+///
+/// 代表一个连接的 actor，连接关闭后停止
+///
+pub struct Session {
+    sender: WebsocketSender,
+}
+
+impl Session {
+    pub fn id(&self) -> ConnectionId {
+        self.sender.id()
+    }
+}
+
+#[async_trait]
+impl Actor for Session {
+    type Message = Message;
+    type Reply = Result<(), WebsocketError>;
+
+    async fn handle(&mut self, msg: Message, ctx: &mut Context<Self>) -> Result<(), WebsocketError> {
+        let result = self.sender.send(msg);
+        if self.sender.is_closed() {
+            ctx.stop();
+        }
+        result
+    }
+}
+
+///
+/// 投递给处理 actor 的连接事件，同一个连接的事件按发生顺序到达
+///
+#[derive(Debug)]
+pub enum SessionEvent {
+    Opened { id: ConnectionId, session: Addr<Session> },
+    Message { id: ConnectionId, session: Addr<Session>, msg: Message },
+    Closed { id: ConnectionId, frame: Option<CloseFrame> },
+    Error { id: ConnectionId, error: WebsocketError },
+}
+
+///
+/// 为每个连接启动 Session，并把事件转发给处理 actor
+/// 处理 actor 已经停止时以 1011 关闭新的消息所在的连接
+///
+pub struct SessionHandler<H: Actor<Message = SessionEvent>> {
+    handler: Addr<H>,
+    sessions: Mutex<HashMap<ConnectionId, Addr<Session>>>,
+}
+
+impl<H: Actor<Message = SessionEvent>> SessionHandler<H> {
+    pub fn new(handler: Addr<H>) -> SessionHandler<H> {
+        SessionHandler { handler, sessions: Mutex::new(HashMap::new()) }
+    }
+
+    ///
+    /// 连接对应的会话地址，连接已经关闭时返回 None
+    ///
+    pub fn session(&self, id: ConnectionId) -> Option<Addr<Session>> {
+        self.sessions.lock().unwrap().get(&id).cloned()
+    }
+
+    pub fn sessions(&self) -> usize {
+        self.sessions.lock().unwrap().len()
+    }
+
+    async fn deliver(&self, sender: &WebsocketSender, event: SessionEvent) {
+        if self.handler.send(event).await.is_err() {
+            let _ = sender.close(1011, "handler stopped");
+        }
+    }
+}
+
+#[async_trait]
+impl<H: Actor<Message = SessionEvent>> WebsocketHandler for SessionHandler<H> {
+    async fn on_open(&self, sender: &WebsocketSender) {
+        let session = actor::spawn(Session { sender: sender.clone() });
+        self.sessions.lock().unwrap().insert(sender.id(), session.clone());
+        self.deliver(sender, SessionEvent::Opened { id: sender.id(), session }).await;
+    }
+
+    async fn on_message(&self, sender: &WebsocketSender, msg: Message) {
+        if let Some(session) = self.session(sender.id()) {
+            self.deliver(sender, SessionEvent::Message { id: sender.id(), session, msg }).await;
+        }
+    }
+
+    async fn on_close(&self, sender: &WebsocketSender, frame: Option<CloseFrame>) {
+        let session = self.sessions.lock().unwrap().remove(&sender.id());
+        if let Some(session) = session {
+            session.stop().await;
+        }
+        let _ = self.handler.send(SessionEvent::Closed { id: sender.id(), frame }).await;
+    }
+
+    async fn on_error(&self, sender: &WebsocketSender, error: WebsocketError) {
+        let _ = self.handler.send(SessionEvent::Error { id: sender.id(), error }).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+    use crate::websocket::client::WebsocketClient;
+    use crate::websocket::server::WebsocketServer;
+
+    ///
+    /// 把每条消息转发给所有在线会话
+    ///
+    #[derive(Default)]
+    struct Chat {
+        sessions: HashMap<ConnectionId, Addr<Session>>,
+        closed: Arc<Mutex<Vec<ConnectionId>>>,
+    }
+
+    #[async_trait]
+    impl Actor for Chat {
+        type Message = SessionEvent;
+        type Reply = ();
+
+        async fn handle(&mut self, event: SessionEvent, _ctx: &mut Context<Self>) {
+            match event {
+                SessionEvent::Opened { id, session } => {
+                    let _ = session.send(Message::Text(id.to_string())).await;
+                    self.sessions.insert(id, session);
+                }
+                SessionEvent::Message { id, msg: Message::Text(text), .. } => {
+                    for session in self.sessions.values() {
+                        let _ = session.send(Message::Text(format!("{}: {}", id, text))).await;
+                    }
+                }
+                SessionEvent::Closed { id, .. } => {
+                    self.sessions.remove(&id);
+                    self.closed.lock().unwrap().push(id);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    async fn join(url: &str) -> (WebsocketClient, ConnectionId) {
+        let mut client = WebsocketClient::connect(url).await.unwrap();
+        match client.recv().await.unwrap() {
+            Some(Message::Text(id)) => (client, id.parse().unwrap()),
+            other => panic!("unexpected message: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_actor_sessions() {
+        let chat = Chat::default();
+        let closed = chat.closed.clone();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/", listener.local_addr().unwrap());
+        let mut server = WebsocketServer::new("127.0.0.1".into(), 0);
+        server.handler(SessionHandler::new(actor::spawn(chat)));
+        tokio::spawn(async move { server.serve(listener).await });
+
+        let (mut first, first_id) = join(&url).await;
+        let (mut second, second_id) = join(&url).await;
+        first.text("hi").await.unwrap();
+        let expected = Some(Message::Text(format!("{}: hi", first_id)));
+        assert_eq!(first.recv().await.unwrap(), expected);
+        assert_eq!(second.recv().await.unwrap(), expected);
+
+        // 关闭的连接不再收到消息
+        first.close(1000, "").await.unwrap();
+        while closed.lock().unwrap().is_empty() {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(*closed.lock().unwrap(), vec![first_id]);
+        second.text("bye").await.unwrap();
+        assert_eq!(second.recv().await.unwrap(), Some(Message::Text(format!("{}: bye", second_id))));
+    }
+}
@@ -0,0 +1,644 @@
+//!
+//! WAMP v2 basic profile 路由（https://wamp-proto.org/spec.html），使用 wamp.2.json 子协议
+//! 路由同时扮演 Broker（PUBLISH/SUBSCRIBE）与 Dealer（CALL/REGISTER/YIELD）两个角色，
+//! 会话通过 HELLO/WELCOME 加入某个 realm，不同 realm 之间的主题与过程互不可见。
+//!
+//! 作为普通的处理器挂在 WebsocketServer 的路由上：
+//!
+//! ```ignore
+//! let router = WampRouter::new(vec!["realm1"]);
+//! WebsocketServer::builder()
+//!     .protocols(vec![WebsocketSubProtocols::WAMP])
+//!     .protocol_policy(ProtocolPolicy::Reject)
+//!     .sync_route("/ws", router.clone())
+//!     .build();
+//! ```
+//!
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use rand::Rng;
+use serde_json::{json, Map, Value};
+use crate::websocket::handler::{CloseFrame, Message, SyncWebsocketHandler, WebsocketSender};
+use crate::websocket::registry::ConnectionId;
+use crate::websocket::server::WebsocketSubProtocols;
+
+pub const HELLO: u64 = 1;
+pub const WELCOME: u64 = 2;
+pub const ABORT: u64 = 3;
+pub const GOODBYE: u64 = 6;
+pub const ERROR: u64 = 8;
+pub const PUBLISH: u64 = 16;
+pub const PUBLISHED: u64 = 17;
+pub const SUBSCRIBE: u64 = 32;
+pub const SUBSCRIBED: u64 = 33;
+pub const UNSUBSCRIBE: u64 = 34;
+pub const UNSUBSCRIBED: u64 = 35;
+pub const EVENT: u64 = 36;
+pub const CALL: u64 = 48;
+pub const RESULT: u64 = 50;
+pub const REGISTER: u64 = 64;
+pub const REGISTERED: u64 = 65;
+pub const UNREGISTER: u64 = 66;
+pub const UNREGISTERED: u64 = 67;
+pub const INVOCATION: u64 = 68;
+pub const YIELD: u64 = 70;
+
+///
+/// WAMP 的 id 取值范围为 [1, 2^53]，保证在 JavaScript 中精确表示
+///
+pub type Id = u64;
+
+const MAX_ID: Id = 1 << 53;
+
+fn random_id() -> Id {
+    rand::thread_rng().gen_range(1..=MAX_ID)
+}
+
+struct Session {
+    id: Id,
+    realm: String,
+    sender: WebsocketSender,
+}
+
+struct Subscription {
+    id: Id,
+    subscribers: HashSet<ConnectionId>,
+}
+
+struct Registration {
+    id: Id,
+    callee: ConnectionId,
+}
+
+///
+/// 已转发给被调用方、尚未收到 YIELD 或 ERROR 的调用
+///
+struct Invocation {
+    caller: ConnectionId,
+    request: Id,
+    callee: ConnectionId,
+}
+
+#[derive(Default)]
+struct Realm {
+    subscriptions: HashMap<String, Subscription>,
+    registrations: HashMap<String, Registration>,
+}
+
+#[derive(Default)]
+struct State {
+    realms: HashMap<String, Realm>,
+    sessions: HashMap<ConnectionId, Session>,
+    invocations: HashMap<Id, Invocation>,
+    next_request: Id,
+}
+
+///
+/// 处理单条消息时的结果，Err 表示对端违反了协议，会话会被中止
+///
+type Handled = Result<(), String>;
+
+///
+/// WAMP 路由，可以 clone 后在服务外部调用 publish 推送事件
+///
+#[derive(Clone)]
+pub struct WampRouter {
+    state: Arc<Mutex<State>>,
+}
+
+impl WampRouter {
+    ///
+    /// 创建路由，只接受加入给定的 realm
+    ///
+    pub fn new<I, S>(realms: I) -> WampRouter
+        where
+            I: IntoIterator<Item=S>,
+            S: Into<String>
+    {
+        let state = State {
+            realms: realms.into_iter().map(|realm| (realm.into(), Realm::default())).collect(),
+            ..State::default()
+        };
+        WampRouter { state: Arc::new(Mutex::new(state)) }
+    }
+
+    ///
+    /// 由服务端向 realm 中的主题发布事件，返回收到事件的会话数
+    ///
+    pub fn publish(&self, realm: &str, topic: &str, arguments: Vec<Value>) -> usize {
+        let state = self.state.lock().unwrap();
+        let subscription = match state.realms.get(realm).and_then(|realm| realm.subscriptions.get(topic)) {
+            Some(subscription) => subscription,
+            None => return 0,
+        };
+        let event = json!([EVENT, subscription.id, random_id(), {}, arguments]);
+        subscription.subscribers
+            .iter()
+            .filter_map(|id| state.sessions.get(id))
+            .filter(|session| send(&session.sender, &event))
+            .count()
+    }
+
+    ///
+    /// realm 中已建立的会话数
+    ///
+    pub fn session_count(&self, realm: &str) -> usize {
+        self.state.lock().unwrap().sessions.values().filter(|session| session.realm == realm).count()
+    }
+
+    fn handle(&self, sender: &WebsocketSender, msg: &[Value]) -> Handled {
+        let code = msg.first().and_then(Value::as_u64).ok_or("message type must be an integer")?;
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        if !state.sessions.contains_key(&sender.id()) {
+            return match code {
+                HELLO => hello(state, sender, msg),
+                _ => Err(format!("message type {} received before HELLO", code)),
+            };
+        }
+        match code {
+            HELLO => Err("HELLO received in an established session".into()),
+            GOODBYE => {
+                leave(state, sender.id());
+                send(sender, &json!([GOODBYE, {}, "wamp.close.goodbye_and_out"]));
+                Ok(())
+            }
+            SUBSCRIBE => subscribe(state, sender, msg),
+            UNSUBSCRIBE => unsubscribe(state, sender, msg),
+            PUBLISH => publish(state, sender, msg),
+            REGISTER => register(state, sender, msg),
+            UNREGISTER => unregister(state, sender, msg),
+            CALL => call(state, sender, msg),
+            YIELD => yield_result(state, sender, msg),
+            ERROR => invocation_error(state, sender, msg),
+            _ => Err(format!("unexpected message type {}", code)),
+        }
+    }
+
+    fn abort(&self, sender: &WebsocketSender, reason: &str, message: &str) {
+        leave(&mut self.state.lock().unwrap(), sender.id());
+        send(sender, &json!([ABORT, {"message": message}, reason]));
+        let _ = sender.close(1000, "");
+    }
+}
+
+impl SyncWebsocketHandler for WampRouter {
+    fn on_open(&self, sender: &WebsocketSender) {
+        if sender.protocol() != Some(WebsocketSubProtocols::WAMP) {
+            let _ = sender.close(1002, "wamp.2.json subprotocol required");
+        }
+    }
+
+    fn on_message(&self, sender: &WebsocketSender, msg: Message) {
+        let parsed = match msg {
+            Message::Text(text) => match serde_json::from_str::<Value>(&text) {
+                Ok(Value::Array(items)) => Ok(items),
+                _ => Err("message must be a JSON array".to_string()),
+            },
+            _ => Err("binary messages are not used by wamp.2.json".to_string()),
+        };
+        if let Err(message) = parsed.and_then(|msg| self.handle(sender, &msg)) {
+            self.abort(sender, "wamp.error.protocol_violation", &message);
+        }
+    }
+
+    fn on_close(&self, sender: &WebsocketSender, _frame: Option<CloseFrame>) {
+        leave(&mut self.state.lock().unwrap(), sender.id());
+    }
+}
+
+fn send(sender: &WebsocketSender, msg: &Value) -> bool {
+    sender.text(msg.to_string()).is_ok()
+}
+
+fn send_to(state: &State, id: ConnectionId, msg: &Value) {
+    if let Some(session) = state.sessions.get(&id) {
+        send(&session.sender, msg);
+    }
+}
+
+fn send_error(sender: &WebsocketSender, request_type: u64, request: Id, error: &str) {
+    send(sender, &json!([ERROR, request_type, request, {}, error]));
+}
+
+fn id_at(msg: &[Value], index: usize) -> Result<Id, String> {
+    msg.get(index)
+        .and_then(Value::as_u64)
+        .filter(|id| (1..=MAX_ID).contains(id))
+        .ok_or_else(|| format!("element {} must be an id", index))
+}
+
+fn dict_at(msg: &[Value], index: usize) -> Result<&Map<String, Value>, String> {
+    msg.get(index).and_then(Value::as_object).ok_or_else(|| format!("element {} must be a dict", index))
+}
+
+fn str_at(msg: &[Value], index: usize) -> Result<&str, String> {
+    msg.get(index).and_then(Value::as_str).ok_or_else(|| format!("element {} must be a string", index))
+}
+
+///
+/// 从 index 开始可选的 Arguments|list 与 ArgumentsKw|dict，原样转发给对端
+///
+fn payload_at(msg: &[Value], index: usize) -> Result<Vec<Value>, String> {
+    let payload = msg.get(index..).unwrap_or_default();
+    match payload {
+        [] | [Value::Array(_)] | [Value::Array(_), Value::Object(_)] => Ok(payload.to_vec()),
+        _ => Err("arguments must be a list followed by an optional dict".into()),
+    }
+}
+
+///
+/// 宽松的 URI 校验：由 `.` 分隔的非空片段，不含空白与 `#`
+///
+fn is_valid_uri(uri: &str) -> bool {
+    uri.split('.').all(|part| !part.is_empty() && !part.chars().any(|c| c.is_whitespace() || c == '#'))
+}
+
+fn with_payload(mut msg: Vec<Value>, payload: Vec<Value>) -> Value {
+    msg.extend(payload);
+    Value::Array(msg)
+}
+
+fn hello(state: &mut State, sender: &WebsocketSender, msg: &[Value]) -> Handled {
+    let realm = str_at(msg, 1)?;
+    dict_at(msg, 2)?;
+    if !state.realms.contains_key(realm) {
+        send(sender, &json!([ABORT, {"message": format!("realm {} does not exist", realm)}, "wamp.error.no_such_realm"]));
+        let _ = sender.close(1000, "");
+        return Ok(());
+    }
+    let id = loop {
+        let id = random_id();
+        if state.sessions.values().all(|session| session.id != id) {
+            break id;
+        }
+    };
+    state.sessions.insert(sender.id(), Session { id, realm: realm.into(), sender: sender.clone() });
+    send(sender, &json!([WELCOME, id, {"roles": {"broker": {}, "dealer": {}}}]));
+    Ok(())
+}
+
+///
+/// 会话结束时清理它的订阅、注册与未完成的调用
+///
+fn leave(state: &mut State, id: ConnectionId) {
+    let session = match state.sessions.remove(&id) {
+        Some(session) => session,
+        None => return,
+    };
+    if let Some(realm) = state.realms.get_mut(&session.realm) {
+        realm.subscriptions.retain(|_, subscription| {
+            subscription.subscribers.remove(&id);
+            !subscription.subscribers.is_empty()
+        });
+        realm.registrations.retain(|_, registration| registration.callee != id);
+    }
+    let lost: Vec<Id> = state.invocations
+        .iter()
+        .filter(|(_, invocation)| invocation.caller == id || invocation.callee == id)
+        .map(|(request, _)| *request)
+        .collect();
+    for request in lost {
+        let invocation = state.invocations.remove(&request).expect("invocation exists");
+        if invocation.callee == id {
+            send_to(state, invocation.caller, &json!([ERROR, CALL, invocation.request, {}, "wamp.error.canceled"]));
+        }
+    }
+}
+
+fn realm_of<'a>(state: &'a mut State, sender: &WebsocketSender) -> &'a mut Realm {
+    let realm = &state.sessions[&sender.id()].realm;
+    state.realms.get_mut(realm).expect("session realm exists")
+}
+
+fn subscribe(state: &mut State, sender: &WebsocketSender, msg: &[Value]) -> Handled {
+    let request = id_at(msg, 1)?;
+    let options = dict_at(msg, 2)?;
+    let topic = str_at(msg, 3)?;
+    if !is_valid_uri(topic) {
+        send_error(sender, SUBSCRIBE, request, "wamp.error.invalid_uri");
+        return Ok(());
+    }
+    if matches!(options.get("match"), Some(m) if m != "exact") {
+        send_error(sender, SUBSCRIBE, request, "wamp.error.option_not_allowed");
+        return Ok(());
+    }
+    // 同一 realm 中订阅同一主题的会话共享订阅 id
+    let subscription = realm_of(state, sender)
+        .subscriptions
+        .entry(topic.into())
+        .or_insert_with(|| Subscription { id: random_id(), subscribers: HashSet::new() });
+    subscription.subscribers.insert(sender.id());
+    send(sender, &json!([SUBSCRIBED, request, subscription.id]));
+    Ok(())
+}
+
+fn unsubscribe(state: &mut State, sender: &WebsocketSender, msg: &[Value]) -> Handled {
+    let request = id_at(msg, 1)?;
+    let subscription = id_at(msg, 2)?;
+    let realm = realm_of(state, sender);
+    let topic = realm.subscriptions
+        .iter()
+        .find(|(_, s)| s.id == subscription && s.subscribers.contains(&sender.id()))
+        .map(|(topic, _)| topic.clone());
+    match topic {
+        Some(topic) => {
+            let subscribers = &mut realm.subscriptions.get_mut(&topic).expect("subscription exists").subscribers;
+            subscribers.remove(&sender.id());
+            if subscribers.is_empty() {
+                realm.subscriptions.remove(&topic);
+            }
+            send(sender, &json!([UNSUBSCRIBED, request]));
+        }
+        None => send_error(sender, UNSUBSCRIBE, request, "wamp.error.no_such_subscription"),
+    }
+    Ok(())
+}
+
+fn publish(state: &mut State, sender: &WebsocketSender, msg: &[Value]) -> Handled {
+    let request = id_at(msg, 1)?;
+    let options = dict_at(msg, 2)?;
+    let topic = str_at(msg, 3)?;
+    let payload = payload_at(msg, 4)?;
+    let acknowledge = options.get("acknowledge").and_then(Value::as_bool).unwrap_or(false);
+    if !is_valid_uri(topic) {
+        if acknowledge {
+            send_error(sender, PUBLISH, request, "wamp.error.invalid_uri");
+        }
+        return Ok(());
+    }
+    // 默认不把事件发回给发布者
+    let exclude_me = options.get("exclude_me").and_then(Value::as_bool).unwrap_or(true);
+    let publication = random_id();
+    let realm = &state.sessions[&sender.id()].realm;
+    if let Some(subscription) = state.realms[realm].subscriptions.get(topic) {
+        let event = with_payload(vec![json!(EVENT), json!(subscription.id), json!(publication), json!({})], payload);
+        for id in &subscription.subscribers {
+            if *id != sender.id() || !exclude_me {
+                send_to(state, *id, &event);
+            }
+        }
+    }
+    if acknowledge {
+        send(sender, &json!([PUBLISHED, request, publication]));
+    }
+    Ok(())
+}
+
+fn register(state: &mut State, sender: &WebsocketSender, msg: &[Value]) -> Handled {
+    let request = id_at(msg, 1)?;
+    dict_at(msg, 2)?;
+    let procedure = str_at(msg, 3)?;
+    if !is_valid_uri(procedure) || procedure.starts_with("wamp.") {
+        send_error(sender, REGISTER, request, "wamp.error.invalid_uri");
+        return Ok(());
+    }
+    let realm = realm_of(state, sender);
+    if realm.registrations.contains_key(procedure) {
+        send_error(sender, REGISTER, request, "wamp.error.procedure_already_exists");
+        return Ok(());
+    }
+    let registration = random_id();
+    realm.registrations.insert(procedure.into(), Registration { id: registration, callee: sender.id() });
+    send(sender, &json!([REGISTERED, request, registration]));
+    Ok(())
+}
+
+fn unregister(state: &mut State, sender: &WebsocketSender, msg: &[Value]) -> Handled {
+    let request = id_at(msg, 1)?;
+    let registration = id_at(msg, 2)?;
+    let realm = realm_of(state, sender);
+    let procedure = realm.registrations
+        .iter()
+        .find(|(_, r)| r.id == registration && r.callee == sender.id())
+        .map(|(procedure, _)| procedure.clone());
+    match procedure {
+        Some(procedure) => {
+            realm.registrations.remove(&procedure);
+            send(sender, &json!([UNREGISTERED, request]));
+        }
+        None => send_error(sender, UNREGISTER, request, "wamp.error.no_such_registration"),
+    }
+    Ok(())
+}
+
+fn call(state: &mut State, sender: &WebsocketSender, msg: &[Value]) -> Handled {
+    let request = id_at(msg, 1)?;
+    dict_at(msg, 2)?;
+    let procedure = str_at(msg, 3)?;
+    let payload = payload_at(msg, 4)?;
+    let registration = match realm_of(state, sender).registrations.get(procedure) {
+        Some(registration) => (registration.id, registration.callee),
+        None => {
+            send_error(sender, CALL, request, "wamp.error.no_such_procedure");
+            return Ok(());
+        }
+    };
+    let (registration, callee) = registration;
+    state.next_request = state.next_request % MAX_ID + 1;
+    let invocation = state.next_request;
+    state.invocations.insert(invocation, Invocation { caller: sender.id(), request, callee });
+    let msg = vec![json!(INVOCATION), json!(invocation), json!(registration), json!({})];
+    send_to(state, callee, &with_payload(msg, payload));
+    Ok(())
+}
+
+fn take_invocation(state: &mut State, sender: &WebsocketSender, request: Id) -> Option<Invocation> {
+    match state.invocations.get(&request) {
+        Some(invocation) if invocation.callee == sender.id() => state.invocations.remove(&request),
+        _ => None,
+    }
+}
+
+fn yield_result(state: &mut State, sender: &WebsocketSender, msg: &[Value]) -> Handled {
+    let request = id_at(msg, 1)?;
+    dict_at(msg, 2)?;
+    let payload = payload_at(msg, 3)?;
+    // 调用方可能已经断开，此时丢弃结果
+    if let Some(invocation) = take_invocation(state, sender, request) {
+        let result = vec![json!(RESULT), json!(invocation.request), json!({})];
+        send_to(state, invocation.caller, &with_payload(result, payload));
+    }
+    Ok(())
+}
+
+fn invocation_error(state: &mut State, sender: &WebsocketSender, msg: &[Value]) -> Handled {
+    if id_at(msg, 1)? != INVOCATION {
+        return Err("ERROR is only accepted for INVOCATION".into());
+    }
+    let request = id_at(msg, 2)?;
+    dict_at(msg, 3)?;
+    let error = str_at(msg, 4)?;
+    let payload = payload_at(msg, 5)?;
+    if let Some(invocation) = take_invocation(state, sender, request) {
+        let msg = vec![json!(ERROR), json!(CALL), json!(invocation.request), json!({}), json!(error)];
+        send_to(state, invocation.caller, &with_payload(msg, payload));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::websocket::client::{ClientConfig, WebsocketClient};
+    use crate::websocket::server::{ProtocolPolicy, WebsocketServer};
+    use tokio::net::TcpListener;
+
+    async fn serve(router: WampRouter) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = WebsocketServer::builder()
+            .protocols(vec![WebsocketSubProtocols::WAMP])
+            .protocol_policy(ProtocolPolicy::Reject)
+            .sync_route("/ws", router)
+            .build();
+        tokio::spawn(async move { server.serve(listener).await });
+        format!("ws://{}/ws", addr)
+    }
+
+    async fn connect(url: &str) -> WebsocketClient {
+        let config = ClientConfig { protocols: vec![WebsocketSubProtocols::WAMP], ..ClientConfig::default() };
+        WebsocketClient::connect_with(url, config).await.unwrap()
+    }
+
+    async fn send(client: &mut WebsocketClient, msg: Value) {
+        client.text(msg.to_string()).await.unwrap();
+    }
+
+    async fn recv(client: &mut WebsocketClient) -> Value {
+        match client.recv().await.unwrap() {
+            Some(Message::Text(text)) => serde_json::from_str(&text).unwrap(),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    async fn join(url: &str, realm: &str) -> WebsocketClient {
+        let mut client = connect(url).await;
+        send(&mut client, json!([HELLO, realm, {"roles": {"publisher": {}, "subscriber": {}, "caller": {}, "callee": {}}}])).await;
+        let welcome = recv(&mut client).await;
+        assert_eq!(welcome[0], WELCOME);
+        assert!(welcome[1].as_u64().unwrap() >= 1);
+        assert!(welcome[2]["roles"]["broker"].is_object());
+        client
+    }
+
+    #[tokio::test]
+    async fn test_session_lifecycle() {
+        let router = WampRouter::new(vec!["realm1"]);
+        let url = serve(router.clone()).await;
+
+        let mut client = connect(&url).await;
+        send(&mut client, json!([HELLO, "unknown", {"roles": {}}])).await;
+        let abort = recv(&mut client).await;
+        assert_eq!((abort[0].as_u64(), abort[2].as_str()), (Some(ABORT), Some("wamp.error.no_such_realm")));
+
+        let mut client = connect(&url).await;
+        send(&mut client, json!([SUBSCRIBE, 1, {}, "com.example.topic"])).await;
+        let abort = recv(&mut client).await;
+        assert_eq!(abort[2], "wamp.error.protocol_violation");
+
+        let mut client = join(&url, "realm1").await;
+        assert_eq!(router.session_count("realm1"), 1);
+        send(&mut client, json!([GOODBYE, {}, "wamp.close.system_shutdown"])).await;
+        assert_eq!(recv(&mut client).await, json!([GOODBYE, {}, "wamp.close.goodbye_and_out"]));
+        assert_eq!(router.session_count("realm1"), 0);
+
+        // 没有协商 wamp.2.json 的连接直接被关闭
+        let mut raw = WebsocketClient::connect(&url).await.unwrap();
+        match raw.recv().await.unwrap() {
+            Some(Message::Close(Some(frame))) => assert_eq!(frame.code, 1002),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_publish_subscribe() {
+        let router = WampRouter::new(vec!["realm1", "realm2"]);
+        let url = serve(router.clone()).await;
+        let mut subscriber = join(&url, "realm1").await;
+        let mut publisher = join(&url, "realm1").await;
+        let mut other_realm = join(&url, "realm2").await;
+
+        send(&mut subscriber, json!([SUBSCRIBE, 1, {}, "com.example.topic"])).await;
+        let subscribed = recv(&mut subscriber).await;
+        assert_eq!((subscribed[0].as_u64(), subscribed[1].as_u64()), (Some(SUBSCRIBED), Some(1)));
+        let subscription = subscribed[2].as_u64().unwrap();
+
+        send(&mut publisher, json!([SUBSCRIBE, 1, {}, "com.example.topic"])).await;
+        assert_eq!(recv(&mut publisher).await[2], subscription);
+        send(&mut other_realm, json!([SUBSCRIBE, 1, {}, "com.example.topic"])).await;
+        assert_ne!(recv(&mut other_realm).await[2], subscription);
+
+        send(&mut publisher, json!([PUBLISH, 2, {"acknowledge": true}, "com.example.topic", [1, "two"], {"k": true}])).await;
+        let published = recv(&mut publisher).await;
+        assert_eq!(published[0], PUBLISHED);
+        let event = recv(&mut subscriber).await;
+        assert_eq!(event, json!([EVENT, subscription, published[2], {}, [1, "two"], {"k": true}]));
+
+        // exclude_me 为 false 时发布者也会收到
+        send(&mut publisher, json!([PUBLISH, 3, {"exclude_me": false}, "com.example.topic", ["again"]])).await;
+        assert_eq!(recv(&mut publisher).await[4], json!(["again"]));
+        assert_eq!(recv(&mut subscriber).await[4], json!(["again"]));
+
+        assert_eq!(router.publish("realm1", "com.example.topic", vec![json!("server")]), 2);
+        assert_eq!(recv(&mut subscriber).await[4], json!(["server"]));
+        assert_eq!(router.publish("realm1", "com.example.none", vec![]), 0);
+        // 其他 realm 的订阅者只收到本 realm 的事件
+        assert_eq!(router.publish("realm2", "com.example.topic", vec![json!("realm2")]), 1);
+        assert_eq!(recv(&mut other_realm).await[4], json!(["realm2"]));
+
+        send(&mut subscriber, json!([UNSUBSCRIBE, 4, subscription])).await;
+        assert_eq!(recv(&mut subscriber).await, json!([UNSUBSCRIBED, 4]));
+        send(&mut subscriber, json!([UNSUBSCRIBE, 5, subscription])).await;
+        assert_eq!(recv(&mut subscriber).await, json!([ERROR, UNSUBSCRIBE, 5, {}, "wamp.error.no_such_subscription"]));
+    }
+
+    #[tokio::test]
+    async fn test_remote_procedure_call() {
+        let url = serve(WampRouter::new(vec!["realm1"])).await;
+        let mut callee = join(&url, "realm1").await;
+        let mut caller = join(&url, "realm1").await;
+
+        send(&mut caller, json!([CALL, 1, {}, "com.example.add"])).await;
+        assert_eq!(recv(&mut caller).await, json!([ERROR, CALL, 1, {}, "wamp.error.no_such_procedure"]));
+
+        send(&mut callee, json!([REGISTER, 1, {}, "com.example.add"])).await;
+        let registered = recv(&mut callee).await;
+        assert_eq!(registered[0], REGISTERED);
+        send(&mut caller, json!([REGISTER, 2, {}, "com.example.add"])).await;
+        assert_eq!(recv(&mut caller).await[4], "wamp.error.procedure_already_exists");
+
+        send(&mut caller, json!([CALL, 3, {}, "com.example.add", [2, 3]])).await;
+        let invocation = recv(&mut callee).await;
+        assert_eq!(invocation[0], INVOCATION);
+        assert_eq!(invocation[2], registered[2]);
+        assert_eq!(invocation[4], json!([2, 3]));
+        send(&mut callee, json!([YIELD, invocation[1], {}, [5]])).await;
+        assert_eq!(recv(&mut caller).await, json!([RESULT, 3, {}, [5]]));
+
+        send(&mut caller, json!([CALL, 4, {}, "com.example.add", ["x"]])).await;
+        let invocation = recv(&mut callee).await;
+        send(&mut callee, json!([ERROR, INVOCATION, invocation[1], {}, "com.example.invalid_argument", ["x"]])).await;
+        assert_eq!(recv(&mut caller).await, json!([ERROR, CALL, 4, {}, "com.example.invalid_argument", ["x"]]));
+
+        // 被调用方断开后，未完成的调用返回 canceled，注册随之移除
+        send(&mut caller, json!([CALL, 5, {}, "com.example.add", [1, 1]])).await;
+        recv(&mut callee).await;
+        callee.close(1000, "").await.unwrap();
+        assert_eq!(recv(&mut caller).await, json!([ERROR, CALL, 5, {}, "wamp.error.canceled"]));
+        send(&mut caller, json!([CALL, 6, {}, "com.example.add"])).await;
+        assert_eq!(recv(&mut caller).await[4], "wamp.error.no_such_procedure");
+    }
+
+    #[test]
+    fn test_message_helpers() {
+        assert!(is_valid_uri("com.example.topic"));
+        assert!(!is_valid_uri("com..topic"));
+        assert!(!is_valid_uri("com.example topic"));
+        assert!(payload_at(&[json!(1), json!([1]), json!({})], 1).is_ok());
+        assert!(payload_at(&[json!(1), json!({})], 1).is_err());
+        assert!(id_at(&[json!(0)], 0).is_err());
+        assert!(id_at(&[json!(MAX_ID + 1)], 0).is_err());
+    }
+}